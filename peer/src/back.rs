@@ -1,25 +1,37 @@
-use crate::com::{connect, getfilef, receive, seedf, send};
-use crate::data::{MetaFile, PeerConfig};
+use crate::bitfield::Bitfield;
+use crate::com::{hellof, receive_message, seedf, send};
+use crate::data::{
+    get_buffer_size, get_file_key, get_piece_digests, MetaFile, PeerConfig, TrackerConfig,
+};
 use crate::db::{
-    get_buffermap, get_file, get_leeching_files, get_peer, get_peer_key, get_peers_from_file,
-    get_seeding_files, set_buffermap,
+    get_availability, get_buffermap, get_file, get_leeching_files, get_peer_key,
+    get_peers_from_file, get_seeding_files, set_buffermap, set_piece_digests,
+};
+use crate::events::{emit, Event};
+use crate::inflight::{get_request_timeout, in_flight, requested_from, reserve, timed_out_on};
+use crate::protocol::Message;
+use crate::respons_handler::{
+    Answer, ExpectDigests, ExpectHello, ExpectOk, ExpectPeers, ExpectedAnswer,
 };
-use crate::respons_handler::{Answer, ExpectOk, ExpectPeers, ExpectedAnswer};
-use crate::tasks::{Have, Peer};
+use crate::selector::get_strategy;
+use crate::state::{forget_written, load_state, save_state, track_file};
+use crate::swarm::prove;
+use crate::tasks::{Have, Peer, Session};
 use crate::threads::Pool;
-use log::{debug, error, info, trace};
+use crate::transport::Transport;
+use crate::tls::{check_pin, open, Remote};
+use log::{debug, error, info, trace, warn};
 use md5::digest::block_buffer::Error;
 use rayon::prelude::*;
-use std::cmp::min;
-use std::collections::BinaryHeap;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::io::{Seek, SeekFrom, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::sync::Mutex;
 
-pub fn is_stream_open(stream: &TcpStream) -> bool {
+pub fn is_stream_open(stream: &dyn Transport) -> bool {
     trace!("checking if stream is open");
     match stream.take_error() {
         Ok(Some(err)) => false,
@@ -28,11 +40,183 @@ pub fn is_stream_open(stream: &TcpStream) -> bool {
     }
 }
 
+/// Opens a connection to a peer and exchanges the handshake.
+///
+/// Our hello is sent first, the peer answers with its own hello,
+/// or with an error if it refuses us in which case the connection is dropped.
+///
+/// # Arguments
+/// * `peer` - The peer to connect to.
+///
+/// # Returns
+/// * `Option<(Box<dyn Transport>, Session)>` - The stream and the session holding who the peer is,
+///   or None if the connection or the handshake failed.
+pub fn connect_peer(peer: &PeerConfig) -> Option<(Box<dyn Transport>, Session)> {
+    let mut stream = open(Remote::Peer, peer.addr.port(), &peer.addr.ip().to_string())?;
+    send(&mut stream, &hellof());
+    match ExpectHello.expect(receive_message(&mut stream, 3000)) {
+        Ok(answer) => {
+            let session: Session = handshake_session(answer, peer.addr.ip());
+            if let Err(e) = check_peer_pin(&session, &stream) {
+                warn!("Refusing {} : {}", get_peer_key(peer.clone()), e);
+                return None;
+            }
+            Some((stream, session))
+        }
+        Err(e) => {
+            warn!("Handshake with {} failed : {}", get_peer_key(peer.clone()), e);
+            None
+        }
+    }
+}
+
+/// Opens a connection to a peer for a file, proving we know its secret if the file is private.
+///
+/// # Arguments
+/// * `peer` - The peer to connect to.
+/// * `key` - The key of the file.
+///
+/// # Returns
+/// * `Option<(Box<dyn Transport>, Session)>` - The stream and the session,
+///   or None if the connection failed or the peer did not admit us.
+pub fn join_swarm(peer: &PeerConfig, key: &str) -> Option<(Box<dyn Transport>, Session)> {
+    let (mut stream, session) = connect_peer(peer)?;
+    match prove(&mut stream, key) {
+        Ok(()) => Some((stream, session)),
+        Err(e) => {
+            warn!("{} did not admit us to {} : {}", get_peer_key(peer.clone()), key, e);
+            None
+        }
+    }
+}
+
+/// Number of peers asked for the digests of a file.
+const DIGEST_PEERS: usize = 5;
+
+/// Asks a peer for the digests of the pieces of a file.
+///
+/// # Arguments
+/// * `peer` - The peer to ask.
+/// * `key` - The key of the file.
+///
+/// # Returns
+/// * `Option<Vec<String>>` - The md5 of every piece, or None if the peer could not give them.
+fn ask_digests(peer: &PeerConfig, key: &str) -> Option<Vec<String>> {
+    let (mut stream, _) = join_swarm(peer, key)?;
+    let request = Message::Getdigests {
+        key: key.to_string(),
+    };
+    send(&mut stream, &request);
+    match ExpectDigests.expect(receive_message(&mut stream, 3000)) {
+        Ok(Answer::Digests(digests)) if !digests.is_empty() => Some(digests),
+        Ok(_) => {
+            debug!("{} does not know the digests of {}", get_peer_key(peer.clone()), key);
+            None
+        }
+        Err(e) => {
+            warn!("Could not get digests from {} : {}", get_peer_key(peer.clone()), e);
+            None
+        }
+    }
+}
+
+/// Gets the digests of the pieces of a file from peers other than the one sending the data.
+///
+/// A peer could send corrupted pieces along with their digests, so the sender is never asked.
+/// The digests are only trusted if a strict majority of the peers that answered agree on them.
+///
+/// # Arguments
+/// * `key` - The key of the file.
+/// * `sender` - The peer sending the pieces.
+///
+/// # Returns
+/// * `Option<Vec<String>>` - The md5 of every piece, or None if no majority could be found.
+pub fn fetch_digests(key: &str, sender: &PeerConfig) -> Option<Vec<String>> {
+    let me_key: String = get_peer_key(PeerConfig::new());
+    let sender_key: String = get_peer_key(sender.clone());
+    let answers: Vec<Vec<String>> = get_peers_from_file(key.to_string())
+        .into_iter()
+        .filter(|peer| {
+            let peer_key = get_peer_key(peer.clone());
+            peer_key != me_key && peer_key != sender_key
+        })
+        .take(DIGEST_PEERS)
+        .filter_map(|peer| ask_digests(&peer, key))
+        .collect();
+    let majority = answers
+        .iter()
+        .find(|&digests| {
+            answers.iter().filter(|&other| other == digests).count() * 2 > answers.len()
+        })
+        .cloned();
+    if majority.is_none() {
+        warn!("No majority of peers agrees on the digests of {}", key);
+    }
+    majority
+}
+
+/// Answers the handshake of an incoming connection.
+///
+/// The first message must be a hello of the same protocol version,
+/// otherwise an error message is sent back and the connection should be closed.
+///
+/// # Arguments
+/// * `stream` - The incoming stream.
+///
+/// # Returns
+/// * `Result<Session, Box<dyn std::error::Error>>` - The session holding who the peer is, or why it was refused.
+pub fn accept_peer(stream: &mut dyn Transport) -> Result<Session, Box<dyn std::error::Error>> {
+    // a dual-stack listener sees IPv4 peers as IPv4-mapped IPv6 addresses
+    let address: IpAddr = stream.peer_addr()?.ip().to_canonical();
+    match ExpectHello.expect(receive_message(stream, 3000)) {
+        Ok(answer) => {
+            let session: Session = handshake_session(answer, address);
+            if let Err(e) = check_peer_pin(&session, stream) {
+                let refusal = Message::Error {
+                    reason: "certificate does not match the peer id".to_string(),
+                };
+                send(stream, &refusal);
+                return Err(e);
+            }
+            send(stream, &hellof());
+            Ok(session)
+        }
+        Err(e) => {
+            let refusal = Message::Error {
+                reason: e.to_string(),
+            };
+            send(stream, &refusal);
+            Err(e)
+        }
+    }
+}
+
+// the remote listening port comes from the hello, not from the socket
+fn handshake_session(answer: Answer, address: IpAddr) -> Session {
+    let mut session = Session::default();
+    if let Answer::Hello { peer_id, port } = answer {
+        let addr = SocketAddr::new(address, port);
+        debug!("Handshake done with {} ({})", peer_id, addr);
+        session.peer_id = Some(peer_id);
+        session.peer = Some(PeerConfig { addr });
+    }
+    session
+}
+
+// over TLS, the certificate must be the one pinned to the peer id of the hello
+fn check_peer_pin(session: &Session, stream: &dyn Transport) -> Result<(), Box<dyn std::error::Error>> {
+    match &session.peer_id {
+        Some(peer_id) => check_pin(peer_id, stream),
+        None => Ok(()),
+    }
+}
+
 /// Starts the download process for a file.
 ///
 /// This function connects to a tracker, sends a request for the file, and receives a response.
 /// It then checks the response and retrieves the peers that hold the file.
 /// For each peer, it creates a new task and adds it to a vector of tasks.
+/// If the tracker cannot be reached, the peers found on the LAN are used instead.
 ///
 /// # Arguments
 /// * `key` - A string that holds the key of the file to be downloaded.
@@ -54,76 +238,246 @@ pub fn start_download(
     // let meta_file = get_file(&key).unwrap();
     // let chunk_size = meta_file.piece_size;
     // get the peers thare hold buffermap for the file
-    if let Some(mut stream) = connect(tracker_port, &tracker_adress) {
-        let getfile_message = getfilef(key);
-
-        // send getfile
-        send(&mut stream, getfile_message);
-
-        // get answer
-        let response = receive(&mut stream, 3000);
-        // check if answer is valid
-        match ExpectPeers.check_answer(&response) {
-            Ok(valeur) => {
-                // peers that hold each buffermap
-                // Here retrieve peers from correct answer
-                let peers: Answer = ExpectPeers.retrieve_data(valeur);
-
-                // now we should ask each peer for their buffermap that is a task
-
-                // say that i am downloading it
-                let seeded_files: Vec<MetaFile> = get_seeding_files();
-                let mut leeching_files_strings: Vec<String> = Vec::new();
-                let leeching_files: Vec<MetaFile> = get_leeching_files();
-                for leech in leeching_files {
-                    leeching_files_strings.push(leech.hash);
-                }
-                let mut stream: TcpStream = connect(tracker_port, &tracker_adress).unwrap();
-                let message = seedf(
-                    seeded_files,
-                    PeerConfig::new().port.to_string(),
-                    leeching_files_strings,
-                ); // create the message
-                send(&mut stream, message.clone());
-                debug!("OPTION -p Sent: {}", message);
-                let response = receive(&mut stream, 3000); // receive the answer
-                trace!("Received: {}", response);
-                match ExpectOk.check_answer(&response) {
-                    Ok(_) => {}
-                    Err(valeur) => {
-                        error!("{}", valeur);
-                    }
+    let (peers, from_tracker): (Vec<Peer>, bool) =
+        match tracker_peers(&key, tracker_port, tracker_adress) {
+            Some(peers) => (peers, true),
+            None => {
+                let peers: Vec<Peer> = discovered_peers(&key, &pool);
+                if peers.is_empty() {
+                    return Err(Error);
                 }
+                warn!(
+                    "No tracker, downloading {} from {} peers of the LAN",
+                    key,
+                    peers.len()
+                );
+                (peers, false)
+            }
+        };
+
+    // keep the progress of the download across restarts
+    if let Some(file) = get_file(&key) {
+        let written: Bitfield = get_buffermap(PeerConfig::new(), &key)
+            .unwrap_or_else(|| Bitfield::new(get_buffer_size(&file)));
+        track_file(&file, written);
+        if let Err(e) = save_state() {
+            error!("Could not save state : {}", e);
+        }
+    }
 
-                match peers {
-                    Answer::Peers(peers) => {
-                        let mut tasks = Vec::new();
-                        for mut peer in peers {
-                            // retrieve data init pool with an empty one
-                            // so we need to overwrite it
-                            let pool: Pool = pool.clone();
-                            peer.pool = pool;
-                            peer.length_tcp = length_tcp;
-                            tasks.push(Box::new(peer));
-                        }
-                        Ok(tasks)
-                    }
-                    _ => {
-                        error!("couldn't retrieve peers from tracker");
-                        Err(Error)
-                    }
+    // say that i am downloading it
+    if from_tracker {
+        announce(tracker_port, tracker_adress);
+    }
+
+    let mut tasks = Vec::new();
+    for mut peer in peers {
+        // retrieve data init pool with an empty one
+        // so we need to overwrite it
+        let pool: Pool = pool.clone();
+        peer.pool = pool;
+        peer.length_tcp = length_tcp;
+        tasks.push(Box::new(peer));
+    }
+    Ok(tasks)
+}
+
+// the peers the tracker gives for a file, None if it could not be asked
+fn tracker_peers(key: &str, tracker_port: u16, tracker_adress: &str) -> Option<Vec<Peer>> {
+    let mut stream = open(Remote::Tracker, tracker_port, tracker_adress)?;
+    let getfile_message = Message::Getfile {
+        key: key.to_string(),
+    };
+
+    // send getfile
+    send(&mut stream, &getfile_message);
+
+    // get answer
+    let response = receive_message(&mut stream, 3000);
+    // check if answer is valid
+    match ExpectPeers.expect(response) {
+        Ok(Answer::Peers(peers)) => Some(peers),
+        Ok(_) => {
+            error!("couldn't retrieve peers from tracker");
+            None
+        }
+        Err(valeur) => {
+            error!("{}", valeur);
+            None
+        }
+    }
+}
+
+// the peers of a file known without the tracker, found on the LAN
+fn discovered_peers(key: &str, pool: &Pool) -> Vec<Peer> {
+    let me: PeerConfig = PeerConfig::new();
+    get_peers_from_file(key.to_string())
+        .into_iter()
+        .filter(|peer| *peer != me)
+        .map(|config| Peer {
+            hash: key.to_string(),
+            length_tcp: 0,
+            config,
+            pool: pool.clone(),
+        })
+        .collect()
+}
+
+/// Announces the files we seed and leech to the tracker.
+///
+/// # Arguments
+/// * `tracker_port` - A u16 that represents the port of the tracker.
+/// * `tracker_adress` - A string slice that holds the address of the tracker.
+pub fn announce(tracker_port: u16, tracker_adress: &str) {
+    let seeded_files: Vec<MetaFile> = get_seeding_files();
+    let mut leeching_files_strings: Vec<String> = Vec::new();
+    let leeching_files: Vec<MetaFile> = get_leeching_files();
+    for leech in leeching_files {
+        leeching_files_strings.push(leech.hash);
+    }
+    let message = seedf(
+        seeded_files,
+        PeerConfig::new().addr.port().to_string(),
+        leeching_files_strings,
+    ); // create the message
+    if let Some(mut stream) = open(Remote::Tracker, tracker_port, tracker_adress) {
+        send(&mut stream, &message);
+        debug!("OPTION -p Sent: {}", message);
+        let response = receive_message(&mut stream, 3000); // receive the answer
+        if let Err(valeur) = ExpectOk.expect(response) {
+            error!("{}", valeur);
+        }
+    }
+}
+
+/// Checks a file whose last piece has just been written, then seeds it.
+///
+/// The whole file must hash to its key. If it does, the file is announced to the
+/// tracker as seeded. Otherwise the pieces that do not match their digest are
+/// marked as missing so the running downloads fetch them again. When every piece
+/// matches, the digests themselves are wrong: they are dropped with all the pieces.
+///
+/// # Arguments
+/// * `key` - The key of the file.
+pub fn complete_download(key: &str) {
+    let file: MetaFile = match get_file(key) {
+        Some(file) => file,
+        None => {
+            error!("File {} not found", key);
+            return;
+        }
+    };
+    let nb_pieces = get_buffer_size(&file);
+    let computed = if Path::new(&file.file_name).exists() {
+        get_file_key(&file.file_name).unwrap_or_else(|e| {
+            error!("Could not hash {} : {}", file.file_name, e);
+            String::new()
+        })
+    } else {
+        String::new()
+    };
+
+    if computed == file.hash {
+        if let Err(e) = save_state() {
+            error!("Could not save state : {}", e);
+        }
+        let tracker = TrackerConfig::new();
+        announce(tracker.port, &tracker.address);
+        emit(Event::DownloadComplete {
+            key: file.hash,
+            file_name: file.file_name,
+        });
+        return;
+    }
+
+    let mut bad: Vec<usize> = match get_piece_digests(&file.file_name, file.piece_size, nb_pieces)
+    {
+        Ok(digests) => (0..nb_pieces)
+            .filter(|&index| file.piece_digests.get(index) != digests.get(index))
+            .collect(),
+        Err(e) => {
+            warn!("Could not hash {} : {}", file.file_name, e);
+            Vec::new()
+        }
+    };
+    if bad.is_empty() {
+        warn!("Digests of {} do not add up to its key, asking them again", file.file_name);
+        set_piece_digests(key, Vec::new());
+        bad = (0..nb_pieces).collect();
+    }
+
+    forget_written(key, &bad);
+    if let Some(mut buffermap) = get_buffermap(PeerConfig::new(), key) {
+        let len = buffermap.len();
+        for &index in bad.iter().filter(|&&index| index < len) {
+            buffermap.set(index, false);
+        }
+        set_buffermap(key.to_string(), get_peer_key(PeerConfig::new()), buffermap);
+    }
+    if let Err(e) = save_state() {
+        error!("Could not save state : {}", e);
+    }
+    emit(Event::VerificationFailed {
+        key: file.hash,
+        file_name: file.file_name,
+        pieces: bad,
+    });
+}
+
+/// Reloads the files of the previous run, announces them and resumes the unfinished downloads.
+///
+/// # Arguments
+/// * `tracker_port` - A u16 that represents the port of the tracker.
+/// * `tracker_adress` - A string slice that holds the address of the tracker.
+/// * `pool` - The pool the download tasks are added to.
+/// * `length_tcp` - How many bytes to ask a peer for at once.
+pub fn resume_session(tracker_port: u16, tracker_adress: &str, mut pool: Pool, length_tcp: usize) {
+    let files: Vec<MetaFile> = load_state();
+    if files.is_empty() {
+        return;
+    }
+    announce(tracker_port, tracker_adress);
+
+    for file in files {
+        let complete = get_buffermap(PeerConfig::new(), &file.hash).is_some_and(|b| b.is_full());
+        if complete {
+            continue;
+        }
+        info!("Resuming download of {}", file.file_name);
+        match start_download(file.hash.clone(), tracker_port, tracker_adress, pool.clone(), length_tcp) {
+            Ok(tasks) => {
+                for task in tasks {
+                    pool.add_task(task);
                 }
             }
-            Err(valeur) => {
-                error!("{}", valeur);
-                Err(Error)
-            }
+            Err(e) => error!("Could not resume {} : {}", file.file_name, e),
         }
-    } else {
-        Err(Error)
     }
 }
 
+/// Splits the pieces asked to us between the ones we hold and the others.
+///
+/// Only the pieces set in our buffermap are held, so a leecher shares what it
+/// downloaded and nothing more, and an unknown file has no piece at all.
+///
+/// # Arguments
+/// * `key` - The key of the file.
+/// * `pieces` - The indexes asked.
+///
+/// # Returns
+/// * `(Vec<usize>, Vec<usize>)` - The pieces held and the missing ones, in the order asked.
+pub fn split_held_pieces(key: &str, pieces: &[usize]) -> (Vec<usize>, Vec<usize>) {
+    let held: Bitfield = match get_file(key) {
+        Some(file) => get_buffermap(PeerConfig::new(), key)
+            .filter(|buffermap| buffermap.len() == get_buffer_size(&file))
+            .unwrap_or_else(|| Bitfield::new(0)),
+        None => Bitfield::new(0),
+    };
+    pieces
+        .iter()
+        .partition(|&&index| index < held.len() && held.get(index))
+}
+
 /// Retrieves the specified chunks from a file.
 ///
 /// This function iterates over a vector of chunk indices, retrieves each chunk from the file,
@@ -142,23 +496,47 @@ pub fn get_chunks_from_file(
     chunk_indexes: &Vec<usize>,
 ) -> Vec<(usize, Vec<u8>)> {
     // get filepath
-    let meta_file = get_file(&key)
-        .ok_or(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "File not found",
-        ))
-        .unwrap();
+    let mut chunks: Vec<(usize, Vec<u8>)> = Vec::new();
+    let meta_file = match get_file(&key) {
+        Some(meta_file) => meta_file,
+        None => {
+            error!("File {} not found", key);
+            return chunks;
+        }
+    };
     let file_path = &meta_file.file_name;
 
     // open the file only once to reduce io
-    let file: File = File::open(file_path).unwrap();
+    let file: File = match File::open(file_path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Could not open {} : {}", file_path, e);
+            return chunks;
+        }
+    };
 
     // cycle throught all the chunks
-    let mut chunks: Vec<(usize, Vec<u8>)> = Vec::new();
     for chunk_index in chunk_indexes {
         let chunk_index: usize = chunk_index.clone();
-        let chunk = get_chunk(file.try_clone().unwrap(), chunk_size, chunk_index).unwrap();
-        chunks.push((chunk_index, chunk));
+        let chunk = file
+            .try_clone()
+            .and_then(|file| get_chunk(file, chunk_size, chunk_index));
+        // a short read means the piece is not on disk, it is better not sent than sent wrong
+        let expected: usize = meta_file
+            .length
+            .saturating_sub(chunk_index * chunk_size)
+            .min(chunk_size);
+        match chunk {
+            Ok(chunk) if chunk.len() == expected => chunks.push((chunk_index, chunk)),
+            Ok(chunk) => error!(
+                "Chunk {} of {} has {} bytes instead of {}",
+                chunk_index,
+                file_path,
+                chunk.len(),
+                expected
+            ),
+            Err(e) => error!("Could not read chunk {} of {} : {}", chunk_index, file_path, e),
+        }
     }
     let ret: Vec<(usize, Vec<u8>)> = chunks.clone();
     ret
@@ -190,105 +568,97 @@ fn get_chunk(mut file: File, chunk_size: usize, chunk_index: usize) -> std::io::
 pub fn store_have_to_db(peer: PeerConfig, have: Have) {
     let file_key: String = have.key;
     let peer_key: String = get_peer_key(peer);
-    let buffermap: Vec<u8> = have.buffermap;
+    let buffermap: Bitfield = have.buffermap;
 
     set_buffermap(file_key, peer_key, buffermap);
 }
 
 static LOCK: Mutex<()> = Mutex::new(());
 
-// can be optimised ? without computation -> double the download speed
+/// Chooses the pieces to ask a peer for and reserves them for it.
+///
+/// The pieces are chosen among the ones the peer has and we neither have nor asked,
+/// by the strategy of the download, from the availability kept by the database.
+/// A piece whose request to this peer timed out is left to the other peers having it.
+///
+/// # Arguments
+/// * `peer_key` - The key of the distant peer.
+/// * `file_key` - The key of the file.
+/// * `nb_pieces` - How many pieces to ask at most.
+///
+/// # Returns
+/// * `Vec<usize>` - The chosen pieces, empty if the peer has nothing for us.
 pub fn get_wanted_piece_from_peer(peer_key: &str, file_key: &str, nb_pieces: usize) -> Vec<usize> {
-    // to allow only one thread at a time here
+    // to allow only one thread at a time here, so a piece is not reserved twice
     let _guard = LOCK.lock().unwrap();
 
-    let file_key_clone = String::from(file_key);
-    let peers: Vec<PeerConfig> = get_peers_from_file(file_key_clone);
     let me: PeerConfig = PeerConfig::new();
-
-    let me_clone = me.clone();
-    let distant_peer: Mutex<PeerConfig> = Mutex::new(me_clone.clone());
-
-    let mut buffmaps: Vec<Vec<u8>> = Vec::new();
-
-    // cycle through all peers to get their buffmap
-    for peer in peers {
-        //peers.par_iter().for_each( |peer| {
-        if peer_key == get_peer_key(peer.clone()) {
-            *distant_peer.lock().unwrap() = peer.clone();
-        }
-        let file_key_clone = file_key;
-        let option: Option<Vec<u8>> = get_buffermap(peer.clone(), file_key_clone);
-        match option {
-            Some(buffmap) => buffmaps.push(buffmap),
-            None => {}
+    let distant_peer: Option<PeerConfig> = get_peers_from_file(file_key.to_string())
+        .into_iter()
+        .find(|peer| get_peer_key(peer.clone()) == peer_key);
+    let distant_peer: PeerConfig = match distant_peer {
+        Some(peer) if peer != me => peer,
+        _ => {
+            error!("Can't detect who is the distant peer");
+            return Vec::new();
         }
-    } //);
-
-    let distant_peer: PeerConfig = distant_peer.lock().unwrap().clone();
-
-    if distant_peer.address == me.address && distant_peer.port == me.port {
-        error!("Can't detect who is the distant peer");
-        let ret: Vec<usize> = Vec::new();
-        return ret;
-    }
+    };
 
     // get distant peer buffmap
-    let distant_buffmap: Vec<u8> = get_buffermap(distant_peer, file_key).unwrap();
-
-    // now we need to calculate the rarest parts scores
-    //let buffmaps_clone = buffmaps.clone();
-
-    let mut main_buffmap: Vec<u8>;
-    match get_buffermap(PeerConfig::new(), file_key) {
-        Some(arr) => main_buffmap = arr,
-        None => main_buffmap = vec![0 as u8; buffmaps[0].len()],
-    }
-    let len: usize = main_buffmap.len();
-    let mut scores: Vec<usize> = vec![0; len];
-    let mut remaining: usize = 0;
-
-    let mut done: usize = 0;
-    for e in &main_buffmap {
-        if *e == 1 {
-            done += 1;
+    let distant_buffmap: Bitfield = match get_buffermap(distant_peer, file_key) {
+        Some(buffmap) => buffmap,
+        None => {
+            error!("No buffermap for {} on {}", peer_key, file_key);
+            return Vec::new();
         }
+    };
+
+    let main_buffmap: Bitfield = match get_buffermap(me, file_key) {
+        Some(arr) => arr,
+        None => Bitfield::new(distant_buffmap.len()),
+    };
+    if main_buffmap.is_empty() {
+        return Vec::new();
     }
-
-    for i in 0..len {
-        if main_buffmap[i] == 0 {
-            remaining += 1;
-            for buffmap in &buffmaps {
-                if (buffmap[i] + distant_buffmap[i]) == 2 {
-                    scores[i] += 1;
-                }
-            }
-        }
+    let len: usize = main_buffmap.len();
+    let done: usize = main_buffmap.count_ones();
+    if distant_buffmap.len() != len {
+        error!(
+            "Buffermap of {} has {} pieces instead of {}",
+            peer_key,
+            distant_buffmap.len(),
+            len
+        );
+        return Vec::new();
     }
 
-    // now we have a score per index
-    // we just need to the get lowest scores
-    let nb_pieces: usize = min(nb_pieces, remaining);
-
-    // now get the nb_pieces smallest score index
-    let mut ret: Vec<usize> = Vec::new();
-    let mut heap = BinaryHeap::new();
-
-    for (i, &score) in scores.iter().enumerate() {
-        if main_buffmap[i] == 0 {
-            heap.push((score, i));
-        }
-        if heap.len() > nb_pieces {
-            heap.pop();
+    // pieces the distant peer can give us
+    let availability: Vec<usize> = get_availability(file_key);
+    let mut avoided: Bitfield = timed_out_on(file_key, peer_key, len);
+    for index in avoided.clone().ones() {
+        if availability.get(index).copied().unwrap_or(0) <= 1 {
+            avoided.set(index, false);
         }
     }
-
-    for _ in 0..nb_pieces {
-        let (_, index) = heap.pop().unwrap();
-        ret.push(index);
-    }
-
-    ret.reverse();
+    // endgame: every missing piece is asked already, a slow peer must not
+    // hold the end of the download so they are asked to this one too
+    let wanted: Bitfield = Bitfield::full(len).and_not(&main_buffmap);
+    let reserved: Bitfield = in_flight(file_key, len);
+    let endgame: bool = wanted.and_not(&reserved).count_ones() == 0;
+    let busy: Bitfield = if endgame {
+        debug!("Endgame on {}, asking {} for pieces already asked", file_key, peer_key);
+        requested_from(file_key, peer_key, len)
+    } else {
+        reserved
+    };
+    let candidates: Bitfield = distant_buffmap
+        .and(&wanted)
+        .and_not(&busy)
+        .and_not(&avoided);
+    let strategy = get_strategy(file_key);
+    let ret: Vec<usize> = strategy
+        .selector()
+        .select(&candidates, &availability, done, nb_pieces);
 
     info!(
         "Dowloaded: {} chunks over {} ({}%)",
@@ -296,13 +666,9 @@ pub fn get_wanted_piece_from_peer(peer_key: &str, file_key: &str, nb_pieces: usi
         len,
         ((done as f64) / (len as f64) * 100.0) as u64
     );
-    trace!("Rarest parts are {:?}", ret);
-
-    for piece in ret.clone() {
-        main_buffmap[piece] = 1;
-    }
+    trace!("{} pieces are {:?}", strategy, ret);
 
-    set_buffermap(file_key.to_string(), get_peer_key(me_clone), main_buffmap);
+    reserve(file_key, peer_key, &ret, get_request_timeout());
 
     ret
 }
@@ -332,8 +698,14 @@ impl FileAssembler {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{ProtocolError, PROTOCOL_VERSION};
     use std::fs::File;
+    use crate::data::get_piece_digest;
+    use crate::db::set_peer_to_file;
+    use crate::transport::duplex;
     use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
 
     #[test]
     fn test_get_chunk() -> std::io::Result<()> {
@@ -341,6 +713,7 @@ mod tests {
         let file_path = "test_file.txt";
         let mut file = File::create(file_path)?;
         file.write_all(b"Hello, world!")?;
+        let file = File::open(file_path)?;
 
         // Read the first chunk from the file
         let chunk = get_chunk(file, 5, 0)?;
@@ -350,4 +723,117 @@ mod tests {
 
         Ok(())
     }
+
+    // runs accept_peer on an in-memory connection, the client side sends `hello`
+    fn handshake(hello: Message) -> (Result<Session, String>, Result<Message, ProtocolError>) {
+        let (mut client, mut server) = duplex(
+            "127.0.0.1:40000".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+        );
+        send(&mut client, &hello);
+        let session = accept_peer(&mut server).map_err(|e| e.to_string());
+        let answer = receive_message(&mut client, 3000);
+        (session, answer)
+    }
+
+    #[test]
+    fn test_accept_peer() {
+        let (session, answer) = handshake(Message::Hello {
+            version: PROTOCOL_VERSION,
+            peer_id: "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b".to_string(),
+            port: 6000,
+        });
+        let session = session.unwrap();
+        assert_eq!(
+            session.peer,
+            Some(PeerConfig {
+                addr: "127.0.0.1:6000".parse().unwrap(),
+            })
+        );
+        assert_eq!(session.peer_id.unwrap(), "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b");
+        assert_eq!(answer.unwrap(), hellof());
+    }
+
+    #[test]
+    fn test_accept_peer_refused() {
+        let (session, answer) = handshake(Message::Hello {
+            version: PROTOCOL_VERSION + 1,
+            peer_id: "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b".to_string(),
+            port: 6000,
+        });
+        assert!(session.is_err());
+        assert!(matches!(answer, Ok(Message::Error { .. })));
+
+        let (session, answer) = handshake(Message::Interested {
+            key: "8905e92afeb80fc7722ec89eb0bf0966".to_string(),
+        });
+        assert!(session.is_err());
+        assert!(matches!(answer, Ok(Message::Error { .. })));
+    }
+
+    // a peer of the file answering getdigests with `digests` on each of `connections`
+    fn digest_peer(
+        key: &str,
+        digests: Vec<String>,
+        connections: usize,
+    ) -> (PeerConfig, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = PeerConfig {
+            addr: listener.local_addr().unwrap(),
+        };
+        let file = get_file(key).unwrap();
+        set_peer_to_file(peer.clone(), file, Bitfield::new(3));
+        let remote = thread::spawn(move || {
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                receive_message(&mut stream, 3000).unwrap();
+                send(
+                    &mut stream,
+                    &Message::Hello {
+                        version: PROTOCOL_VERSION,
+                        peer_id: "7c1d2e3f4a5b6c7d8e9f0a1b2c3d4e5f".to_string(),
+                        port: 1,
+                    },
+                );
+                let Ok(Message::Getdigests { key }) = receive_message(&mut stream, 3000) else {
+                    panic!("expected getdigests");
+                };
+                let digests = digests.clone();
+                send(&mut stream, &Message::Digests { key, digests });
+            }
+        });
+        (peer, remote)
+    }
+
+    #[test]
+    fn test_fetch_digests() {
+        let digests: Vec<String> = (0..3).map(|index| get_piece_digest(&[index; 1024])).collect();
+        let file = MetaFile {
+            file_name: "fetch_digests.dat".to_string(),
+            length: 2048,
+            piece_size: 1024,
+            hash: "5d41402abc4b2a76b9719d911017c592".to_string(),
+            piece_digests: Vec::new(),
+        };
+        set_peer_to_file(PeerConfig::new(), file.clone(), Bitfield::new(3));
+        let mut forged: Vec<String> = digests.clone();
+        forged[1] = get_piece_digest(&[0; 1024]);
+
+        let (honest, first) = digest_peer(&file.hash, digests.clone(), 1);
+        let (_, second) = digest_peer(&file.hash, digests.clone(), 2);
+        let (forger, third) = digest_peer(&file.hash, forged.clone(), 1);
+
+        // the forger sends the data, the two other peers agree
+        assert_eq!(fetch_digests(&file.hash, &forger), Some(digests.clone()));
+        // an honest peer sends the data, the two others disagree
+        assert_eq!(fetch_digests(&file.hash, &honest), None);
+        first.join().unwrap();
+        second.join().unwrap();
+        third.join().unwrap();
+
+        // the digests kept are never replaced by the next answer
+        assert!(set_piece_digests(&file.hash, digests.clone()));
+        assert!(!set_piece_digests(&file.hash, forged));
+        assert_eq!(get_file(&file.hash).unwrap().piece_digests, digests);
+    }
 }
@@ -0,0 +1,255 @@
+//! packed buffermaps, one bit per piece
+use crate::data::{b64_dec, b64_enc};
+use std::fmt;
+use std::str::FromStr;
+
+const WORD_BITS: usize = 64;
+
+/// Which pieces of a file a peer holds.
+///
+/// Bits are packed in `u64` words so counting and comparing buffermaps
+/// works on 64 pieces at a time. Bits past `len` are always zero.
+///
+/// On the wire a bitfield is written `$Length:$Base64`, the base64 holding
+/// `ceil(len / 8)` bytes where piece 0 is the most significant bit of the first byte.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    len: usize,
+    words: Vec<u64>,
+}
+
+impl Bitfield {
+    /// Creates a bitfield of `len` pieces, none of them held.
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            len,
+            words: vec![0; len.div_ceil(WORD_BITS)],
+        }
+    }
+
+    /// Creates a bitfield of `len` pieces, all of them held.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Bitfield {
+            len,
+            words: vec![u64::MAX; len.div_ceil(WORD_BITS)],
+        };
+        bitfield.clear_tail();
+        bitfield
+    }
+
+    /// Number of pieces
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Tells if piece `index` is held, pieces out of the bitfield are not.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.words[index / WORD_BITS] & (1 << (index % WORD_BITS)) != 0
+    }
+
+    /// Marks piece `index` as held or not.
+    ///
+    /// # Panics
+    /// If `index` is out of the bitfield, like indexing a `Vec`.
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(
+            index < self.len,
+            "piece {} out of a bitfield of {} pieces",
+            index,
+            self.len
+        );
+        let mask: u64 = 1 << (index % WORD_BITS);
+        if value {
+            self.words[index / WORD_BITS] |= mask;
+        } else {
+            self.words[index / WORD_BITS] &= !mask;
+        }
+    }
+
+    /// Number of pieces held
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Tells if every piece is held
+    pub fn is_full(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    /// Pieces held in both bitfields.
+    pub fn and(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Pieces held in `self` but not in `other`.
+    pub fn and_not(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    // the result is as long as `self`, missing words of `other` count as zeros
+    fn combine(&self, other: &Bitfield, op: impl Fn(u64, u64) -> u64) -> Bitfield {
+        let words = self
+            .words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| op(*word, other.words.get(i).copied().unwrap_or(0)))
+            .collect();
+        let mut bitfield = Bitfield {
+            len: self.len,
+            words,
+        };
+        bitfield.clear_tail();
+        bitfield
+    }
+
+    /// Indexes of the pieces held, in increasing order.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(i * WORD_BITS + bit)
+            })
+        })
+    }
+
+    /// Packs the bitfield in `ceil(len / 8)` bytes, piece 0 being the most significant bit.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.len.div_ceil(8)];
+        for index in self.ones() {
+            bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+        bytes
+    }
+
+    /// Unpacks a bitfield of `len` pieces written by `to_bytes`.
+    ///
+    /// # Returns
+    /// * `Result<Bitfield, String>` - The bitfield, or why the bytes do not hold `len` pieces.
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Result<Bitfield, String> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(format!(
+                "{} pieces need {} bytes, got {}",
+                len,
+                len.div_ceil(8),
+                bytes.len()
+            ));
+        }
+        let mut bitfield = Bitfield::new(len);
+        for (i, byte) in bytes.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let index = i * 8 + bit;
+                    if index >= len {
+                        return Err(format!("piece {} set past the {} pieces", index, len));
+                    }
+                    bitfield.set(index, true);
+                }
+            }
+        }
+        Ok(bitfield)
+    }
+
+    fn clear_tail(&mut self) {
+        let used = self.len % WORD_BITS;
+        if used != 0 {
+            if let Some(last) = self.words.last_mut() {
+                *last &= (1 << used) - 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Bitfield {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.len, b64_enc(self.to_bytes()))
+    }
+}
+
+impl FromStr for Bitfield {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (len, bits) = s.split_once(':').ok_or("missing length")?;
+        let len: usize = len
+            .parse()
+            .map_err(|_| format!("length {:?} is not a number", len))?;
+        let bytes = b64_dec(bits).map_err(|e| format!("bits are not base64: {}", e))?;
+        Bitfield::from_bytes(len, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_count() {
+        let mut bitfield = Bitfield::new(130);
+        assert_eq!(bitfield.count_ones(), 0);
+        bitfield.set(0, true);
+        bitfield.set(64, true);
+        bitfield.set(129, true);
+        assert!(bitfield.get(64));
+        assert!(!bitfield.get(63));
+        assert!(!bitfield.get(500));
+        assert_eq!(bitfield.count_ones(), 3);
+        assert_eq!(bitfield.ones().collect::<Vec<usize>>(), vec![0, 64, 129]);
+        bitfield.set(64, false);
+        assert_eq!(bitfield.count_ones(), 2);
+
+        let full = Bitfield::full(130);
+        assert_eq!(full.count_ones(), 130);
+        assert!(full.is_full());
+        assert!(!bitfield.is_full());
+        assert!(Bitfield::new(0).is_full());
+    }
+
+    #[test]
+    fn test_and() {
+        let mut a = Bitfield::new(70);
+        let mut b = Bitfield::new(70);
+        for i in [1, 2, 65] {
+            a.set(i, true);
+        }
+        for i in [2, 3, 65, 69] {
+            b.set(i, true);
+        }
+        assert_eq!(a.and(&b).ones().collect::<Vec<usize>>(), vec![2, 65]);
+        assert_eq!(b.and_not(&a).ones().collect::<Vec<usize>>(), vec![3, 69]);
+        assert_eq!(Bitfield::full(70).and_not(&Bitfield::new(3)).count_ones(), 70);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        assert_eq!(bitfield.to_bytes(), vec![0x80, 0x40]);
+        assert_eq!(bitfield.to_string(), "10:gEA=");
+        assert_eq!("10:gEA=".parse::<Bitfield>().unwrap(), bitfield);
+        assert_eq!("0:".parse::<Bitfield>().unwrap(), Bitfield::new(0));
+
+        let big = Bitfield::full(4 * 1024 * 1024);
+        assert!(big.to_string().len() < 800 * 1024);
+        assert_eq!(big.to_string().parse::<Bitfield>().unwrap(), big);
+    }
+
+    #[test]
+    fn test_wire_format_errors() {
+        assert!("gEA=".parse::<Bitfield>().is_err());
+        assert!("x:gEA=".parse::<Bitfield>().is_err());
+        assert!("10:gEA".parse::<Bitfield>().is_err());
+        // too many bytes, and a bit set past the length
+        assert!("8:gEA=".parse::<Bitfield>().is_err());
+        assert!("9:gEA=".parse::<Bitfield>().is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! upload slots, and the peers that refuse to upload to us
+//!
+//! A seeder sends pieces to a few peers at a time. Every `CHOKE_PERIOD` the
+//! slots go to the interested peers that upload the most to us, plus one
+//! optimistic slot given to a random peer, a newcomer if possible, every
+//! `OPTIMISTIC_ROUNDS` periods. The other peers are answered `choke` until
+//! they get an `unchoke`.
+use crate::data::{get_config_path, PeerConfig};
+use crate::db::get_peer_key;
+use crate::protocol::Message;
+use crate::threads::TaskHandle;
+use hashbrown::{HashMap, HashSet};
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::debug;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time between two choke decisions
+pub const CHOKE_PERIOD: Duration = Duration::from_secs(10);
+/// Periods an optimistic unchoke lasts
+const OPTIMISTIC_ROUNDS: usize = 3;
+/// Periods without a request after which a peer is not interested anymore
+const IDLE_ROUNDS: u32 = 3;
+/// How long a choke is respected without hearing from the peer, it is asked again after
+pub const CHOKED_RECHECK: Duration = Duration::from_secs(30);
+
+/// a peer asking us for pieces
+struct Interested {
+    peer: PeerConfig,
+    keys: HashSet<String>,
+    last: Instant,
+}
+
+#[derive(Default)]
+struct Choker {
+    interested: HashMap<String, Interested>,
+    unchoked: HashSet<String>,
+    optimistic: Option<String>,
+    round: usize,
+}
+
+impl Choker {
+    /// Records a request for pieces and tells if it can be served.
+    fn request(&mut self, peer: &PeerConfig, key: &str, slots: usize, now: Instant) -> bool {
+        let peer_key = get_peer_key(peer.clone());
+        let interested = self
+            .interested
+            .entry(peer_key.clone())
+            .or_insert_with(|| Interested {
+                peer: peer.clone(),
+                keys: HashSet::new(),
+                last: now,
+            });
+        interested.keys.insert(key.to_string());
+        interested.last = now;
+        if self.unchoked.contains(&peer_key) || self.optimistic.as_ref() == Some(&peer_key) {
+            return true;
+        }
+        // a free slot is taken at once, no need to wait for the next round
+        if self.unchoked.len() < slots {
+            debug!("Unchoking {}, a slot is free", peer_key);
+            self.unchoked.insert(peer_key);
+            return true;
+        }
+        false
+    }
+
+    /// Chooses the peers having a slot until the next round.
+    ///
+    /// # Arguments
+    /// * `slots` - The number of regular slots.
+    /// * `now` - The time of the decision.
+    /// * `uploaded` - How fast a peer uploads to us, in bytes/s.
+    /// * `random` - A random number to pick the optimistic peer.
+    ///
+    /// # Returns
+    /// * `Vec<(PeerConfig, Message)>` - The `choke` and `unchoke` to send.
+    fn rechoke(
+        &mut self,
+        slots: usize,
+        now: Instant,
+        uploaded: impl Fn(&str) -> Option<f64>,
+        random: u64,
+    ) -> Vec<(PeerConfig, Message)> {
+        self.interested.retain(|_, interested| {
+            now.duration_since(interested.last) < CHOKE_PERIOD * IDLE_ROUNDS
+        });
+
+        // tit-for-tat: the peers giving us the most get the slots
+        let mut ranked: Vec<&String> = self.interested.keys().collect();
+        ranked.sort_by(|a, b| {
+            let a_rate = uploaded(a).unwrap_or(0.0);
+            let b_rate = uploaded(b).unwrap_or(0.0);
+            b_rate.total_cmp(&a_rate).then(a.cmp(b))
+        });
+        let unchoked: HashSet<String> = ranked
+            .iter()
+            .take(slots)
+            .map(|key| key.to_string())
+            .collect();
+
+        let optimistic_still = self.optimistic.as_ref().filter(|key| {
+            self.interested.contains_key(*key) && !self.round.is_multiple_of(OPTIMISTIC_ROUNDS)
+        });
+        let optimistic: Option<String> = match optimistic_still {
+            Some(key) => Some(key.clone()),
+            None => {
+                let choked: Vec<&String> = ranked.iter().skip(slots).copied().collect();
+                // newcomers never sent us anything, they need a slot to start trading
+                let newcomers: Vec<&String> = choked
+                    .iter()
+                    .filter(|key| uploaded(key).is_none())
+                    .copied()
+                    .collect();
+                let pool = if newcomers.is_empty() {
+                    choked
+                } else {
+                    newcomers
+                };
+                match pool.len() {
+                    0 => None,
+                    len => Some(pool[(random % len as u64) as usize].clone()),
+                }
+            }
+        };
+        self.round += 1;
+
+        let mut before: HashSet<String> = self.unchoked.clone();
+        before.extend(self.optimistic.clone());
+        let mut after: HashSet<String> = unchoked.clone();
+        after.extend(optimistic.clone());
+        let mut messages: Vec<(PeerConfig, Message)> = Vec::new();
+        for (peer_key, interested) in self.interested.iter() {
+            let message: fn(String) -> Message =
+                match (before.contains(peer_key), after.contains(peer_key)) {
+                    (true, false) => |key| Message::Choke { key },
+                    (false, true) => |key| Message::Unchoke { key },
+                    _ => continue,
+                };
+            for key in interested.keys.iter() {
+                messages.push((interested.peer.clone(), message(key.clone())));
+            }
+        }
+        self.unchoked = unchoked;
+        self.optimistic = optimistic;
+        messages
+    }
+}
+
+/// a peer refusing to send us pieces of a file
+struct ChokedBy {
+    since: Instant,
+    // requests waiting for the unchoke
+    waiting: Vec<TaskHandle>,
+}
+
+impl ChokedBy {
+    fn new() -> ChokedBy {
+        ChokedBy {
+            since: Instant::now(),
+            waiting: Vec::new(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref CHOKER: Mutex<Choker> = Mutex::new(Choker::default());
+    /// peers choking us by (peer, file)
+    static ref CHOKED: Mutex<HashMap<(String, String), ChokedBy>> = Mutex::new(HashMap::new());
+    /// `upload-slots` of the Peer section, read once
+    static ref UPLOAD_SLOTS: usize = Ini::load_from_file(get_config_path())
+        .ok()
+        .and_then(|conf| {
+            conf.section(Some("Peer"))
+                .and_then(|section| section.get("upload-slots"))
+                .and_then(|slots| slots.parse().ok())
+        })
+        .unwrap_or(4);
+}
+
+/// Tells if a peer may be sent pieces of a file, recording that it wants them.
+pub fn may_upload(peer: &PeerConfig, key: &str) -> bool {
+    let mut choker = CHOKER.lock().unwrap();
+    choker.request(peer, key, *UPLOAD_SLOTS, Instant::now())
+}
+
+/// Gives the slots for the next round, tit-for-tat on what the peers upload to us.
+///
+/// # Arguments
+/// * `uploaded` - How fast a peer uploads to us, in bytes/s.
+///
+/// # Returns
+/// * `Vec<(PeerConfig, Message)>` - The `choke` and `unchoke` to send.
+pub fn rechoke(uploaded: impl Fn(&str) -> Option<f64>) -> Vec<(PeerConfig, Message)> {
+    let random = RandomState::new().hash_one(Instant::now());
+    let mut choker = CHOKER.lock().unwrap();
+    choker.rechoke(*UPLOAD_SLOTS, Instant::now(), uploaded, random)
+}
+
+/// Records that a peer refuses to send us pieces of a file.
+pub fn set_choked(peer_key: &str, file_key: &str) {
+    let mut choked = CHOKED.lock().unwrap();
+    choked
+        .entry((peer_key.to_string(), file_key.to_string()))
+        .or_insert_with(ChokedBy::new)
+        .since = Instant::now();
+}
+
+/// Tells if a peer chokes us, a choke that was not renewed for `CHOKED_RECHECK` is ignored.
+pub fn is_choked(peer_key: &str, file_key: &str) -> bool {
+    let choked = CHOKED.lock().unwrap();
+    choked
+        .get(&(peer_key.to_string(), file_key.to_string()))
+        .is_some_and(|choked| choked.since.elapsed() < CHOKED_RECHECK)
+}
+
+/// Remembers a request delayed until the peer unchokes us.
+pub fn wait_unchoke(peer_key: &str, file_key: &str, handle: TaskHandle) {
+    let mut choked = CHOKED.lock().unwrap();
+    let waiting = &mut choked
+        .entry((peer_key.to_string(), file_key.to_string()))
+        .or_insert_with(ChokedBy::new)
+        .waiting;
+    waiting.retain(TaskHandle::is_pending);
+    waiting.push(handle);
+}
+
+/// Records that a peer unchoked us.
+///
+/// # Returns
+/// * `Vec<TaskHandle>` - The requests that were waiting for it.
+pub fn set_unchoked(peer_key: &str, file_key: &str) -> Vec<TaskHandle> {
+    let mut choked = CHOKED.lock().unwrap();
+    choked
+        .remove(&(peer_key.to_string(), file_key.to_string()))
+        .map(|choked| choked.waiting)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn peer(port: u16) -> PeerConfig {
+        PeerConfig {
+            addr: SocketAddr::new([10, 0, 0, 1].into(), port),
+        }
+    }
+
+    #[test]
+    fn test_choker() {
+        let mut choker = Choker::default();
+        let now = Instant::now();
+        // two slots, taken by the first ones asking
+        assert!(choker.request(&peer(1), "a", 2, now));
+        assert!(choker.request(&peer(2), "a", 2, now));
+        assert!(!choker.request(&peer(3), "a", 2, now));
+        assert!(!choker.request(&peer(4), "b", 2, now));
+
+        // 3 and 4 upload to us, 1 and 2 do not; 5 is new
+        assert!(!choker.request(&peer(5), "a", 2, now));
+        let uploaded = |key: &str| match key {
+            "10.0.0.1:3" => Some(100.0),
+            "10.0.0.1:4" => Some(50.0),
+            "10.0.0.1:1" | "10.0.0.1:2" => Some(0.0),
+            _ => None,
+        };
+        let messages = choker.rechoke(2, now, uploaded, 7);
+        assert!(choker.request(&peer(3), "a", 2, now));
+        assert!(choker.request(&peer(4), "b", 2, now));
+        // the newcomer is the optimistic unchoke
+        assert!(choker.request(&peer(5), "a", 2, now));
+        assert!(!choker.request(&peer(1), "a", 2, now));
+        assert_eq!(messages.len(), 5);
+        assert!(messages.contains(&(
+            peer(1),
+            Message::Choke {
+                key: "a".to_string()
+            }
+        )));
+        assert!(messages.contains(&(
+            peer(4),
+            Message::Unchoke {
+                key: "b".to_string()
+            }
+        )));
+        assert!(messages.contains(&(
+            peer(5),
+            Message::Unchoke {
+                key: "a".to_string()
+            }
+        )));
+
+        // nothing changes, nothing is sent; idle peers are forgotten
+        assert!(choker.rechoke(2, now, uploaded, 7).is_empty());
+        let later = now + CHOKE_PERIOD * IDLE_ROUNDS;
+        choker.request(&peer(1), "a", 2, later);
+        let messages = choker.rechoke(2, later, uploaded, 7);
+        assert!(choker.interested.len() == 1 && choker.unchoked.contains("10.0.0.1:1"));
+        assert!(messages.contains(&(
+            peer(1),
+            Message::Unchoke {
+                key: "a".to_string()
+            }
+        )));
+    }
+
+    #[test]
+    fn test_choked() {
+        let (peer_key, file_key) = ("choke_test:1", "file");
+        assert!(!is_choked(peer_key, file_key));
+        set_choked(peer_key, file_key);
+        assert!(is_choked(peer_key, file_key));
+        wait_unchoke(peer_key, file_key, TaskHandle::default());
+        assert_eq!(set_unchoked(peer_key, file_key).len(), 1);
+        assert!(!is_choked(peer_key, file_key));
+    }
+}
@@ -1,47 +1,17 @@
 //! communication between the peer and the tracker
-use crate::data::MetaFile;
+use crate::back::is_stream_open;
+use crate::data::{get_peer_id, host_port, MetaFile, PeerConfig};
 use crate::db::{get_leeching_files, get_seeding_files};
+use crate::protocol::{Framing, Message, ProtocolError, SizeOp, PROTOCOL_VERSION};
+use crate::transport::Transport;
 use core::cmp::min;
 use log::{debug, error, info, warn};
 use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::net::TcpStream;
 use std::time::Duration;
-use crate::back::is_stream_open;
-
-// format the data message
-pub fn dataf(key: &String, pieces: Vec<String>) -> String {
-    format!("data {} [{}]\n", key, pieces.join(" "))
-}
-
-// format the getpieces msg
-pub fn getpiecesf(key: String, pieces: Vec<usize>) -> String {
-    let indexes_str = pieces
-        .iter()
-        .map(|&index| index.to_string())
-        .collect::<Vec<_>>()
-        .join(" ");
-
-    format!("getpieces {} [{}]\n", key.trim(), indexes_str)
-}
-
-// format the interested message
-pub fn interestedf(key: String) -> String {
-    format!("interested {}\n", key)
-}
 
-// format the have msg
-pub fn havef(key: String, buffermap: Vec<u8>) -> String {
-    // convert [0, 0, 1, 0] to 0010
-    let buffermap = buffermap
-        .iter()
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>()
-        .join("");
-
-    let message: String = format!("have {} {}\n", key, buffermap);
-
-    message
-}
+/// How long to wait for the rest of a binary answer once it has started
+const FRAME_TIMEOUT_MS: u64 = 10000;
 
 // # Examples
 //
@@ -61,8 +31,8 @@ pub fn havef(key: String, buffermap: Vec<u8>) -> String {
 //     },
 // ];
 // let peer_port = "8000".to_string();
-// let leeched_files = "file3.txt".to_string();
-// let message = seed(seeded_files, peer_port, leeched_files);
+// let leeched_files = vec!["file3.txt".to_string()];
+// let message = seedf(seeded_files, peer_port, leeched_files);
 // println!("{}", message);
 // ```
 //
@@ -72,96 +42,67 @@ pub fn havef(key: String, buffermap: Vec<u8>) -> String {
 // announce listen 8000 seed [file1.txt 100 10 abc123 file2.txt 200 20 def456] leech [file3.txt]
 // ```
 
-/// Formats a seeding announcement message.
+/// Builds a seeding announcement message.
 ///
-/// This function takes a vector of MetaFile objects seeded, a peer port, and a string leeched.
-/// It formats a seeding announcement message by transforming the MetaFile objects into a string representation,
-/// and then concatenating them into the announcement message.
+/// This function takes a vector of MetaFile objects seeded, a peer port, and the keys leeched.
+/// Empty leeched keys are ignored.
 ///
 /// # Arguments
 /// * `seeded` - A vector of MetaFile objects that are being seeded.
 /// * `peer_port` - A string representing the peer port.
-/// * `leeched` - A string representing the leeched information.
+/// * `leeched` - The keys of the files being leeched.
 ///
 /// # Returns
-/// * `String` - The formatted seeding announcement message.
-// format the seed message
-pub fn seedf(seeded: Vec<MetaFile>, peer_port: String, leeched: Vec<String>) -> String {
+/// * `Message` - The announce message.
+pub fn seedf(seeded: Vec<MetaFile>, peer_port: String, leeched: Vec<String>) -> Message {
     // why is seeded different from leached (type) ? Because of sujet
-    /*
-    into_iter() : transform the vector into an iterator
-    map() : apply a function to each element of the iterator
-    collect() : transform the iterator into a vector
-    join() : concatenate the elements of the vector into a single string
-    d
-     */
-    let seeded_string: Vec<String> = seeded
-        .into_iter()
-        .map(|file| {
-            format!(
-                "{} {} {} {}",
-                file.file_name, file.length, file.piece_size, file.hash
-            )
-        })
-        .collect();
-
-    let msg = format!(
-        "announce listen {} seed [{}] leech [{}]\r\n",
-        peer_port,
-        seeded_string.join(" "),
-        leeched.join(" "),
-    );
-    msg
+    Message::Announce {
+        port: peer_port.trim().parse().unwrap_or(0),
+        seed: seeded,
+        leech: leeched.into_iter().filter(|key| !key.is_empty()).collect(),
+    }
 }
 
-/// Formats a "look" message with a given filename and filesize.
+/// Builds a "look" message with a given filename and filesize.
 ///
-/// This function takes a filename and a filesize as strings.
-/// It formats a "look" message by concatenating the filename and filesize into the message.
+/// This function takes a filename and a filesize criterion as typed by the user.
+/// The filesize criterion is an operator followed by a size, quoted or not (Ex: `<"10"`).
+/// An empty string means no criterion.
 ///
 /// # Arguments
 /// * `filename` - A string representing the filename.
-/// * `filesize` - A string representing the filesize.
+/// * `filesize` - A string representing the filesize criterion.
 ///
 /// # Returns
-/// * `String` - The formatted "look" message.
-// format the look message
-pub fn lookf(filename: String, filesize: String) -> String {
-    let mut res: String = "look [".to_string();
-    let mut b: bool = false;
-    if !filename.is_empty() {
-        res = format!("{}filename=\"{}\"", res, filename);
-        b = true;
-    }
-    if !filesize.is_empty() {
-        if b {
-            res = format!("{} filesize{}", res, filesize);
-        } else {
-            res = format!("{}filesize{}", res, filesize); // why do we add filesize if its empty ?
+/// * `Message` - The look message.
+pub fn lookf(filename: String, filesize: String) -> Message {
+    let filename = Some(filename).filter(|name| !name.is_empty());
+    let filesize = filesize.trim();
+    let filesize = filesize.chars().next().and_then(|op| {
+        let op = match SizeOp::from_char(op) {
+            Some(op) => op,
+            None => {
+                warn!("Unknown filesize operator {}, ignoring criterion", op);
+                return None;
+            }
+        };
+        let size = filesize[1..].trim_matches(&['"', '\''] as &[_]);
+        match size.parse::<usize>() {
+            Ok(size) => Some((op, size)),
+            Err(_) => {
+                warn!("Filesize {} is not a number, ignoring criterion", size);
+                None
+            }
         }
-    }
-    format!("{}]\n", res)
-}
-
-/// Formats a "getfile" request message with a given key.
-///
-/// This function takes a key as a string and formats a "getfile" request message by inserting the key into the message.
-///
-/// # Arguments
-/// * `key` - A string representing the key.
-///
-/// # Returns
-/// * `String` - The formatted "getfile" request message.
-// format the getfile message
-pub fn getfilef(key: String) -> String {
-    format!("getfile {}\n", key)
+    });
+    Message::Look { filename, filesize }
 }
 
 /// Establishes a TCP connection to a given address and port.
 ///
 /// This function takes a port number and an address as arguments,
 /// and attempts to establish a TCP connection to the specified address and port.
-/// If the connection is successful, it returns an `Option` containing the `TcpStream`.
+/// If the connection is successful, it returns an `Option` containing the connection.
 /// If the connection fails, it logs an error message and returns `None`.
 ///
 /// # Arguments
@@ -169,16 +110,17 @@ pub fn getfilef(key: String) -> String {
 /// * `adress` - A string slice representing the address.
 ///
 /// # Returns
-/// * `Option<TcpStream>` - The established TCP connection, or `None` if the connection failed.
-pub fn connect(port: u16, adress: &str) -> Option<TcpStream> {
-    let stream = TcpStream::connect(format!("{}:{}", adress, port));
+/// * `Option<Box<dyn Transport>>` - The established TCP connection, or `None` if the connection failed.
+pub fn connect(port: u16, adress: &str) -> Option<Box<dyn Transport>> {
+    // an IPv6 address is given without brackets, the tuple resolves it as is
+    let stream = TcpStream::connect((adress, port));
     match stream {
         Ok(stream) => {
-            info!("Connected to {}:{}", adress, port);
-            Some(stream)
+            info!("Connected to {}", host_port(adress, port));
+            Some(Box::new(stream))
         }
         Err(e) => {
-            error!("{} Could not connect to {}:{}", e, adress, port);
+            error!("{} Could not connect to {}", e, host_port(adress, port));
             None
         }
     }
@@ -186,55 +128,202 @@ pub fn connect(port: u16, adress: &str) -> Option<TcpStream> {
 
 /// Sends a message to a given address and port.
 ///
-/// This function takes a mutable reference to a `TcpStream` and a message as a string.
-/// It sends the message to the address and port associated with the `TcpStream`.
-/// If the message is successfully sent, it logs an informational message.
+/// This function takes a mutable reference to a `Transport` and a message.
+/// It encodes the message and sends it to the address and port associated with the `Transport`.
+/// If the message is successfully sent, it logs a debug message.
 ///
 /// # Arguments
-/// * `stream` - A mutable reference to a `TcpStream`.
-/// * `message` - A string representing the message to be sent.
-pub fn send(stream: &mut TcpStream, message: String) {
+/// * `stream` - A mutable reference to a `Transport`.
+/// * `message` - The message to be sent.
+pub fn send(stream: &mut dyn Transport, message: &Message) {
+    send_framed(stream, message, Framing::Text);
+}
 
+/// Sends a message using the framing negotiated on the connection.
+///
+/// # Arguments
+/// * `stream` - A mutable reference to a `Transport`.
+/// * `message` - The message to be sent.
+/// * `framing` - The framing of the connection.
+pub fn send_framed(stream: &mut dyn Transport, message: &Message, framing: Framing) {
+    send_raw(stream, &message.encode_with(framing));
+}
+
+/// Sends raw bytes to a given address and port.
+///
+/// # Arguments
+/// * `stream` - A mutable reference to a `Transport`.
+/// * `bytes` - The bytes to be sent.
+pub fn send_raw(stream: &mut dyn Transport, bytes: &[u8]) {
     if !is_stream_open(stream) {
         warn!("Trying to send to closed stream");
         return;
     }
-    stream.write(message.as_bytes()).unwrap();
+    if let Err(e) = stream.write_all(bytes) {
+        error!("Could not send to {:?} because of {}", stream.peer_addr(), e);
+        return;
+    }
     debug!(
-        "Sending to {} : {}",
-        stream.peer_addr().unwrap(),
-        message.chars().take(128).collect::<String>()
+        "Sending to {:?} : {}",
+        stream.peer_addr(),
+        String::from_utf8_lossy(&bytes[0..min(128, bytes.len())]) // only shows the first 128 chars
     );
 }
 
 /// Receives a message from a given address and port.
 ///
-/// This function takes a mutable reference to a `TcpStream`.
-/// It reads a message from the address and port associated with the `TcpStream` into a buffer.
-/// If the message is successfully read, it logs an informational message and returns the message as a string.
-/// If the message cannot be read, it logs an error message and returns an empty string.
+/// This function takes a mutable reference to a `Transport`.
+/// It reads one line from the address and port associated with the `Transport` and decodes it.
 ///
 /// # Arguments
-/// * `stream` - A mutable reference to a `TcpStream`.
+/// * `stream` - A mutable reference to a `Transport`.
+/// * `timeout_ms` - How long to wait for the first byte.
 ///
 /// # Returns
-/// * `String` - The message received from the `TcpStream`, or an empty string if the message could not be read.
-pub fn receive(stream: &mut TcpStream, timeout_ms: u64) -> String {
+/// * `Result<Message, ProtocolError>` - The decoded message, `ProtocolError::Empty` if nothing was received.
+pub fn receive_message(stream: &mut dyn Transport, timeout_ms: u64) -> Result<Message, ProtocolError> {
+    let line = receive(stream, timeout_ms);
+    let message = Message::decode(&line);
+    if let Err(e) = &message {
+        if *e != ProtocolError::Empty {
+            warn!(
+                "Could not decode {} : {}",
+                String::from_utf8_lossy(&line[0..min(128, line.len())]),
+                e
+            );
+        }
+    }
+    message
+}
+
+/// Receives a message on a connection using `framing`.
+///
+/// In binary framing the answer may either be binary data frames or a text line,
+/// the first byte tells them apart.
+///
+/// # Arguments
+/// * `stream` - A mutable reference to a `Transport`.
+/// * `timeout_ms` - How long to wait for the first byte.
+/// * `framing` - The framing of the connection.
+/// * `max_pieces` - How many pieces were asked for, binary answers holding more are refused.
+/// * `piece_size` - The size of the pieces of the file, longer binary pieces are refused.
+///
+/// # Returns
+/// * `Result<Message, ProtocolError>` - The decoded message, `ProtocolError::Empty` if nothing was received.
+pub fn receive_framed(
+    stream: &mut dyn Transport,
+    timeout_ms: u64,
+    framing: Framing,
+    max_pieces: usize,
+    piece_size: usize,
+) -> Result<Message, ProtocolError> {
+    if framing == Framing::Text {
+        return receive_message(stream, timeout_ms);
+    }
+    if !is_stream_open(stream) {
+        warn!("Trying to receive from closed stream");
+        return Err(ProtocolError::Empty);
+    }
+    let mut first = [0u8; 1];
+    let peeked = stream
+        .set_read_timeout(Some(Duration::from_millis(timeout_ms)))
+        .and_then(|_| stream.peek(&mut first));
+    match peeked {
+        Ok(0) => Err(ProtocolError::Empty),
+        Ok(_) if Message::is_frame_start(first[0]) => {
+            // the whole answer is on its way, do not give up in the middle of a piece
+            if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(FRAME_TIMEOUT_MS)))
+            {
+                error!("Could not set read timeout : {}", e);
+                return Err(ProtocolError::Empty);
+            }
+            let message = Message::read_frames(stream, max_pieces, piece_size);
+            match &message {
+                Ok(Message::Data { pieces, .. }) => debug!(
+                    "Received {} binary pieces ({} bytes) from {:?}",
+                    pieces.len(),
+                    pieces.iter().map(|(_, piece)| piece.len()).sum::<usize>(),
+                    stream.peer_addr()
+                ),
+                Ok(_) => {}
+                Err(e) => warn!("Could not read frames from {:?} : {}", stream.peer_addr(), e),
+            }
+            message
+        }
+        Ok(_) => receive_message(stream, timeout_ms),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            Err(ProtocolError::Empty)
+        }
+        Err(e) => {
+            error!("Could not receive from {:?} because of {}", stream.peer_addr(), e);
+            Err(ProtocolError::Empty)
+        }
+    }
+}
+
+/// Tells if something arrived on a stream, without waiting for it.
+///
+/// # Arguments
+/// * `stream` - A reference to a `Transport`.
+///
+/// # Returns
+/// * `Option<bool>` - Whether a message is waiting, None if the connection is closed or broken.
+pub fn poll_readable(stream: &dyn Transport) -> Option<bool> {
+    if let Err(e) = stream.set_nonblocking(true) {
+        error!("Could not poll stream : {}", e);
+        return None;
+    }
+    let mut byte = [0u8; 1];
+    let readable = match stream.peek(&mut byte) {
+        Ok(0) => None,
+        Ok(_) => Some(true),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Some(false),
+        Err(_) => None,
+    };
+    if let Err(e) = stream.set_nonblocking(false) {
+        error!("Could not poll stream : {}", e);
+        return None;
+    }
+    readable
+}
+
+/// Receives a line from a given address and port.
+///
+/// This function takes a mutable reference to a `Transport`.
+/// It reads a line from the address and port associated with the `Transport` into a buffer.
+/// If the line is successfully read, it logs a debug message and returns it.
+/// If the line cannot be read, it logs an error message and returns an empty buffer.
+///
+/// # Arguments
+/// * `stream` - A mutable reference to a `Transport`.
+/// * `timeout_ms` - How long to wait for the first byte.
+///
+/// # Returns
+/// * `Vec<u8>` - The line received from the `Transport`, or an empty buffer if it could not be read.
+pub fn receive(stream: &mut dyn Transport, timeout_ms: u64) -> Vec<u8> {
     if !is_stream_open(stream) {
         warn!("Trying to receive from closed stream");
-        return "".to_string();
+        return Vec::new();
     }
 
     let mut buffer: Vec<u8> = Vec::new();
-    let port = stream.peer_addr().unwrap().port();
-    let ip = stream.peer_addr().unwrap().ip();
+    let (ip, port) = match stream.peer_addr() {
+        Ok(addr) => (addr.ip(), addr.port()),
+        Err(e) => {
+            warn!("Trying to receive from disconnected stream : {}", e);
+            return Vec::new();
+        }
+    };
     let mut reader = BufReader::new(stream);
     debug!("About to read from {}:{}", ip, port);
-    // implement timeout so that this method doesnt block, 1s timeout
-    reader
+    // implement timeout so that this method doesnt block
+    if let Err(e) = reader
         .get_ref()
         .set_read_timeout(Some(Duration::from_millis(timeout_ms)))
-        .unwrap();
+    {
+        error!("Could not set read timeout on {}:{} : {}", ip, port, e);
+        return Vec::new();
+    }
 
     loop {
         match reader.read_until(b'\n', &mut buffer) {
@@ -245,10 +334,11 @@ pub fn receive(stream: &mut TcpStream, timeout_ms: u64) -> String {
                     if timeout_ms > 2000 {
                         warn!("Didn't receive any data from {}:{}", ip, port);
                     }
-                    return "".to_string();
+                    return buffer;
                 } else {
-                    // Data was read, continue processing the buffer
-                    debug!("Msg partially read, will continue reading");
+                    // Connection closed after a partial message, hand out what we have
+                    debug!("Msg partially read before the connection was closed");
+                    return buffer;
                 }
             }
             Ok(_) => {
@@ -258,27 +348,24 @@ pub fn receive(stream: &mut TcpStream, timeout_ms: u64) -> String {
                     port,
                     String::from_utf8_lossy(&buffer[0..min(128, buffer.len())]) // only shows the first 128 chars
                 );
-                return buffer
-                    .iter()
-                    .map(|&c| char::from_u32(c as u32).unwrap())
-                    .collect::<String>();
+                return buffer;
             }
             Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut {
                     // Timeout occurred, check if any data was read
                     if buffer.is_empty() {
                         // No data was read, continue with an empty buffer
                         if timeout_ms > 2000 {
                             warn!("Didn't receive any data from {}:{}", ip, port);
                         }
-                        return "".to_string();
+                        return buffer;
                     } else {
                         // Data was read, continue processing the buffer
                         debug!("Msg partially read, will continue reading");
                     }
                 } else {
                     error!("Could not receive from {}:{} because of {}", ip, port, e);
-                    return "".to_string();
+                    return Vec::new();
                 }
             }
         }
@@ -287,47 +374,63 @@ pub fn receive(stream: &mut TcpStream, timeout_ms: u64) -> String {
 
 /// Generates an update message with the current seeding and leeching files.
 ///
-/// This function retrieves the list of seeding and leeching files.
-/// It formats these lists into strings, where each file is represented by its hash and files are separated by spaces.
-/// It then generates an update message containing these formatted lists of seeding and leeching files.
+/// This function retrieves the list of seeding and leeching files
+/// and builds an update message containing their keys.
 ///
 /// # Returns
-/// * `String` - The update message containing the formatted lists of seeding and leeching files.
-// format the update message
-pub fn updatef() -> String {
-    let seeds: Vec<MetaFile> = get_seeding_files();
-    let leeches: Vec<MetaFile> = get_leeching_files();
+/// * `Message` - The update message containing the keys of the seeding and leeching files.
+pub fn updatef() -> Message {
+    let seed: Vec<String> = get_seeding_files().into_iter().map(|f| f.hash).collect();
+    let leech: Vec<String> = get_leeching_files().into_iter().map(|f| f.hash).collect();
+    Message::Update { seed, leech }
+}
 
-    let mut formated_seeds: String = String::new();
-    let mut formated_leeches: String = String::new();
+/// Generates the hello message opening every peer connection.
+///
+/// # Returns
+/// * `Message` - The hello message with the protocol version, our peer id and listening port.
+pub fn hellof() -> Message {
+    Message::Hello {
+        version: PROTOCOL_VERSION,
+        peer_id: get_peer_id(),
+        port: PeerConfig::new().addr.port(),
+    }
+}
 
-    let mut i: bool = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
 
-    for seed in seeds {
-        let hash = seed.hash;
-        formated_seeds += &hash;
-        if i {
-            formated_seeds += " ";
-        }
-        i = true;
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
     }
 
-    i = false;
+    #[test]
+    fn test_receive_framed() {
+        let (mut client, mut server) = stream_pair();
+        let data = Message::Data {
+            key: "aaaa".to_string(),
+            pieces: vec![(3, b"raw\nbytes".to_vec())],
+        };
 
-    for leech in leeches {
-        let hash = leech.hash;
-        formated_leeches += &hash;
-        if i {
-            formated_leeches += " ";
-        }
-        i = true;
-    }
+        send_framed(&mut server, &data, Framing::Binary);
+        assert_eq!(receive_framed(&mut client, 1000, Framing::Binary, 1, 1024), Ok(data));
 
-    format!(
-        "update seed [{}] leech [{}]\n",
-        formated_seeds, formated_leeches
-    )
-}
+        // text answers are still understood on a binary connection
+        send_framed(&mut server, &Message::Ok, Framing::Binary);
+        assert_eq!(
+            receive_framed(&mut client, 1000, Framing::Binary, 1, 1024),
+            Ok(Message::Ok)
+        );
 
-#[cfg(test)]
-mod tests {}
+        assert_eq!(
+            receive_framed(&mut client, 100, Framing::Binary, 1, 1024),
+            Err(ProtocolError::Empty)
+        );
+    }
+}
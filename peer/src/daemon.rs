@@ -0,0 +1,533 @@
+//! control of a running peer through JSON-RPC 2.0 over a unix socket
+//!
+//! Requests and responses are one JSON object per line. A connection can
+//! send several requests, and one that calls `subscribe` gets every event
+//! as a `event` notification until it is closed.
+use crate::back::announce;
+use crate::bitfield::Bitfield;
+use crate::data::{get_config_path, MetaFile, PeerConfig, TrackerConfig};
+use crate::db::{get_file, get_file_buffermaps, get_files, remove_peer_to_file};
+use crate::events::{subscribe, Event};
+use crate::menu::{queue_download, upload_section, DownloadError, SeedError};
+use crate::peerstats::get_stats;
+use crate::selector::{get_strategy, Strategy};
+use crate::state::{
+    get_state_dir, get_tracked, is_paused, pause_download, save_state, unpause_download,
+    untrack_file,
+};
+use crate::threads::Pool;
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// errors of the peer itself
+const TRACKER_ERROR: i64 = -32000;
+const UNKNOWN_FILE: i64 = -32001;
+const NO_PEER: i64 = -32002;
+const IO_ERROR: i64 = -32003;
+
+lazy_static! {
+    static ref CONTROL_SOCKET: Mutex<Option<String>> = Mutex::new(None);
+}
+
+pub fn set_control_socket(path: String) {
+    let mut socket = CONTROL_SOCKET.lock().unwrap();
+    *socket = Some(path);
+}
+
+/// Returns the control socket, given on the command line or by `control-socket` in the Peer section.
+///
+/// Defaults to `control.sock` in the state directory.
+pub fn get_control_socket() -> PathBuf {
+    if let Some(path) = CONTROL_SOCKET.lock().unwrap().clone() {
+        return PathBuf::from(path);
+    }
+    Ini::load_from_file(get_config_path())
+        .ok()
+        .and_then(|conf| {
+            conf.section(Some("Peer"))
+                .and_then(|section| section.get("control-socket"))
+                .map(PathBuf::from)
+        })
+        .unwrap_or_else(|| get_state_dir().join("control.sock"))
+}
+
+/// What the requests act on
+#[derive(Debug, Clone)]
+pub struct Daemon {
+    pub tracker_config: TrackerConfig,
+    pub pool: Pool,
+    pub length_tcp: usize,
+}
+
+// error member of a response
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<DownloadError> for RpcError {
+    fn from(e: DownloadError) -> Self {
+        let code = match e {
+            DownloadError::Tracker => TRACKER_ERROR,
+            DownloadError::UnknownFile => UNKNOWN_FILE,
+            DownloadError::NoPeer => NO_PEER,
+            DownloadError::Output(_) => IO_ERROR,
+        };
+        RpcError::new(code, e)
+    }
+}
+
+impl From<SeedError> for RpcError {
+    fn from(e: SeedError) -> Self {
+        let code = match e {
+            SeedError::File(..) => IO_ERROR,
+            SeedError::Tracker => TRACKER_ERROR,
+        };
+        RpcError::new(code, e)
+    }
+}
+
+// binds the socket in a directory only we can enter, then moves it in place
+// with owner-only permissions, so no other user can connect in between
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let dir: PathBuf = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let hidden: PathBuf = dir.join("control.sock");
+    let bound = UnixListener::bind(&hidden).and_then(|listener| {
+        fs::set_permissions(&hidden, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&hidden, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    bound
+}
+
+impl Daemon {
+    /// Serves the control socket until the client is killed.
+    ///
+    /// Only the user running the daemon can connect to the socket.
+    ///
+    /// # Returns
+    /// * `io::Result<()>` - An error if the socket could not be bound,
+    ///   for instance because another daemon uses it.
+    pub fn serve(&self, path: &Path) -> io::Result<()> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a daemon already listens on {}", path.display()),
+                ));
+            }
+            // only a socket left by a daemon that did not stop cleanly is removed
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let listener = bind_private(path)?;
+        info!("Control socket listening on {}", path.display());
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let daemon = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = daemon.handle_connection(stream) {
+                            debug!("Control connection closed : {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("{}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (response, subscribed) = self.handle_line(&line);
+            // subscribed before answering so no event is missed
+            let events = subscribed.then(subscribe);
+            if let Some(response) = response {
+                writeln!(writer, "{}", response)?;
+            }
+            if let Some(events) = events {
+                // the connection only gets events from now on
+                for event in events {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "event",
+                        "params": event_to_json(&event),
+                    });
+                    writeln!(writer, "{}", notification)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers one line of a control connection.
+    ///
+    /// # Returns
+    /// * `(Option<Value>, bool)` - The response, None for a notification,
+    ///   and whether the connection subscribed to the events.
+    fn handle_line(&self, line: &str) -> (Option<Value>, bool) {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                return (
+                    Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+                    false,
+                )
+            }
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) if request.get("jsonrpc") == Some(&json!("2.0")) => method,
+            _ => {
+                let e = RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request");
+                return (Some(error_response(id.unwrap_or(Value::Null), e)), false);
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        debug!("Control request {} {}", method, params);
+
+        let result = self.call(method, &params);
+        let subscribed = method == "subscribe" && result.is_ok();
+        let response = id.map(|id| match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, e),
+        });
+        (response, subscribed)
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "seed" => self.seed(params),
+            "start" => self.start(params),
+            "pause" => {
+                let key = key_param(params)?;
+                pause_download(&key);
+                info!("Download of {} paused", key);
+                Ok(json!(true))
+            }
+            "cancel" => self.cancel(params),
+            "list" => Ok(Value::Array(
+                get_tracked().iter().map(transfer_to_json).collect(),
+            )),
+            "files" => Ok(Value::Array(get_files().iter().map(file_to_json).collect())),
+            "peers" => {
+                let key = key_param(params)?;
+                let peers: Vec<Value> = get_file_buffermaps(&key)
+                    .into_iter()
+                    .map(|(peer, buffermap)| {
+                        let stats = get_stats(&peer);
+                        json!({
+                            "peer": peer,
+                            "pieces": buffermap.count_ones(),
+                            "total": buffermap.len(),
+                            "throughput": stats.throughput.map(|bytes| bytes.round() as u64),
+                            "latency_ms": stats.latency.map(|latency| latency.as_millis() as u64),
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(peers))
+            }
+            "subscribe" => Ok(json!(true)),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {}", method),
+            )),
+        }
+    }
+
+    // params: {"paths": [...]}
+    fn seed(&self, params: &Value) -> Result<Value, RpcError> {
+        let paths: Vec<String> = params
+            .get("paths")
+            .and_then(Value::as_array)
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter_map(|p| p.as_str().map(String::from))
+                    .collect()
+            })
+            .filter(|paths: &Vec<String>| !paths.is_empty())
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Expected {\"paths\": [...]}"))?;
+        if let Some(missing) = paths.iter().find(|path| !Path::new(path).is_file()) {
+            return Err(RpcError::new(
+                IO_ERROR,
+                format!("File {} does not exist", missing),
+            ));
+        }
+        let keys: Vec<String> = upload_section(
+            self.tracker_config.port,
+            &self.tracker_config.address,
+            paths,
+        )?
+        .into_iter()
+        .map(|file| file.hash)
+        .collect();
+        Ok(json!(keys))
+    }
+
+    // params: {"key": ..., "out": ..., "strategy": ...}, also resumes a paused download
+    fn start(&self, params: &Value) -> Result<Value, RpcError> {
+        let key = key_param(params)?;
+        let out = params.get("out").and_then(Value::as_str);
+        let strategy: Option<Strategy> = match params.get("strategy").and_then(Value::as_str) {
+            Some(name) => Some(name.parse().map_err(|e| RpcError::new(INVALID_PARAMS, e))?),
+            None => None,
+        };
+        unpause_download(&key);
+        queue_download(
+            &self.tracker_config,
+            self.pool.clone(),
+            self.length_tcp,
+            &key,
+            out,
+            strategy,
+        )?;
+        Ok(json!(true))
+    }
+
+    // stops a download and forgets it, the pieces already written stay on disk
+    fn cancel(&self, params: &Value) -> Result<Value, RpcError> {
+        let key = key_param(params)?;
+        if get_file(&key).is_none() {
+            return Err(RpcError::new(UNKNOWN_FILE, format!("Unknown file {}", key)));
+        }
+        pause_download(&key);
+        untrack_file(&key);
+        remove_peer_to_file(PeerConfig::new(), key.clone());
+        if let Err(e) = save_state() {
+            warn!("Could not save state : {}", e);
+        }
+        announce(self.tracker_config.port, &self.tracker_config.address);
+        info!("Download of {} cancelled", key);
+        Ok(json!(true))
+    }
+}
+
+fn key_param(params: &Value) -> Result<String, RpcError> {
+    params
+        .get("key")
+        .and_then(Value::as_str)
+        .map(String::from)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Expected {\"key\": ...}"))
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": e.code, "message": e.message },
+    })
+}
+
+fn file_to_json(file: &MetaFile) -> Value {
+    json!({
+        "key": file.hash,
+        "file_name": file.file_name,
+        "length": file.length,
+        "piece_size": file.piece_size,
+    })
+}
+
+fn transfer_to_json((file, written): &(MetaFile, Bitfield)) -> Value {
+    let status = if written.is_full() {
+        "seeding"
+    } else if is_paused(&file.hash) {
+        "paused"
+    } else {
+        "downloading"
+    };
+    let mut transfer = file_to_json(file);
+    transfer["status"] = json!(status);
+    transfer["strategy"] = json!(get_strategy(&file.hash).to_string());
+    transfer["pieces"] = json!(written.count_ones());
+    transfer["total"] = json!(written.len());
+    transfer
+}
+
+fn event_to_json(event: &Event) -> Value {
+    match event {
+        Event::DownloadComplete { key, file_name } => json!({
+            "event": "download-complete",
+            "key": key,
+            "file_name": file_name,
+        }),
+        Event::VerificationFailed {
+            key,
+            file_name,
+            pieces,
+        } => json!({
+            "event": "verification-failed",
+            "key": key,
+            "file_name": file_name,
+            "pieces": pieces,
+        }),
+    }
+}
+
+/// Sends one request to a running daemon.
+///
+/// # Arguments
+/// * `path` - The control socket of the daemon.
+/// * `method` - The method to call.
+/// * `params` - Its parameters.
+///
+/// # Returns
+/// * `io::Result<(Value, BufReader<UnixStream>)>` - The response, and the connection
+///   to read the notifications from after a `subscribe`.
+pub fn call(
+    path: &Path,
+    method: &str,
+    params: Value,
+) -> io::Result<(Value, BufReader<UnixStream>)> {
+    let mut stream = UnixStream::connect(path)?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writeln!(stream, "{}", request)?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "daemon closed the connection",
+        ));
+    }
+    let response = serde_json::from_str(&line)?;
+    Ok((response, reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::emit;
+
+    fn daemon() -> Daemon {
+        Daemon {
+            tracker_config: TrackerConfig {
+                address: "127.0.0.1".to_string(),
+                port: 1,
+            },
+            pool: Pool::new(0),
+            length_tcp: 1024,
+        }
+    }
+
+    #[test]
+    fn test_handle_line_errors() {
+        let daemon = daemon();
+        let code = |line: &str| daemon.handle_line(line).0.unwrap()["error"]["code"].clone();
+        assert_eq!(code("{"), json!(PARSE_ERROR));
+        assert_eq!(
+            code(r#"{"id": 1, "method": "list"}"#),
+            json!(INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "fly"}"#),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "pause", "params": {}}"#),
+            json!(INVALID_PARAMS)
+        );
+        assert_eq!(
+            code(
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "seed", "params": {"paths": ["no_such_file"]}}"#
+            ),
+            json!(IO_ERROR)
+        );
+        // notifications get no answer
+        assert!(daemon
+            .handle_line(r#"{"jsonrpc": "2.0", "method": "list"}"#)
+            .0
+            .is_none());
+    }
+
+    #[test]
+    fn test_serve() {
+        let path = std::env::temp_dir().join(format!("peer-control-{}.sock", std::process::id()));
+        let server = path.clone();
+        thread::spawn(move || daemon().serve(&server));
+        let mut tries = 0;
+        while UnixStream::connect(&path).is_err() && tries < 100 {
+            thread::sleep(std::time::Duration::from_millis(10));
+            tries += 1;
+        }
+
+        let (response, _) = call(&path, "list", Value::Null).unwrap();
+        assert!(response["result"].is_array());
+        // other users cannot drive the daemon
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(daemon().serve(&path).is_err());
+
+        let (response, mut events) = call(&path, "subscribe", Value::Null).unwrap();
+        assert_eq!(response["result"], json!(true));
+        let event = Event::DownloadComplete {
+            key: "0123456789abcdef0123456789abcdef".to_string(),
+            file_name: "daemon_test.dat".to_string(),
+        };
+        // other tests emit events too
+        events
+            .get_ref()
+            .set_read_timeout(Some(std::time::Duration::from_millis(100)))
+            .unwrap();
+        let mut line = String::new();
+        for _ in 0..50 {
+            emit(event.clone());
+            line.clear();
+            if events.read_line(&mut line).is_ok() && line.contains("daemon_test.dat") {
+                break;
+            }
+        }
+        let notification: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(notification["params"], event_to_json(&event));
+        fs::remove_file(path).unwrap();
+
+        // a file that is not a socket is never removed
+        let file = std::env::temp_dir().join(format!("peer-control-{}.txt", std::process::id()));
+        fs::write(&file, b"not a socket").unwrap();
+        let served = daemon().serve(&file);
+        let kept = fs::read(&file);
+        fs::remove_file(&file).unwrap();
+        assert_eq!(served.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(kept.unwrap(), b"not a socket");
+    }
+}
@@ -1,37 +1,48 @@
 use base64::{engine::general_purpose, Engine as _};
 use ini::Ini;
 use lazy_static::lazy_static;
-use log::{debug, info};
+use log::{debug, info, warn};
 use md5::{Digest, Md5};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 use std::sync::Mutex;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MetaFile {
     pub file_name: String,
     pub length: usize,
     pub piece_size: usize,
     pub hash: String,
+    /// md5 of each piece in hexadecimal, empty until computed or received from a peer
+    pub piece_digests: Vec<String>,
 }
 
 impl MetaFile {
-    pub fn new(file_name: String) -> Self {
+    /// Describes a file on disk, hashing it whole and piece by piece.
+    ///
+    /// # Returns
+    /// * `std::io::Result<Self>` - The metadata, or an error if the file could not be read.
+    pub fn new(file_name: String) -> std::io::Result<Self> {
         let path = Path::new(&file_name);
-        let length = path.metadata().unwrap().len() as usize;
-        MetaFile {
-            hash: get_file_key(&file_name),
+        let length = path.metadata()?.len() as usize;
+        let mut file = MetaFile {
+            hash: get_file_key(&file_name)?,
             file_name,
             length,
             piece_size: 1024,
-        }
+            piece_digests: Vec::new(),
+        };
+        file.piece_digests =
+            get_piece_digests(&file.file_name, file.piece_size, get_buffer_size(&file))?;
+        Ok(file)
     }
 }
 
-#[derive(Debug, Clone)]
+/// Address of a peer, IPv6 ones are written in brackets
+#[derive(Debug, Clone, PartialEq)]
 pub struct PeerConfig {
-    pub address: String,
-    pub port: u16,
+    pub addr: SocketAddr,
 }
 
 #[derive(Clone, Debug)]
@@ -93,6 +104,13 @@ lazy_static! {
     static ref TRACKER_PORT: Mutex<Option<u16>> = Mutex::new(None);
     static ref TRACKER_ADDRESS: Mutex<Option<String>> = Mutex::new(None);
     static ref PEER_PORT: Mutex<Option<u16>> = Mutex::new(None);
+    static ref PEER_ID: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Returns the config file given on the command line, `config.ini` by default.
+pub fn get_config_path() -> String {
+    let lock = CONFIG_PATH.lock().unwrap();
+    lock.clone().unwrap_or_else(|| "config.ini".to_string())
 }
 
 pub fn set_config_path(path: String) {
@@ -121,7 +139,14 @@ impl PeerConfig {
         let conf = Ini::load_from_file(&config_path).unwrap();
         let peer_section = conf.section(Some("Peer")).unwrap();
 
-        let peer_address = peer_section.get("peer-address").unwrap().to_string();
+        // `::` listens on IPv6 and IPv4
+        let peer_address: IpAddr = match peer_section.get("peer-address").unwrap().parse() {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("Wrong peer-address : {}, listening on ::", e);
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            }
+        };
 
         let peer_port = {
             let lock = PEER_PORT.lock().unwrap();
@@ -137,12 +162,74 @@ impl PeerConfig {
             }
         };
         PeerConfig {
-            address: peer_address,
-            port: peer_port,
+            addr: SocketAddr::new(peer_address, peer_port),
+        }
+    }
+}
+
+/// Returns the identifier of this peer, sent in the handshake.
+///
+/// The identifier is read from the file named by `peer-id-file` in the Peer section
+/// of the config (`peer_id` by default). On first start it is drawn from the OS random
+/// generator and written to that file, so the peer keeps the same identifier across restarts.
+///
+/// # Returns
+/// * `String` - 32 hexadecimal characters.
+pub fn get_peer_id() -> String {
+    let mut peer_id = PEER_ID.lock().unwrap();
+    if let Some(id) = peer_id.clone() {
+        return id;
+    }
+    let id = load_peer_id(&get_peer_id_path());
+    *peer_id = Some(id.clone());
+    id
+}
+
+/// Returns the file named by `peer-id-file` in the Peer section, `peer_id` by default.
+fn get_peer_id_path() -> String {
+    // tests must not leave an id next to the sources
+    if cfg!(test) {
+        let path = std::env::temp_dir().join(format!("peer-id-{}", std::process::id()));
+        return path.to_string_lossy().to_string();
+    }
+    Ini::load_from_file(get_config_path())
+        .ok()
+        .and_then(|conf| {
+            conf.section(Some("Peer"))
+                .and_then(|section| section.get("peer-id-file"))
+                .map(|path| path.to_string())
+        })
+        .unwrap_or_else(|| "peer_id".to_string())
+}
+
+/// Reads the peer id kept in a file, or draws a new one and writes it there.
+///
+/// # Arguments
+/// * `id_path` - The file keeping the peer id.
+///
+/// # Returns
+/// * `String` - 32 hexadecimal characters.
+fn load_peer_id(id_path: &str) -> String {
+    match std::fs::read_to_string(id_path) {
+        Ok(id) if is_peer_id(id.trim()) => id.trim().to_string(),
+        _ => {
+            // pinned certificates and swarm proofs are bound to it, it must not be guessable
+            let mut random = [0u8; 16];
+            getrandom::getrandom(&mut random).expect("Could not read the OS random generator");
+            let id = hex::encode(random);
+            match std::fs::write(id_path, &id) {
+                Ok(_) => info!("Generated peer id {} in {}", id, id_path),
+                Err(e) => warn!("Could not save peer id to {} : {}", id_path, e),
+            }
+            id
         }
     }
 }
 
+fn is_peer_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 /// Computes the MD5 hash of a file.
 ///
 /// This function takes a file path as a string.
@@ -153,15 +240,53 @@ impl PeerConfig {
 /// * `path` - A string slice representing the file path.
 ///
 /// # Returns
-/// * `String` - The MD5 hash of the file content.
-pub fn get_file_key(path: &str) -> String {
+/// * `std::io::Result<String>` - The MD5 hash of the file content, or an error if the file could not be read.
+pub fn get_file_key(path: &str) -> std::io::Result<String> {
     let path = Path::new(path);
-    let file = File::open(path).unwrap();
+    let file = File::open(path)?;
     let mut reader = BufReader::new(file);
     let mut hasher = Md5::new();
-    std::io::copy(&mut reader, &mut hasher).unwrap();
+    std::io::copy(&mut reader, &mut hasher)?;
     let result = hasher.finalize();
-    format!("{:x}", result)
+    Ok(format!("{:x}", result))
+}
+
+/// Computes the MD5 hash of each piece of a file.
+///
+/// # Arguments
+/// * `path` - A string slice representing the file path.
+/// * `piece_size` - The size of a piece.
+/// * `nb_pieces` - The number of pieces, the last ones may be short or empty.
+///
+/// # Returns
+/// * `std::io::Result<Vec<String>>` - The hash of each piece, or an error if the file could not be read.
+pub fn get_piece_digests(
+    path: &str,
+    piece_size: usize,
+    nb_pieces: usize,
+) -> std::io::Result<Vec<String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut digests: Vec<String> = Vec::with_capacity(nb_pieces);
+    let mut piece: Vec<u8> = Vec::with_capacity(piece_size);
+    for _ in 0..nb_pieces {
+        piece.clear();
+        (&mut reader)
+            .take(piece_size as u64)
+            .read_to_end(&mut piece)?;
+        digests.push(get_piece_digest(&piece));
+    }
+    Ok(digests)
+}
+
+/// Computes the MD5 hash of a piece.
+///
+/// # Arguments
+/// * `piece` - The content of the piece.
+///
+/// # Returns
+/// * `String` - The MD5 hash in hexadecimal.
+pub fn get_piece_digest(piece: &[u8]) -> String {
+    format!("{:x}", Md5::digest(piece))
 }
 
 /// Computes the buffer size for a file.
@@ -180,9 +305,24 @@ pub fn b64_enc(data: Vec<u8>) -> String {
     general_purpose::STANDARD.encode(data)
 }
 
-/// Return the decoded string from bytes array
-pub fn b64_dec(base64_string: String) -> Vec<u8> {
-    general_purpose::STANDARD.decode(base64_string).unwrap()
+/// Return the decoded bytes array from a base64 string
+pub fn b64_dec(base64_string: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    general_purpose::STANDARD.decode(base64_string)
+}
+
+/// Joins a host and a port, putting IPv6 addresses in brackets.
+///
+/// # Arguments
+/// * `address` - A domain name or an IP address, IPv6 ones without brackets.
+/// * `port` - The port.
+///
+/// # Returns
+/// * `String` - `$Host:$Port`, or `[$Ipv6]:$Port`.
+pub fn host_port(address: &str, port: u16) -> String {
+    match address.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]:{}", address, port),
+        Err(_) => format!("{}:{}", address, port),
+    }
 }
 
 /// Gets the hash of a file.
@@ -204,7 +344,65 @@ mod tests {
     fn test_peer_config_new() {
         // Set up the test
         let peer_config = PeerConfig::new();
-        assert_eq!(peer_config.address, "0.0.0.0");
-        assert_eq!(peer_config.port, 54321);
+        assert_eq!(peer_config.addr, "[::]:54321".parse().unwrap());
+    }
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!(host_port("jibelibeju.fr", 12345), "jibelibeju.fr:12345");
+        assert_eq!(host_port("127.0.0.1", 80), "127.0.0.1:80");
+        assert_eq!(host_port("2001:db8::1", 80), "[2001:db8::1]:80");
+    }
+
+    #[test]
+    fn test_piece_digests() {
+        let path = "test_piece_digests.txt";
+        std::fs::write(path, b"Hello, world!").unwrap();
+        let digests = get_piece_digests(path, 5, 4).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            digests,
+            vec![
+                get_piece_digest(b"Hello"),
+                get_piece_digest(b", wor"),
+                get_piece_digest(b"ld!"),
+                get_piece_digest(b""),
+            ]
+        );
+        assert_eq!(get_piece_digest(b""), "d41d8cd98f00b204e9800998ecf8427e");
+    }
+
+    #[test]
+    fn test_metafile_new() {
+        let path = "test_metafile_new.txt";
+        std::fs::write(path, b"Hello, world!").unwrap();
+        let file = MetaFile::new(path.to_string());
+        std::fs::remove_file(path).unwrap();
+        let file = file.unwrap();
+        assert_eq!(file.length, 13);
+        assert_eq!(file.hash, "6cd3556deb0da54bca060b4c39479839");
+        assert_eq!(file.piece_digests, vec![get_piece_digest(b"Hello, world!")]);
+        // a file gone before being hashed is an error, not a panic
+        assert!(MetaFile::new(path.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_peer_id_is_stable() {
+        let id = get_peer_id();
+        assert!(is_peer_id(&id));
+        assert_eq!(get_peer_id(), id);
+
+        // a new id is saved, then read back on the next start
+        let path = std::env::temp_dir().join(format!("peer-id-stable-{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let id = load_peer_id(&path);
+        let saved = std::fs::read_to_string(&path);
+        let loaded = load_peer_id(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_peer_id(&id));
+        assert_eq!(saved.unwrap(), id);
+        assert_eq!(loaded, id);
+        assert!(!is_peer_id("1234"));
+        assert!(!is_peer_id("zz3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b"));
     }
 }
@@ -1,3 +1,4 @@
+use crate::bitfield::Bitfield;
 use crate::data::*;
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
@@ -17,8 +18,11 @@ use std::sync::Mutex;
 lazy_static! {
     static ref PEERSDB: Mutex<HashMap<String, PeerConfig>> = Mutex::new(HashMap::new());
     static ref FILEDB: Mutex<HashMap<String, MetaFile>> = Mutex::new(HashMap::new());
-    static ref BUFFERMAPDB: Mutex<HashMap<String, HashMap<String, Vec<u8>>>> =
+    static ref BUFFERMAPDB: Mutex<HashMap<String, HashMap<String, Bitfield>>> =
         Mutex::new(HashMap::new());
+    // how many other peers have each piece of a file, follows BUFFERMAPDB
+    // always locked after BUFFERMAPDB
+    static ref AVAILABILITY: Mutex<HashMap<String, Vec<usize>>> = Mutex::new(HashMap::new());
 }
 
 /// Generates a unique key for a peer.
@@ -33,7 +37,7 @@ lazy_static! {
 /// # Returns
 /// * `String` - The unique key for the peer.
 pub fn get_peer_key(peer: PeerConfig) -> String {
-    format!("{}:{}", peer.address, peer.port.to_string())
+    peer.addr.to_string()
 }
 
 /// Retrieves a peer from the database.
@@ -101,6 +105,63 @@ fn set_file(file: MetaFile) {
     // drop(db);
 }
 
+/// Retrieves every file of the database.
+///
+/// # Returns
+/// * `Vec<MetaFile>` - The files, in no particular order.
+pub fn get_files() -> Vec<MetaFile> {
+    let db = FILEDB.lock().unwrap();
+    db.values().cloned().collect()
+}
+
+/// Changes where a file is written on disk.
+///
+/// # Arguments
+/// * `key` - A string slice representing the file key.
+/// * `file_name` - The new path of the file.
+///
+/// # Returns
+/// * `bool` - false if the file is not in the database.
+pub fn set_file_name(key: &str, file_name: String) -> bool {
+    let mut db = FILEDB.lock().unwrap();
+    match db.get_mut(key) {
+        Some(file) => {
+            file.file_name = file_name;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stores the digests of the pieces of a file.
+///
+/// This function takes a file key and the digests of its pieces.
+/// It locks the database and sets the digests of the file associated with the key.
+/// The digests come from peers, so they are only kept if there is one per piece,
+/// and digests already known are never replaced by other ones.
+///
+/// # Arguments
+/// * `key` - A string slice representing the file key.
+/// * `digests` - The md5 of each piece, or nothing to forget them.
+///
+/// # Returns
+/// * `bool` - false if the file is not in the database or the digests do not fit it.
+pub fn set_piece_digests(key: &str, digests: Vec<String>) -> bool {
+    let mut db = FILEDB.lock().unwrap();
+    match db.get_mut(key) {
+        Some(file) if digests.is_empty() => {
+            file.piece_digests = digests;
+            true
+        }
+        Some(file) if !file.piece_digests.is_empty() => file.piece_digests == digests,
+        Some(file) if digests.len() == get_buffer_size(file) => {
+            file.piece_digests = digests;
+            true
+        }
+        _ => false,
+    }
+}
+
 /// Inserts a buffermap into the database.
 ///
 /// This function takes a file key, a peer key, and a buffermap.
@@ -109,12 +170,22 @@ fn set_file(file: MetaFile) {
 /// # Arguments
 /// * `file_key` - A String representing the file key.
 /// * `peer_key` - A String representing the peer key.
-/// * `buffermap` - A Vec<u8> representing the buffermap.
-pub fn set_buffermap(file_key: String, peer_key: String, buffermap: Vec<u8>) {
+/// * `buffermap` - A Bitfield representing the buffermap.
+pub fn set_buffermap(file_key: String, peer_key: String, buffermap: Bitfield) {
+    // our own pieces are not counted as available
+    let counted = peer_key != get_peer_key(PeerConfig::new());
     let mut buffermap_db = BUFFERMAPDB.lock().unwrap();
     let file_buffermaps = buffermap_db
         .entry(file_key.clone())
         .or_insert_with(HashMap::new);
+    if counted {
+        let mut availability = AVAILABILITY.lock().unwrap();
+        update_availability(
+            availability.entry(file_key.clone()).or_default(),
+            file_buffermaps.get(&peer_key),
+            Some(&buffermap),
+        );
+    }
     if let Some(buf) = file_buffermaps.get_mut(&peer_key) {
         modify_buffer(buf, buffermap);
     } else {
@@ -123,8 +194,85 @@ pub fn set_buffermap(file_key: String, peer_key: String, buffermap: Vec<u8>) {
     drop(buffermap_db);
 }
 
-fn modify_buffer(bufdest: &mut Vec<u8>, bufsrc: Vec<u8>) {
-    bufdest.copy_from_slice(&bufsrc);
+/// Marks one piece of a buffermap as held or not.
+///
+/// Unlike getting then setting the buffermap, this cannot lose
+/// the change of another thread made in between.
+///
+/// # Arguments
+/// * `file_key` - A string slice representing the file key.
+/// * `peer_key` - A string slice representing the peer key.
+/// * `index` - The index of the piece.
+/// * `value` - Whether the peer holds the piece.
+///
+/// # Returns
+/// * `bool` - false if there is no such buffermap or piece.
+pub fn set_buffermap_piece(file_key: &str, peer_key: &str, index: usize, value: bool) -> bool {
+    let counted = peer_key != get_peer_key(PeerConfig::new());
+    let mut buffermap_db = BUFFERMAPDB.lock().unwrap();
+    let Some(buffermap) = buffermap_db
+        .get_mut(file_key)
+        .and_then(|file_buffermaps| file_buffermaps.get_mut(peer_key))
+    else {
+        return false;
+    };
+    if index >= buffermap.len() {
+        return false;
+    }
+    if counted && buffermap.get(index) != value {
+        let mut availability = AVAILABILITY.lock().unwrap();
+        let counts = availability.entry(file_key.to_string()).or_default();
+        if counts.len() <= index {
+            counts.resize(buffermap.len(), 0);
+        }
+        if value {
+            counts[index] += 1;
+        } else {
+            counts[index] = counts[index].saturating_sub(1);
+        }
+    }
+    buffermap.set(index, value);
+    true
+}
+
+/// Counts the pieces a peer gained and forgets the ones it lost.
+///
+/// # Arguments
+/// * `availability` - How many peers have each piece, grown if too short.
+/// * `old` - The previous buffermap of the peer, None if it is new.
+/// * `new` - Its new buffermap, None if it is removed.
+fn update_availability(availability: &mut Vec<usize>, old: Option<&Bitfield>, new: Option<&Bitfield>) {
+    let empty = Bitfield::new(0);
+    let old = old.unwrap_or(&empty);
+    let new = new.unwrap_or(&empty);
+    let len = old.len().max(new.len());
+    if availability.len() < len {
+        availability.resize(len, 0);
+    }
+    for i in old.ones().filter(|&i| !new.get(i)) {
+        availability[i] = availability[i].saturating_sub(1);
+    }
+    for i in new.ones().filter(|&i| !old.get(i)) {
+        availability[i] += 1;
+    }
+}
+
+/// Retrieves how many peers, other than us, have each piece of a file.
+///
+/// The counts are kept up to date as buffermaps are stored, so this does not look at them.
+///
+/// # Arguments
+/// * `file_key` - A string slice representing the file key.
+///
+/// # Returns
+/// * `Vec<usize>` - The count of each piece, empty if no peer announced the file.
+pub fn get_availability(file_key: &str) -> Vec<usize> {
+    let availability = AVAILABILITY.lock().unwrap();
+    availability.get(file_key).cloned().unwrap_or_default()
+}
+
+fn modify_buffer(bufdest: &mut Bitfield, bufsrc: Bitfield) {
+    *bufdest = bufsrc;
     /*
     for (i, &src_byte) in bufsrc.iter().enumerate() {
         bufdest[i] = src_byte;
@@ -143,8 +291,8 @@ fn modify_buffer(bufdest: &mut Vec<u8>, bufsrc: Vec<u8>) {
 /// * `peer_key` - A string slice representing the peer key.
 ///
 /// # Returns
-/// * `Option<Vec<u8>>` - The buffermap associated with the file key and peer key, or None if no buffermap was found.
-fn __get_buffermap(file_key: &str, peer_key: &str) -> Option<Vec<u8>> {
+/// * `Option<Bitfield>` - The buffermap associated with the file key and peer key, or None if no buffermap was found.
+fn __get_buffermap(file_key: &str, peer_key: &str) -> Option<Bitfield> {
     let buffermap_db = BUFFERMAPDB.lock().unwrap();
     let file_buffermaps = buffermap_db.get(file_key)?;
     let buffermap = file_buffermaps.get(peer_key)?;
@@ -172,7 +320,7 @@ pub fn add_seed_file_to_db(file: MetaFile) {
     set_peer(&peer_key, me2);
 
     // add buffermap to db
-    let buffermap = vec![1u8; buffersize];
+    let buffermap = Bitfield::full(buffersize);
     set_buffermap(file_key, peer_key, buffermap)
 }
 
@@ -190,7 +338,7 @@ pub fn add_leeched_file_to_db(file: MetaFile) {
     let me = PeerConfig::new();
     let peer_key = get_peer_key(me);
     let buffersize = get_buffer_size(&file) as usize;
-    let buffermap = vec![0u8; buffersize];
+    let buffermap = Bitfield::new(buffersize);
     //set_file(file);
     set_buffermap(file_key, peer_key, buffermap)
 }
@@ -202,8 +350,8 @@ pub fn add_leeched_file_to_db(file: MetaFile) {
 /// # Arguments
 /// * `config` - A PeerConfig struct representing the peer.
 /// * `file` - A MetaFile struct representing the file.
-/// * `buffermap` - A Vec<u8> representing the buffermap.
-pub fn set_peer_to_file(config: PeerConfig, file: MetaFile, buffermap: Vec<u8>) {
+/// * `buffermap` - A Bitfield representing the buffermap.
+pub fn set_peer_to_file(config: PeerConfig, file: MetaFile, buffermap: Bitfield) {
     let peer_key = get_peer_key(config.clone());
     set_peer(&file.hash, config);
     set_file(file.clone());
@@ -219,8 +367,8 @@ pub fn set_peer_to_file(config: PeerConfig, file: MetaFile, buffermap: Vec<u8>)
 /// * `key` - A string slice representing the key for the file.
 ///
 /// # Returns
-/// * `Option<Vec<u8>>` - The buffermap for the file, or None if no buffermap was found.
-pub fn get_buffermap(config: PeerConfig, key: &str) -> Option<Vec<u8>> {
+/// * `Option<Bitfield>` - The buffermap for the file, or None if no buffermap was found.
+pub fn get_buffermap(config: PeerConfig, key: &str) -> Option<Bitfield> {
     __get_buffermap(key, &get_peer_key(config))
 }
 
@@ -237,15 +385,33 @@ pub fn get_peers_from_file(key: String) -> Vec<PeerConfig> {
     let mut peers: Vec<PeerConfig> = vec![];
     if let Some(file_buffermaps) = file_buffermaps {
         for (peer_key, _) in file_buffermaps {
-            let peer_parts: Vec<&str> = peer_key.split(':').collect();
-            let address = peer_parts[0].to_string();
-            let port = peer_parts[1].parse().unwrap();
-            peers.push(PeerConfig { address, port });
+            // keys are made by get_peer_key, IPv6 addresses in brackets
+            if let Ok(addr) = peer_key.parse() {
+                peers.push(PeerConfig { addr });
+            }
         }
     }
     peers
 }
 
+/// Retrieves the buffermap of every peer holding a file.
+///
+/// # Arguments
+/// * `key` - A string slice representing the key for the file.
+///
+/// # Returns
+/// * `Vec<(String, Bitfield)>` - The key of each peer with its buffermap.
+pub fn get_file_buffermaps(key: &str) -> Vec<(String, Bitfield)> {
+    let buffermap_db = BUFFERMAPDB.lock().unwrap();
+    match buffermap_db.get(key) {
+        Some(file_buffermaps) => file_buffermaps
+            .iter()
+            .map(|(peer_key, buffermap)| (peer_key.clone(), buffermap.clone()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
 /// Removes a file from the database and its associated buffermap.
 ///
 /// This function takes a MetaFile struct.
@@ -264,6 +430,7 @@ pub fn remove_file_from_db(file: MetaFile) {
     {
         file_db.remove(file_key);
         buffermap_db.remove(file_key);
+        AVAILABILITY.lock().unwrap().remove(file_key);
     }
 }
 
@@ -280,7 +447,11 @@ pub fn remove_peer_to_file(config: PeerConfig, key: String) {
     let mut buffermap_db = BUFFERMAPDB.lock().unwrap();
     let peer_key = get_peer_key(config);
     if let Some(file_buffermaps) = buffermap_db.get_mut(&key) {
-        file_buffermaps.remove(&peer_key);
+        if let Some(old) = file_buffermaps.remove(&peer_key) {
+            if let Some(availability) = AVAILABILITY.lock().unwrap().get_mut(&key) {
+                update_availability(availability, Some(&old), None);
+            }
+        }
     }
 }
 
@@ -301,9 +472,13 @@ pub fn remove_peer_from_db(config: PeerConfig) {
 
     // Remove the peer from all file buffer maps in the BUFFERMAPDB hash map
     let mut db = BUFFERMAPDB.lock().unwrap();
-    for (_file_key, file_buffermap) in db.iter_mut() {
-        file_buffermap.remove(&key);
+    let mut availability = AVAILABILITY.lock().unwrap();
+    for (file_key, file_buffermap) in db.iter_mut() {
+        if let (Some(old), Some(counts)) = (file_buffermap.remove(&key), availability.get_mut(file_key)) {
+            update_availability(counts, Some(&old), None);
+        }
     }
+    drop(availability);
     drop(db);
 }
 
@@ -314,6 +489,7 @@ fn clear_db() {
     let mut buffermap_db = BUFFERMAPDB.lock().unwrap();
     let mut file_db = FILEDB.lock().unwrap();
     let mut peer_db = PEERSDB.lock().unwrap();
+    *AVAILABILITY.lock().unwrap() = HashMap::new();
     *buffermap_db = HashMap::new();
     *file_db = HashMap::new();
     *peer_db = HashMap::new();
@@ -361,7 +537,7 @@ pub fn log_db() {
     for (file_key, file_buffermaps) in buffermap_db.iter() {
         println!("  {}:", file_key);
         for (peer_key, buffermap) in file_buffermaps.iter() {
-            println!("    {}: {:?}", peer_key, buffermap);
+            println!("    {}: {}/{}", peer_key, buffermap.count_ones(), buffermap.len());
         }
     }
 }
@@ -382,7 +558,7 @@ pub fn get_seeding_files() -> Vec<MetaFile> {
     for (file_key, file_buffermap) in db.iter() {
         // Check if the local peer has a buffer map filled with 1 for this file
         if let Some(buffermap) = file_buffermap.get(&get_peer_key(me.clone())) {
-            if buffermap.iter().all(|b| *b == 1) {
+            if buffermap.is_full() {
                 // Get the MetaFile struct for this file from the FILEDB hash map
                 let file_db = FILEDB.lock().unwrap();
                 if let Some(meta_file) = file_db.get(file_key) {
@@ -411,7 +587,7 @@ pub fn get_leeching_files() -> Vec<MetaFile> {
     for (file_key, file_buffermap) in db.iter() {
         // Check if the local peer has a buffer map with at least one "0" for this file
         if let Some(buffermap) = file_buffermap.get(&get_peer_key(me.clone())) {
-            if buffermap.iter().any(|b| *b == 0) {
+            if !buffermap.is_full() {
                 // Get the MetaFile struct for this file from the FILEDB hash map
                 let file_db = FILEDB.lock().unwrap();
                 if let Some(meta_file) = file_db.get(file_key) {
@@ -428,6 +604,26 @@ pub fn get_leeching_files() -> Vec<MetaFile> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_update_availability() {
+        let mut a = Bitfield::new(4);
+        a.set(0, true);
+        a.set(2, true);
+        let mut b = Bitfield::new(4);
+        b.set(2, true);
+        b.set(3, true);
+
+        let mut availability: Vec<usize> = Vec::new();
+        update_availability(&mut availability, None, Some(&a));
+        update_availability(&mut availability, None, Some(&b));
+        assert_eq!(availability, vec![1, 0, 2, 1]);
+        // a now has what b has
+        update_availability(&mut availability, Some(&a), Some(&b));
+        assert_eq!(availability, vec![0, 0, 2, 2]);
+        update_availability(&mut availability, Some(&b), None);
+        assert_eq!(availability, vec![0, 0, 1, 1]);
+    }
+
     #[test]
     fn test_get_seeding_or_leeching() {
         let meta = MetaFile {
@@ -435,22 +631,25 @@ mod tests {
             length: 10,
             piece_size: 10,
             hash: "hash".to_string(),
+            piece_digests: Vec::new(),
         };
         let meta2 = MetaFile {
             file_name: "test2".to_string(),
             length: 10,
             piece_size: 10,
             hash: "hash2".to_string(),
+            piece_digests: Vec::new(),
         };
         let meta3 = MetaFile {
             file_name: "test3".to_string(),
             length: 10,
             piece_size: 10,
             hash: "hash3".to_string(),
+            piece_digests: Vec::new(),
         };
         let me = PeerConfig::new();
-        let mut buffermap: Vec<u8> = vec![1u8; 10];
-        buffermap[0] = 0;
+        let mut buffermap = Bitfield::full(10);
+        buffermap.set(0, false);
         add_seed_file_to_db(meta);
         add_leeched_file_to_db(meta2);
         // add_leeched_file_to_db(meta3.clone());
@@ -469,22 +668,21 @@ mod tests {
     fn test_remove_peer_to_file() {
         clear_db();
         let peer1 = PeerConfig {
-            address: "1.1.1.1".to_string(),
-            port: 1234,
+            addr: "1.1.1.1:1234".parse().unwrap(),
         };
         let peer2 = PeerConfig {
-            address: "2.2.2.2".to_string(),
-            port: 1234,
+            addr: "[2001:db8::2]:1234".parse().unwrap(),
         };
         let meta = MetaFile {
             file_name: "test".to_string(),
             length: 10,
             piece_size: 10,
             hash: "hash1".to_string(),
+            piece_digests: Vec::new(),
         };
 
-        let buffermap = vec![1u8; 10];
-        let buffermap2 = vec![0u8; 10];
+        let buffermap = Bitfield::full(10);
+        let buffermap2 = Bitfield::new(10);
         set_peer_to_file(peer1.clone(), meta.clone(), buffermap);
         set_peer_to_file(peer2.clone(), meta.clone(), buffermap2.clone());
         assert!(!get_peers_from_file("hash1".to_string()).is_empty());
@@ -499,55 +697,53 @@ mod tests {
     fn test_get_peer_from_file() {
         clear_db();
         let peer1 = PeerConfig {
-            address: "1.1.1.1".to_string(),
-            port: 1234,
+            addr: "1.1.1.1:1234".parse().unwrap(),
         };
         let peer2 = PeerConfig {
-            address: "2.2.2.2".to_string(),
-            port: 1234,
+            addr: "2.2.2.2:1234".parse().unwrap(),
         };
         let peer3 = PeerConfig {
-            address: "3.3.3.3".to_string(),
-            port: 1234,
+            addr: "3.3.3.3:1234".parse().unwrap(),
         };
         let meta = MetaFile {
             file_name: "test".to_string(),
             length: 10,
             piece_size: 10,
             hash: "hash1".to_string(),
+            piece_digests: Vec::new(),
         };
-        let buffermap = vec![1u8; 10];
-        let buffermap2 = vec![0u8; 10];
+        let buffermap = Bitfield::full(10);
+        let buffermap2 = Bitfield::new(10);
         set_peer_to_file(peer1.clone(), meta.clone(), buffermap);
         set_peer_to_file(peer2.clone(), meta.clone(), buffermap2.clone());
         let result = get_peers_from_file("hash1".to_string());
         assert!(!result.is_empty());
         assert_eq!(result.len(), 2);
-        assert_eq!(result[0].address, "1.1.1.1".to_string());
-        assert_eq!(result[1].address, "2.2.2.2".to_string());
+        assert_eq!(result[0].addr.ip().to_string(), "1.1.1.1");
+        assert_eq!(result[1].addr.ip().to_string(), "2.2.2.2");
         set_peer_to_file(peer3.clone(), meta, buffermap2);
         let result = get_peers_from_file("hash1".to_string());
         assert!(!result.is_empty());
         assert_eq!(result.len(), 3);
-        assert_eq!(result[0].address, "1.1.1.1".to_string());
-        assert_eq!(result[1].address, "3.3.3.3".to_string());
-        assert_eq!(result[2].address, "2.2.2.2".to_string());
+        assert_eq!(result[0].addr.ip().to_string(), "1.1.1.1");
+        assert_eq!(result[1].addr.ip().to_string(), "3.3.3.3");
+        assert_eq!(result[2].addr.ip().to_string(), "2.2.2.2");
         clear_db();
     }
     #[test]
     fn test_set_peer_to_file() {
         clear_db();
         let peer1 = PeerConfig {
-            address: "1.1.1.1".to_string(),
-            port: 1234,
+            addr: "1.1.1.1:1234".parse().unwrap(),
         };
         let meta = MetaFile {
             file_name: "test".to_string(),
             length: 10,
             piece_size: 10,
             hash: "hash1".to_string(),
+            piece_digests: Vec::new(),
         };
-        let buffermap = vec![1u8; 10];
+        let buffermap = Bitfield::full(10);
         set_peer_to_file(peer1.clone(), meta, buffermap);
         assert!(get_buffermap(peer1, "hash1").is_some());
         clear_db();
@@ -561,11 +757,12 @@ mod tests {
             length: 10,
             piece_size: 10,
             hash: "hash".to_string(),
+            piece_digests: Vec::new(),
         };
         let file1 = "hash";
         let file2 = "hash2";
         let peer = "1.1.1.1:1234";
-        let buffermap = vec![1u8; 10];
+        let buffermap = Bitfield::full(10);
         set_buffermap(file1.to_string(), peer.to_string(), buffermap.clone());
         set_buffermap(file2.to_string(), peer.to_string(), buffermap.clone());
         let result = __get_buffermap(file1, peer);
@@ -585,7 +782,7 @@ mod tests {
         clear_db();
         let peer = "1.1.1.1:1234";
         let file = "hash";
-        let buffermap: Vec<u8> = vec![1u8; 10];
+        let buffermap = Bitfield::full(10);
         set_buffermap(file.to_string(), peer.to_string(), buffermap.clone());
         let result = __get_buffermap(file, peer).unwrap();
         assert_eq!(result, buffermap);
@@ -599,8 +796,8 @@ mod tests {
         let peer2 = "2.2.2.2:1234";
         let file = "hash";
         let file2 = "hash2";
-        let buffermap: Vec<u8> = vec![1u8; 10];
-        let buffermap2: Vec<u8> = vec![0u8; 10];
+        let buffermap = Bitfield::full(10);
+        let buffermap2 = Bitfield::new(10);
         set_buffermap(file.to_string(), peer.to_string(), buffermap.clone());
         let db = BUFFERMAPDB.lock().unwrap();
         let mut db_file = db.get(file).unwrap();
@@ -623,12 +820,15 @@ mod tests {
     fn test_get_peer_key() {
         clear_db();
         let peer = PeerConfig {
-            address: "1.1.1.1".to_string(),
-            port: 1234,
+            addr: "1.1.1.1:1234".parse().unwrap(),
         };
         let expected = "1.1.1.1:1234".to_string();
         let result = get_peer_key(peer);
         assert_eq!(result, expected);
+        let peer = PeerConfig {
+            addr: "[2001:db8::1]:1234".parse().unwrap(),
+        };
+        assert_eq!(get_peer_key(peer), "[2001:db8::1]:1234");
         clear_db();
     }
     #[test]
@@ -636,12 +836,10 @@ mod tests {
         clear_db();
         // println!("test");
         let peer = PeerConfig {
-            address: "1.1.1.1".to_string(),
-            port: 1234,
+            addr: "1.1.1.1:1234".parse().unwrap(),
         };
         let emptypeer = PeerConfig {
-            address: "".to_string(),
-            port: 0,
+            addr: "0.0.0.0:0".parse().unwrap(),
         };
         let mut db = PEERSDB.lock().unwrap();
         db.insert("1.1.1.1:1234".to_string(), peer.clone());
@@ -651,19 +849,17 @@ mod tests {
             Some(value) => value.clone(),
             None => emptypeer,
         };
-        assert_eq!(result.address, peer.address);
+        assert_eq!(result.addr, peer.addr);
         clear_db();
     }
     #[test]
     fn test_set_peer() {
         clear_db();
         let peer = PeerConfig {
-            address: "1.1.1.1".to_string(),
-            port: 1234,
+            addr: "1.1.1.1:1234".parse().unwrap(),
         };
         let emptypeer = PeerConfig {
-            address: "".to_string(),
-            port: 0,
+            addr: "0.0.0.0:0".parse().unwrap(),
         };
         let key = "1.1.1.1:1234";
         set_peer(key, peer);
@@ -674,7 +870,7 @@ mod tests {
         };
         db.clear();
         drop(db);
-        assert_eq!(result.port, 1234);
+        assert_eq!(result.addr.port(), 1234);
         clear_db();
     }
 }
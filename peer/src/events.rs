@@ -0,0 +1,77 @@
+//! notifications about what happens to our files, for whoever listens
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A download finished and the file matches its key, it is now seeded
+    DownloadComplete { key: String, file_name: String },
+    /// A finished download does not match its key, `pieces` are downloaded again
+    VerificationFailed {
+        key: String,
+        file_name: String,
+        pieces: Vec<usize>,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::DownloadComplete { file_name, .. } => {
+                write!(f, "Download of {} complete", file_name)
+            }
+            Event::VerificationFailed {
+                file_name, pieces, ..
+            } => write!(
+                f,
+                "Download of {} is corrupted, {} pieces to download again",
+                file_name,
+                pieces.len()
+            ),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<Event>>> = Mutex::new(Vec::new());
+}
+
+/// Returns a receiver getting every event emitted from now on.
+pub fn subscribe() -> Receiver<Event> {
+    let (sender, receiver) = channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Logs an event and sends it to the subscribers, forgetting the ones that are gone.
+pub fn emit(event: Event) {
+    match &event {
+        Event::DownloadComplete { .. } => info!("{}", event),
+        Event::VerificationFailed { .. } => warn!("{}", event),
+    }
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit() {
+        let receiver = subscribe();
+        let gone = subscribe();
+        drop(gone);
+        let event = Event::DownloadComplete {
+            key: "8905e92afeb80fc7722ec89eb0bf0966".to_string(),
+            file_name: "events_test.dat".to_string(),
+        };
+        emit(event.clone());
+        // other tests may emit too
+        assert!(receiver.try_iter().any(|received| received == event));
+        assert_eq!(event.to_string(), "Download of events_test.dat complete");
+    }
+}
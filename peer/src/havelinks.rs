@@ -0,0 +1,220 @@
+//! connections telling the peers about the pieces we get
+//!
+//! The have thread keeps one connection open to every peer of the files we
+//! download. The full buffermap of a file is exchanged the first time the
+//! file is announced on a connection, then each piece we write is sent alone
+//! in a `havepiece`. A connection that broke is opened again at the next
+//! refresh, with the full buffermaps.
+use crate::back::connect_peer;
+use crate::com::{poll_readable, receive_message, send};
+use crate::data::{MetaFile, PeerConfig};
+use crate::db::{get_buffermap, get_peer_key, get_peers_from_file, set_buffermap};
+use crate::protocol::Message;
+use crate::swarm::prove;
+use crate::transport::Transport;
+use hashbrown::hash_map::Entry;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// where the pieces we write go, None until the have thread starts
+    static ref WRITTEN: Mutex<Option<Sender<(String, usize)>>> = Mutex::new(None);
+}
+
+/// Returns a receiver getting every piece we write from now on, for the have thread.
+pub fn written_pieces() -> Receiver<(String, usize)> {
+    let (sender, receiver) = channel();
+    *WRITTEN.lock().unwrap() = Some(sender);
+    receiver
+}
+
+/// Tells the peers that we now have a piece.
+pub fn announce_piece(file_key: &str, index: usize) {
+    if let Some(sender) = WRITTEN.lock().unwrap().as_ref() {
+        // the have thread is gone once the pool stops
+        let _ = sender.send((file_key.to_string(), index));
+    }
+}
+
+/// a connection to a peer and the files announced on it
+struct Link {
+    stream: Box<dyn Transport>,
+    files: HashSet<String>,
+}
+
+/// The connections of the have thread, by peer
+#[derive(Default)]
+pub struct Links {
+    peers: HashMap<String, Link>,
+}
+
+impl Links {
+    /// Opens the missing connections and exchanges the buffermaps not exchanged yet.
+    ///
+    /// # Arguments
+    /// * `files` - The files whose peers should know our pieces.
+    pub fn refresh(&mut self, files: &[MetaFile]) {
+        let me: PeerConfig = PeerConfig::new();
+        let me_key: String = get_peer_key(me.clone());
+        self.peers.retain(|peer_key, link| {
+            let open = poll_readable(&link.stream).is_some();
+            if !open {
+                debug!("Have connection to {} closed, opening it again", peer_key);
+            }
+            open
+        });
+        for file in files {
+            let Some(buffermap) = get_buffermap(me.clone(), &file.hash) else {
+                continue;
+            };
+            for peer in get_peers_from_file(file.hash.clone()) {
+                let peer_key: String = get_peer_key(peer.clone());
+                if peer_key == me_key {
+                    continue;
+                }
+                let link = match self.peers.entry(peer_key.clone()) {
+                    Entry::Occupied(link) => link.into_mut(),
+                    Entry::Vacant(vacant) => match connect_peer(&peer) {
+                        Some((stream, _)) => vacant.insert(Link {
+                            stream,
+                            files: HashSet::new(),
+                        }),
+                        None => {
+                            warn!("Could not send have to {}", peer_key);
+                            continue;
+                        }
+                    },
+                };
+                if link.files.contains(&file.hash) {
+                    continue;
+                }
+                // the peer drops the connection if we fail
+                if let Err(e) = prove(&mut link.stream, &file.hash) {
+                    warn!("{} did not admit us to {} : {}", peer_key, file.hash, e);
+                    self.peers.remove(&peer_key);
+                    continue;
+                }
+                let msg = Message::Have {
+                    key: file.hash.clone(),
+                    buffermap: buffermap.clone(),
+                };
+                info!("Sending have to {}", peer_key);
+                send(&mut link.stream, &msg);
+
+                // and update their buffermap
+                match receive_message(&mut link.stream, 3000) {
+                    Ok(Message::Have { key, buffermap }) if key == file.hash => {
+                        link.files.insert(key.clone());
+                        set_buffermap(key, peer_key, buffermap);
+                    }
+                    _ => {
+                        warn!("Received wrong have answer from {}", peer_key);
+                        self.peers.remove(&peer_key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a piece we got to the peers the file was announced to.
+    ///
+    /// # Arguments
+    /// * `file_key` - The key of the file.
+    /// * `index` - The piece written.
+    pub fn announce(&mut self, file_key: &str, index: usize) {
+        let msg = Message::HavePiece {
+            key: file_key.to_string(),
+            index,
+        };
+        self.peers.retain(|peer_key, link| {
+            if !link.files.contains(file_key) {
+                return true;
+            }
+            // the peer gets the full buffermap again on a new connection
+            if poll_readable(&link.stream).is_none() {
+                debug!("Have connection to {} closed", peer_key);
+                return false;
+            }
+            send(&mut link.stream, &msg);
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitfield::Bitfield;
+    use crate::db::set_peer_to_file;
+    use crate::protocol::PROTOCOL_VERSION;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_links() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = PeerConfig {
+            addr: listener.local_addr().unwrap(),
+        };
+        let file = MetaFile {
+            file_name: "havelinks_test.dat".to_string(),
+            length: 4096,
+            piece_size: 1024,
+            hash: "havelinkstestkey".to_string(),
+            piece_digests: Vec::new(),
+        };
+        set_peer_to_file(PeerConfig::new(), file.clone(), Bitfield::new(5));
+        set_peer_to_file(peer.clone(), file.clone(), Bitfield::new(5));
+
+        // the peer answers the handshake and the have, then reads what follows
+        let remote = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            receive_message(&mut stream, 3000).unwrap();
+            send(
+                &mut stream,
+                &Message::Hello {
+                    version: PROTOCOL_VERSION,
+                    peer_id: "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b".to_string(),
+                    port: 1,
+                },
+            );
+            let have = receive_message(&mut stream, 3000).unwrap();
+            send(
+                &mut stream,
+                &Message::Have {
+                    key: "havelinkstestkey".to_string(),
+                    buffermap: Bitfield::full(5),
+                },
+            );
+            (have, receive_message(&mut stream, 3000).unwrap())
+        });
+
+        let mut links = Links::default();
+        let peer_key = get_peer_key(peer.clone());
+        links.refresh(std::slice::from_ref(&file));
+        assert!(links.peers[&peer_key].files.contains(&file.hash));
+        assert_eq!(get_buffermap(peer, &file.hash), Some(Bitfield::full(5)));
+        // announced once, only the new piece follows
+        links.refresh(std::slice::from_ref(&file));
+        links.announce(&file.hash, 3);
+
+        let (have, havepiece) = remote.join().unwrap();
+        assert_eq!(
+            have,
+            Message::Have {
+                key: file.hash.clone(),
+                buffermap: Bitfield::new(5),
+            }
+        );
+        assert_eq!(
+            havepiece,
+            Message::HavePiece {
+                key: file.hash,
+                index: 3,
+            }
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! pieces asked to a peer and not written yet
+//!
+//! A piece is reserved by the peer it is asked to until it is written or
+//! given back. A reservation that outlives its deadline is dropped, so the
+//! piece is asked again, to another peer when one has it.
+//!
+//! In endgame, when every missing piece is reserved, a piece can be reserved
+//! by several peers at once. The first copy written wins, the others are ignored.
+use crate::bitfield::Bitfield;
+use crate::data::get_config_path;
+use hashbrown::HashMap;
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::warn;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Request {
+    peer_key: String,
+    deadline: Instant,
+}
+
+#[derive(Default)]
+struct FileRequests {
+    pending: HashMap<usize, Vec<Request>>,
+    // peer whose request of a piece timed out last
+    timed_out: HashMap<usize, String>,
+}
+
+lazy_static! {
+    static ref INFLIGHT: Mutex<HashMap<String, FileRequests>> = Mutex::new(HashMap::new());
+    /// `request-timeout` of the Peer section in seconds, read once
+    static ref REQUEST_TIMEOUT: Duration = Duration::from_secs(
+        Ini::load_from_file(get_config_path())
+            .ok()
+            .and_then(|conf| {
+                conf.section(Some("Peer"))
+                    .and_then(|section| section.get("request-timeout"))
+                    .and_then(|secs| secs.parse().ok())
+            })
+            .unwrap_or(30)
+    );
+}
+
+/// How long a peer has to send a piece once asked
+pub fn get_request_timeout() -> Duration {
+    *REQUEST_TIMEOUT
+}
+
+/// Reserves pieces for the peer they are asked to.
+///
+/// # Arguments
+/// * `file_key` - The key of the file.
+/// * `peer_key` - The key of the peer.
+/// * `pieces` - The pieces asked.
+/// * `timeout` - How long the peer has to send them.
+pub fn reserve(file_key: &str, peer_key: &str, pieces: &[usize], timeout: Duration) {
+    let mut inflight = INFLIGHT.lock().unwrap();
+    let requests = inflight.entry(file_key.to_string()).or_default();
+    let deadline = Instant::now() + timeout;
+    for &index in pieces {
+        requests.timed_out.remove(&index);
+        let peers = requests.pending.entry(index).or_default();
+        peers.retain(|request| request.peer_key != peer_key);
+        peers.push(Request {
+            peer_key: peer_key.to_string(),
+            deadline,
+        });
+    }
+}
+
+/// Gives back a piece the peer did not send.
+pub fn release(file_key: &str, index: usize, peer_key: &str) {
+    let mut inflight = INFLIGHT.lock().unwrap();
+    if let Some(requests) = inflight.get_mut(file_key) {
+        if let Some(peers) = requests.pending.get_mut(&index) {
+            peers.retain(|request| request.peer_key != peer_key);
+            if peers.is_empty() {
+                requests.pending.remove(&index);
+            }
+        }
+    }
+}
+
+/// Drops every reservation of a written piece, the copies still coming are ignored.
+pub fn complete(file_key: &str, index: usize) {
+    let mut inflight = INFLIGHT.lock().unwrap();
+    if let Some(requests) = inflight.get_mut(file_key) {
+        requests.pending.remove(&index);
+        requests.timed_out.remove(&index);
+    }
+}
+
+/// Tells if a piece is reserved by a peer, a piece received after its deadline is not.
+pub fn is_requested_from(file_key: &str, index: usize, peer_key: &str) -> bool {
+    let inflight = INFLIGHT.lock().unwrap();
+    inflight
+        .get(file_key)
+        .and_then(|requests| requests.pending.get(&index))
+        .is_some_and(|peers| {
+            peers
+                .iter()
+                .any(|request| request.peer_key == peer_key && request.deadline > Instant::now())
+        })
+}
+
+/// Returns the pieces of a file that are reserved, dropping the expired reservations.
+///
+/// # Arguments
+/// * `file_key` - The key of the file.
+/// * `len` - The number of pieces of the file.
+///
+/// # Returns
+/// * `Bitfield` - The pieces that must not be asked again.
+pub fn in_flight(file_key: &str, len: usize) -> Bitfield {
+    let mut inflight = INFLIGHT.lock().unwrap();
+    let mut reserved = Bitfield::new(len);
+    let Some(requests) = inflight.get_mut(file_key) else {
+        return reserved;
+    };
+    let now = Instant::now();
+    for (&index, peers) in requests.pending.iter_mut() {
+        for request in peers.iter().filter(|request| request.deadline <= now) {
+            warn!(
+                "Piece {} of {} timed out on {}, asking it again",
+                index, file_key, request.peer_key
+            );
+            requests.timed_out.insert(index, request.peer_key.clone());
+        }
+        peers.retain(|request| request.deadline > now);
+    }
+    requests.pending.retain(|_, peers| !peers.is_empty());
+    for &index in requests.pending.keys().filter(|&&index| index < len) {
+        reserved.set(index, true);
+    }
+    reserved
+}
+
+/// Returns the pieces of a file reserved by a peer.
+pub fn requested_from(file_key: &str, peer_key: &str, len: usize) -> Bitfield {
+    let inflight = INFLIGHT.lock().unwrap();
+    let mut pieces = Bitfield::new(len);
+    if let Some(requests) = inflight.get(file_key) {
+        for (&index, peers) in requests.pending.iter() {
+            if index < len && peers.iter().any(|request| request.peer_key == peer_key) {
+                pieces.set(index, true);
+            }
+        }
+    }
+    pieces
+}
+
+/// Returns the pieces of a file whose last request to a peer timed out.
+pub fn timed_out_on(file_key: &str, peer_key: &str, len: usize) -> Bitfield {
+    let inflight = INFLIGHT.lock().unwrap();
+    let mut pieces = Bitfield::new(len);
+    if let Some(requests) = inflight.get(file_key) {
+        for (&index, peer) in requests.timed_out.iter() {
+            if peer == peer_key && index < len {
+                pieces.set(index, true);
+            }
+        }
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations() {
+        let file_key = "inflight_test";
+        reserve(file_key, "1.1.1.1:1", &[0, 2], Duration::from_secs(60));
+        reserve(file_key, "2.2.2.2:2", &[3], Duration::ZERO);
+        assert!(is_requested_from(file_key, 0, "1.1.1.1:1"));
+        assert!(!is_requested_from(file_key, 0, "2.2.2.2:2"));
+        assert!(!is_requested_from(file_key, 3, "2.2.2.2:2"));
+
+        // piece 3 expired, it goes back to the others
+        assert_eq!(in_flight(file_key, 4).ones().collect::<Vec<usize>>(), vec![0, 2]);
+        assert_eq!(timed_out_on(file_key, "2.2.2.2:2", 4).ones().collect::<Vec<usize>>(), vec![3]);
+        assert_eq!(timed_out_on(file_key, "1.1.1.1:1", 4).count_ones(), 0);
+
+        release(file_key, 0, "1.1.1.1:1");
+        reserve(file_key, "1.1.1.1:1", &[3], Duration::from_secs(60));
+        assert_eq!(in_flight(file_key, 4).ones().collect::<Vec<usize>>(), vec![2, 3]);
+        assert_eq!(timed_out_on(file_key, "2.2.2.2:2", 4).count_ones(), 0);
+        assert_eq!(in_flight("inflight_unknown", 4).count_ones(), 0);
+    }
+
+    #[test]
+    fn test_endgame_reservations() {
+        let file_key = "inflight_endgame_test";
+        reserve(file_key, "1.1.1.1:1", &[0, 1], Duration::from_secs(60));
+        reserve(file_key, "2.2.2.2:2", &[1], Duration::from_secs(60));
+        assert_eq!(requested_from(file_key, "2.2.2.2:2", 2).ones().collect::<Vec<usize>>(), vec![1]);
+
+        // one copy missing, the other is still expected
+        release(file_key, 1, "1.1.1.1:1");
+        assert!(is_requested_from(file_key, 1, "2.2.2.2:2"));
+        assert_eq!(in_flight(file_key, 2).count_ones(), 2);
+
+        // the first copy written wins
+        reserve(file_key, "1.1.1.1:1", &[1], Duration::from_secs(60));
+        complete(file_key, 1);
+        assert!(!is_requested_from(file_key, 1, "1.1.1.1:1"));
+        assert!(!is_requested_from(file_key, 1, "2.2.2.2:2"));
+        assert_eq!(in_flight(file_key, 2).ones().collect::<Vec<usize>>(), vec![0]);
+    }
+}
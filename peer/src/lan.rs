@@ -0,0 +1,321 @@
+//! peer discovery on the local network
+//!
+//! Every peer sends its hello and an `announce` of the files it seeds and
+//! leeches, the one the tracker gets, to a multicast group of the subnet. The
+//! peers reading it add the sender to the files they already know, so a
+//! download can go on from the machines nearby when the tracker is unreachable.
+//! Private files are never announced. A peer seen for the first time gets our
+//! announcement at once instead of at the next period.
+use crate::bitfield::Bitfield;
+use crate::com::{hellof, seedf};
+use crate::data::{get_buffer_size, get_config_path, get_peer_id, MetaFile, PeerConfig};
+use crate::db::{get_buffermap, get_file, get_leeching_files, get_seeding_files, set_peer_to_file};
+use crate::protocol::Message;
+use crate::swarm::is_private;
+use hashbrown::HashSet;
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::{debug, info, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+/// Group used when `lan-group` is not set
+const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6772);
+/// Largest datagram sent, below the usual MTU so it is never fragmented
+const MAX_DATAGRAM: usize = 1400;
+/// How long `receive` waits for a datagram
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// the group to use, None if `lan-discovery` is off
+    static ref GROUP: Option<SocketAddrV4> = load_group();
+}
+
+// `lan-discovery` and `lan-group` of the Peer section
+fn load_group() -> Option<SocketAddrV4> {
+    let conf = Ini::load_from_file(get_config_path()).ok()?;
+    let section = conf.section(Some("Peer"))?;
+    if !section
+        .get("lan-discovery")
+        .is_some_and(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"))
+    {
+        return None;
+    }
+    match section.get("lan-group").map(|group| group.parse()) {
+        Some(Ok(group)) => Some(group),
+        Some(Err(e)) => {
+            warn!("Wrong lan-group : {}, using {}", e, DEFAULT_GROUP);
+            Some(DEFAULT_GROUP)
+        }
+        None => Some(DEFAULT_GROUP),
+    }
+}
+
+/// What a peer of the subnet announced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub peer_id: String,
+    pub peer: PeerConfig,
+    pub seed: Vec<MetaFile>,
+    pub leech: Vec<String>,
+}
+
+/// Builds the datagrams announcing files, each holding our hello and as many files as fit.
+///
+/// # Arguments
+/// * `port` - Our listening port.
+/// * `seed` - The files we seed.
+/// * `leech` - The keys of the files we leech.
+///
+/// # Returns
+/// * `Vec<Vec<u8>>` - The datagrams, at least one even without files.
+pub fn datagrams(port: u16, seed: Vec<MetaFile>, leech: Vec<String>) -> Vec<Vec<u8>> {
+    let hello: Vec<u8> = hellof().encode();
+    let datagram = |seed: Vec<MetaFile>, leech: Vec<String>| {
+        let mut datagram: Vec<u8> = hello.clone();
+        datagram.extend(seedf(seed, port.to_string(), leech).encode());
+        datagram
+    };
+    let mut datagrams: Vec<Vec<u8>> = Vec::new();
+    let (mut seeds, mut leeches): (Vec<MetaFile>, Vec<String>) = (Vec::new(), Vec::new());
+    for file in seed {
+        seeds.push(file);
+        if seeds.len() > 1 && datagram(seeds.clone(), Vec::new()).len() > MAX_DATAGRAM {
+            let last: MetaFile = seeds.pop().unwrap();
+            datagrams.push(datagram(std::mem::take(&mut seeds), Vec::new()));
+            seeds.push(last);
+        }
+    }
+    for key in leech {
+        leeches.push(key);
+        if (!seeds.is_empty() || leeches.len() > 1)
+            && datagram(seeds.clone(), leeches.clone()).len() > MAX_DATAGRAM
+        {
+            let last: String = leeches.pop().unwrap();
+            datagrams.push(datagram(
+                std::mem::take(&mut seeds),
+                std::mem::take(&mut leeches),
+            ));
+            leeches.push(last);
+        }
+    }
+    datagrams.push(datagram(seeds, leeches));
+    datagrams
+}
+
+/// Reads a datagram of another peer.
+///
+/// # Arguments
+/// * `datagram` - The bytes received.
+/// * `from` - Where they came from, the port being the one of the socket and not the listening one.
+///
+/// # Returns
+/// * `Option<Announcement>` - What the peer announced, or None if it is not a hello followed by an announce.
+pub fn read_datagram(datagram: &[u8], from: SocketAddr) -> Option<Announcement> {
+    let mut lines = datagram.split(|byte| *byte == b'\n');
+    let hello = Message::decode(lines.next()?).ok()?;
+    let announce = Message::decode(lines.next()?).ok()?;
+    match (hello, announce) {
+        (Message::Hello { peer_id, .. }, Message::Announce { port, seed, leech }) => {
+            Some(Announcement {
+                peer_id,
+                peer: PeerConfig {
+                    addr: SocketAddr::new(from.ip().to_canonical(), port),
+                },
+                seed,
+                leech,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Adds a peer of the subnet to the files it announced.
+///
+/// Anyone on the subnet can send an announcement, so only the files we already
+/// know get the peer, with our metadata. The other files are skipped.
+pub fn discover(announcement: Announcement) {
+    let Announcement {
+        peer, seed, leech, ..
+    } = announcement;
+    for file in seed.iter().filter_map(|file| get_file(&file.hash)) {
+        let buffermap = Bitfield::full(get_buffer_size(&file));
+        set_peer_to_file(peer.clone(), file, buffermap);
+    }
+    for key in leech {
+        // its pieces come with its have
+        if let Some(file) = get_file(&key).filter(|_| get_buffermap(peer.clone(), &key).is_none()) {
+            let buffermap = Bitfield::new(get_buffer_size(&file));
+            set_peer_to_file(peer.clone(), file, buffermap);
+        }
+    }
+}
+
+/// The multicast socket of the discovery thread
+pub struct Lan {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    // peer ids seen so far
+    known: HashSet<String>,
+}
+
+impl Lan {
+    /// Joins the group of the config.
+    ///
+    /// # Returns
+    /// * `Option<Lan>` - None if discovery is off or the group could not be joined.
+    pub fn open() -> Option<Lan> {
+        let group: SocketAddrV4 = (*GROUP)?;
+        match Lan::join(group) {
+            Ok(lan) => {
+                info!("Discovering peers on {}", group);
+                Some(lan)
+            }
+            Err(e) => {
+                warn!(
+                    "Could not join {}, no peer discovery on the LAN : {}",
+                    group, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Joins a multicast group, several peers of the same machine sharing its port.
+    pub fn join(group: SocketAddrV4) -> io::Result<Lan> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        // the other peers of this machine read it too
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        Ok(Lan {
+            socket: socket.into(),
+            group,
+            known: HashSet::new(),
+        })
+    }
+
+    /// Sends the files we seed and leech to the group, except the private ones.
+    pub fn announce(&self) {
+        let seed: Vec<MetaFile> = get_seeding_files()
+            .into_iter()
+            .filter(|file| !is_private(&file.hash))
+            .collect();
+        let leech: Vec<String> = get_leeching_files()
+            .into_iter()
+            .map(|file| file.hash)
+            .filter(|key| !is_private(key))
+            .collect();
+        trace!(
+            "Announcing {} seeded and {} leeched files on the LAN",
+            seed.len(),
+            leech.len()
+        );
+        for datagram in datagrams(PeerConfig::new().addr.port(), seed, leech) {
+            if let Err(e) = self.socket.send_to(&datagram, self.group) {
+                warn!("Could not announce on {} : {}", self.group, e);
+                return;
+            }
+        }
+    }
+
+    /// Waits a little for an announcement and adds its peer to the files.
+    ///
+    /// # Returns
+    /// * `bool` - True if the peer had never been seen, it should get our files soon.
+    pub fn receive(&mut self) -> bool {
+        let mut buffer = [0u8; 65536];
+        let (len, from) = match self.socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return false
+            }
+            Err(e) => {
+                warn!("Could not read from {} : {}", self.group, e);
+                return false;
+            }
+        };
+        let Some(announcement) = read_datagram(&buffer[..len], from) else {
+            debug!("Ignoring a datagram from {}", from);
+            return false;
+        };
+        if announcement.peer_id == get_peer_id() {
+            return false;
+        }
+        let new: bool = self.known.insert(announcement.peer_id.clone());
+        if new {
+            info!(
+                "Found peer {} on the LAN at {}",
+                announcement.peer_id, announcement.peer.addr
+            );
+        }
+        discover(announcement);
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_leeched_file_to_db, get_peers_from_file};
+
+    fn file(index: usize) -> MetaFile {
+        MetaFile {
+            file_name: format!("lan_test_{}.dat", index),
+            length: 4096,
+            piece_size: 1024,
+            hash: format!("lantestkey{:022}", index),
+            piece_digests: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_datagrams() {
+        let seed: Vec<MetaFile> = (0..40).map(file).collect();
+        let leech: Vec<String> = (40..80).map(|index| file(index).hash).collect();
+        let datagrams = datagrams(6881, seed.clone(), leech.clone());
+        assert!(datagrams.len() > 1);
+        let from: SocketAddr = "192.168.1.20:6772".parse().unwrap();
+        let (mut seen_seed, mut seen_leech) = (Vec::new(), Vec::new());
+        for datagram in &datagrams {
+            assert!(datagram.len() <= MAX_DATAGRAM);
+            let announcement = read_datagram(datagram, from).unwrap();
+            assert_eq!(announcement.peer_id, get_peer_id());
+            assert_eq!(announcement.peer.addr, "192.168.1.20:6881".parse().unwrap());
+            seen_seed.extend(announcement.seed);
+            seen_leech.extend(announcement.leech);
+        }
+        assert_eq!(seen_seed, seed);
+        assert_eq!(seen_leech, leech);
+        assert!(read_datagram(b"announce listen 1 seed [] leech []\n", from).is_none());
+    }
+
+    #[test]
+    fn test_discover() {
+        let known: MetaFile = file(100);
+        add_leeched_file_to_db(known.clone());
+        let unknown: MetaFile = file(101);
+        let mut renamed: MetaFile = known.clone();
+        renamed.file_name = "../lan_test_renamed.dat".to_string();
+        let from: SocketAddr = "[::ffff:192.168.1.21]:6772".parse().unwrap();
+        let datagram = datagrams(6881, vec![renamed, unknown.clone()], Vec::new()).remove(0);
+        discover(read_datagram(&datagram, from).unwrap());
+        let peer = PeerConfig {
+            addr: "192.168.1.21:6881".parse().unwrap(),
+        };
+        assert!(get_peers_from_file(known.hash.clone()).contains(&peer));
+        assert_eq!(
+            get_buffermap(peer, &known.hash),
+            Some(Bitfield::full(get_buffer_size(&known)))
+        );
+        // the announcement does not change what we know of the file
+        assert_eq!(get_file(&known.hash), Some(known));
+        assert!(get_file(&unknown.hash).is_none());
+        assert!(get_peers_from_file(unknown.hash).is_empty());
+    }
+}
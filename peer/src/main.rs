@@ -1,38 +1,64 @@
 mod back;
+mod bitfield;
+mod choke;
 mod com;
+mod daemon;
 mod data;
 mod db;
+mod events;
+mod havelinks;
+mod inflight;
+mod lan;
 mod menu;
 mod parser;
+mod peerstats;
 mod process;
+mod protocol;
+mod ratelimit;
 mod respons_handler;
+mod selector;
+mod state;
+mod swarm;
 mod tasks;
 mod threads;
+mod tls;
+mod transport;
 mod userinput;
-use clap::{builder::NonEmptyStringValueParser, Parser};
+use clap::{Parser, Subcommand};
 use ini::Ini;
 use std::sync::Mutex;
 
+use back::resume_session;
 use data::{
     set_config_path, set_peer_port, set_tracker_address, set_tracker_port, PeerConfig,
     TrackerConfig,
 };
+use state::set_state_dir;
 use lazy_static::lazy_static;
 use log::{debug, error, info};
-use menu::display_menu;
+use daemon::set_control_socket;
+use menu::{
+    ctl_command, daemon_command, display_menu, get_command, search_command, seed_command,
+    status_command,
+};
 use num_traits::ToPrimitive;
+use ratelimit::{set_file_limit, set_global_limit, Direction};
+use selector::Strategy;
 use regex::Regex;
 use simplelog::*;
 
 use std::fs::File;
+use std::net::Ipv6Addr;
+use std::process::ExitCode;
 use threads::Pool;
 /*
 lazy_static! {
     static ref PROGRAM_CONST: Mutex<Option<ProgramConst>> = Mutex::new(None);
 }
 */
-fn main() {
-    let args = Args::parse();
+fn main() -> ExitCode {
+    let mut args = Args::parse();
+    let command = args.command.take();
     let program_const = handle_program_const(args);
     //let mut global_prorgam_const = PROGRAM_CONST.lock().unwrap();
     //*global_prorgam_const = Some(program_const.clone());
@@ -49,6 +75,17 @@ fn main() {
     ])
     .unwrap();
 
+    let tracker_config = program_const.tracker_config.clone();
+    debug!("MAIN: tracker_config : {:?}", tracker_config);
+
+    // commands that only talk to the tracker or read the state
+    let command = match command {
+        Some(Command::Search { name, size }) => return search_command(&tracker_config, name, size),
+        Some(Command::Status) => return status_command(),
+        Some(Command::Ctl { method, params }) => return ctl_command(method, params),
+        command => command,
+    };
+
     // config vars
     let num_threads = program_const.num_threads;
     let update_period_secs = program_const.update_period_secs;
@@ -57,22 +94,65 @@ fn main() {
     // create pool
     let mut pool: Pool = Pool::new(num_threads.to_i32().unwrap());
 
-    let tracker_config = program_const.tracker_config.clone();
-    debug!("MAIN: tracker_config : {:?}", tracker_config);
     //start update thread
     pool.start_update(tracker_config.clone(), update_period_secs.to_i32().unwrap());
 
     //start have thread
     pool.start_have(update_period_secs.to_i32().unwrap());
 
+    //start LAN discovery thread
+    pool.start_lan(update_period_secs.to_i32().unwrap());
+
+    //start events thread
+    pool.start_events();
+
+    //start choke thread
+    pool.start_choker();
+
     //start listening thread
     let peer_config = program_const.peer_config.clone();
     debug!("MAIN: peer_config : {:?}", peer_config);
     pool.start_listening(peer_config);
 
+    // reload the files of the previous run and resume their downloads
+    resume_session(
+        tracker_config.port,
+        &tracker_config.address,
+        pool.clone(),
+        program_const.length_tcp as usize,
+    );
+
     let pool_clone = pool.clone();
 
-    display_menu(program_const, tracker_config, pool_clone);
+    match command {
+        Some(Command::Seed { paths, limit }) => {
+            return seed_command(&tracker_config, paths, limit);
+        }
+        Some(Command::Get {
+            hash,
+            out,
+            strategy,
+            timeout,
+            limit,
+        }) => {
+            if let Some(limit) = limit {
+                set_file_limit(&hash, Direction::Download, limit);
+            }
+            return get_command(
+                &tracker_config,
+                pool_clone,
+                program_const.length_tcp as usize,
+                hash,
+                out,
+                strategy,
+                timeout,
+            )
+        }
+        Some(Command::Daemon) => {
+            return daemon_command(tracker_config, pool_clone, program_const.length_tcp as usize)
+        }
+        _ => display_menu(program_const, tracker_config, pool_clone),
+    }
 
     // auto download section for profiling
     /*
@@ -135,6 +215,7 @@ fn main() {
 
     //delete pool
     pool.drop();
+    ExitCode::SUCCESS
 }
 
 #[derive(Parser, Debug)]
@@ -159,6 +240,67 @@ struct Args {
     length_tcp: Option<u32>,
     #[clap(short, long)]
     update_period_secs: Option<u32>,
+    // dossier où l'état est sauvegardé
+    #[clap(short, long)]
+    state_dir: Option<String>,
+    // socket de contrôle du daemon
+    #[clap(long)]
+    socket: Option<String>,
+    // débit d'envoi maximal en Kio/s, 0 pour aucune limite
+    #[clap(long)]
+    upload_limit: Option<u64>,
+    // débit de réception maximal en Kio/s, 0 pour aucune limite
+    #[clap(long)]
+    download_limit: Option<u64>,
+    // sans commande, le menu interactif est affiché
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Seed files and keep serving them
+    Seed {
+        #[clap(required = true)]
+        paths: Vec<String>,
+        // débit d'envoi maximal de ces fichiers en Kio/s
+        #[clap(long)]
+        limit: Option<u64>,
+    },
+    /// List the files of the tracker, exits with 1 if none matches
+    Search {
+        #[clap(long)]
+        name: Option<String>,
+        // opérateur (<, = ou >) puis taille
+        #[clap(long, num_args = 2, value_names = ["OP", "N"])]
+        size: Option<Vec<String>>,
+    },
+    /// Download a file and wait until it is complete
+    Get {
+        hash: String,
+        // dossier où écrire le fichier
+        #[clap(long)]
+        out: Option<String>,
+        // ordre des pièces: rarest-first, sequential ou random-first
+        #[clap(long)]
+        strategy: Option<Strategy>,
+        // secondes sans nouvelle pièce avant d'abandonner
+        #[clap(long, default_value_t = 60)]
+        timeout: u64,
+        // débit de réception maximal de ce fichier en Kio/s
+        #[clap(long)]
+        limit: Option<u64>,
+    },
+    /// Show the saved files and their progress
+    Status,
+    /// Run in the background, driven through the control socket
+    Daemon,
+    /// Call a method of a running daemon: seed, start, pause, cancel, list, files, peers or subscribe
+    Ctl {
+        method: String,
+        // paramètres en JSON, ex: '{"key": "..."}'
+        params: Option<String>,
+    },
 }
 #[derive(Debug, Clone)]
 struct ProgramConst {
@@ -170,6 +312,40 @@ struct ProgramConst {
     log_level: LevelFilter,
 }
 
+/// Splits the tracker argument into an address and a port, either may be missing.
+///
+/// # Arguments
+/// * `tracker` - `ip:port`, `[ipv6]:port`, `domain:port`, `ip`, `ipv6`, `domain` or `port`.
+///
+/// # Returns
+/// * `Option<(Option<String>, Option<u16>)>` - The address, IPv6 ones without brackets, and the port,
+///   or None if the argument is none of these.
+fn parse_tracker(tracker: &str) -> Option<(Option<String>, Option<u16>)> {
+    let host_regex = Regex::new(r"^(?:\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}|[a-zA-Z0-9.-]+)$").unwrap();
+    if let Ok(port) = tracker.parse::<u16>() {
+        return Some((None, Some(port)));
+    }
+    if tracker.parse::<Ipv6Addr>().is_ok() {
+        return Some((Some(tracker.to_string()), None));
+    }
+    let (host, port) = match tracker.strip_prefix('[') {
+        Some(bracketed) => {
+            let (ipv6, rest) = bracketed.split_once(']')?;
+            ipv6.parse::<Ipv6Addr>().ok()?;
+            let port = match rest {
+                "" => None,
+                rest => Some(rest.strip_prefix(':')?.parse().ok()?),
+            };
+            return Some((Some(ipv6.to_string()), port));
+        }
+        None => match tracker.split_once(':') {
+            Some((host, port)) => (host, Some(port.parse().ok()?)),
+            None => (tracker, None),
+        },
+    };
+    host_regex.is_match(host).then(|| (Some(host.to_string()), port))
+}
+
 fn handle_program_const(args: Args) -> ProgramConst {
     // handle config
     let config_path = args.config.unwrap_or("config.ini".to_string());
@@ -184,37 +360,43 @@ fn handle_program_const(args: Args) -> ProgramConst {
     let peer_section = conf.section(Some("Peer")).unwrap();
     // if user specify a port
     if let Some(port) = args.port {
-        peer_config.port = port;
+        peer_config.addr.set_port(port);
         set_peer_port(port);
     }
+    // handle state directory
+    if let Some(state_dir) = args.state_dir {
+        set_state_dir(state_dir);
+    }
+    // handle control socket
+    if let Some(socket) = args.socket {
+        set_control_socket(socket);
+    }
+    // handle bandwidth limits
+    if let Some(limit) = args.upload_limit {
+        set_global_limit(Direction::Upload, limit);
+    }
+    if let Some(limit) = args.download_limit {
+        set_global_limit(Direction::Download, limit);
+    }
     // handle tracker config
 
     if let Some(tracker) = args.tracker {
-        let ip_domain_regex =
-            Regex::new(r"(?:\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}|[a-zA-Z0-9.-]+)").unwrap();
-        let port_regex = Regex::new(r"\d{1,5}").unwrap();
-        let ip_port_domain_regex =
-            Regex::new(r"(?:\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}|[a-zA-Z0-9.-]+):\d{1,5}").unwrap();
-        if ip_port_domain_regex.is_match(&tracker) {
-            let mut split = tracker.split(":");
-            set_tracker_address(tracker_config.address.clone());
-            tracker_config.address = split.next().unwrap().to_string();
-            set_tracker_port(tracker_config.port);
-            tracker_config.port = split.next().unwrap().parse::<u16>().unwrap();
-            debug!(
-                "tracker address : {:?} port : {:?}",
-                tracker_config.address, tracker_config.port
-            );
-        } else if ip_domain_regex.is_match(&tracker) {
-            set_tracker_address(tracker_config.address.clone());
-            tracker_config.address = tracker;
-            debug!("tracker address : {:?}", tracker_config.address);
-        } else if port_regex.is_match(&tracker) {
-            set_tracker_port(tracker_config.port);
-            tracker_config.port = tracker.parse::<u16>().unwrap();
-            debug!("tracker port : {:?}", tracker_config.port);
-        } else {
-            error!("Wrong tracker format, please use ip:port, domain:port, ip, domain or port, using config value")
+        match parse_tracker(&tracker) {
+            Some((address, port)) => {
+                if let Some(address) = address {
+                    set_tracker_address(address.clone());
+                    tracker_config.address = address;
+                }
+                if let Some(port) = port {
+                    set_tracker_port(port);
+                    tracker_config.port = port;
+                }
+                debug!(
+                    "tracker address : {:?} port : {:?}",
+                    tracker_config.address, tracker_config.port
+                );
+            }
+            None => error!("Wrong tracker format, please use ip:port, [ipv6]:port, domain:port, ip, domain or port, using config value"),
         }
     }
 
@@ -259,3 +441,55 @@ fn handle_program_const(args: Args) -> ProgramConst {
     debug!("ProgramConst : {:?}", ret);
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subcommands() {
+        let args =
+            Args::try_parse_from(["client", "-p", "9000", "get", "abc", "--out", "dl"]).unwrap();
+        assert_eq!(args.port, Some(9000));
+        assert!(matches!(
+            args.command,
+            Some(Command::Get { hash, out: Some(out), strategy: None, timeout: 60, limit: None })
+                if hash == "abc" && out == "dl"
+        ));
+        let args = Args::try_parse_from(["client", "get", "abc", "--strategy", "sequential"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Get { strategy: Some(Strategy::Sequential), .. })
+        ));
+        assert!(Args::try_parse_from(["client", "get", "abc", "--strategy", "fastest"]).is_err());
+        let args = Args::try_parse_from(["client", "search", "--size", "<", "10"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Search { name: None, size: Some(size) }) if size == ["<", "10"]
+        ));
+        assert!(Args::try_parse_from(["client", "seed"]).is_err());
+        let args =
+            Args::try_parse_from(["client", "--upload-limit", "100", "seed", "a.dat", "--limit", "10"])
+                .unwrap();
+        assert_eq!(args.upload_limit, Some(100));
+        assert!(matches!(args.command, Some(Command::Seed { limit: Some(10), .. })));
+        assert!(Args::try_parse_from(["client"]).unwrap().command.is_none());
+        let args = Args::try_parse_from(["client", "ctl", "pause", r#"{"key": "abc"}"#]).unwrap();
+        assert!(matches!(args.command, Some(Command::Ctl { params: Some(_), .. })));
+    }
+
+    #[test]
+    fn test_parse_tracker() {
+        let address = |address: &str| Some(address.to_string());
+        assert_eq!(parse_tracker("1.2.3.4:12345"), Some((address("1.2.3.4"), Some(12345))));
+        assert_eq!(parse_tracker("jibelibeju.fr:80"), Some((address("jibelibeju.fr"), Some(80))));
+        assert_eq!(parse_tracker("jibelibeju.fr"), Some((address("jibelibeju.fr"), None)));
+        assert_eq!(parse_tracker("12345"), Some((None, Some(12345))));
+        assert_eq!(parse_tracker("[2001:db8::1]:6881"), Some((address("2001:db8::1"), Some(6881))));
+        assert_eq!(parse_tracker("[::1]"), Some((address("::1"), None)));
+        assert_eq!(parse_tracker("2001:db8::1"), Some((address("2001:db8::1"), None)));
+        for bad in ["1.2.3.4:70000", "[::1]:", "[::1]80", "[nope]:80", "a/b", "host:port"] {
+            assert_eq!(parse_tracker(bad), None, "{}", bad);
+        }
+    }
+}
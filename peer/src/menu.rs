@@ -1,15 +1,35 @@
 use crate::back::start_download;
-use crate::com::{connect, lookf, receive, seedf, send};
+use crate::bitfield::Bitfield;
+use crate::com::{lookf, receive_message, seedf, send};
+use crate::daemon::{call, get_control_socket, Daemon};
 use crate::data::{get_buffer_size, MetaFile, PeerConfig, TrackerConfig};
-use crate::db::{add_seed_file_to_db, log_db, set_peer_to_file};
+use crate::db::{add_seed_file_to_db, get_buffermap, get_file, set_file_name, set_peer_to_file};
+use crate::events::{subscribe, Event};
+use crate::protocol::SizeOp;
+use crate::ratelimit::{set_file_limit, Direction};
 use crate::respons_handler::{Answer, ExpectList, ExpectOk, ExpectedAnswer};
-use crate::tasks::EmptyTask;
+use crate::selector::{set_strategy, Strategy};
+use crate::state::{get_written, load_state, save_state, track_file};
 use crate::threads::Pool;
+use crate::tls::{open, Remote};
 use crate::userinput::{choose_file, get_file_names, get_filename, get_filesize};
 use log::{error, info, trace, debug};
-use std::io;
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
 use crate::ProgramConst;
 
+// exit codes of the subcommands, 0 being success
+/// nothing matched, or the download did not complete
+const EXIT_FAILURE: u8 = 1;
+/// wrong arguments, or the tracker could not be reached
+const EXIT_ERROR: u8 = 2;
+
 /// Displays a menu to the user and performs actions based on the user's input.
 ///
 /// This function continuously displays a menu to the user with two options: Upload and Download.
@@ -37,7 +57,15 @@ pub fn display_menu(ProgramConst: ProgramConst, tracker_config: TrackerConfig, p
 
         match input {
             // Escape should get back to menu from search, upload and download
-            1 => upload_section(tracker_config.port, &tracker_config.address),
+            1 => {
+                println!("You're in upload");
+                // take the files the user wish to seed
+                let file_names = get_file_names(io::stdin());
+                let seeded = upload_section(tracker_config.port, &tracker_config.address, file_names);
+                if let Err(e) = seeded {
+                    error!("{}", e);
+                }
+            }
             2 => {
                 let pool_clone: Pool = pool.clone();
                 download_section(tracker_config.port, &tracker_config.address, pool_clone, ProgramConst.length_tcp as usize)
@@ -49,35 +77,40 @@ pub fn display_menu(ProgramConst: ProgramConst, tracker_config: TrackerConfig, p
 
 /// Searches for a file on the tracker.
 ///
-/// This function prompts the user for a filename and optional filesize, then sends a LOOK message to the tracker.
+/// This function sends a LOOK message to the tracker with a filename and optional filesize.
 /// It then waits for a response from the tracker and checks the response.
-/// If the response is valid, it retrieves the data from the response and returns it.
+/// If the response is valid, the files are added to the database and returned.
 ///
 /// # Arguments
 /// * `tracker_port` - The port number of the tracker.
 /// * `tracker_address` - The address of the tracker.
+/// * `filename` - The name to look for, empty for any.
+/// * `op_filesize` - An operator followed by a size (Ex: `<"10"`), empty for any.
 ///
 /// # Returns
-/// * `Answer` - An Answer object containing the search results.
-fn search_section(tracker_port: u16, tracker_adress: &str) -> Answer {
-    println!("You're in Search");
-    let filename = get_filename(io::stdin());
-    let op_filesize = get_filesize(io::stdin());
+/// * `Option<Answer>` - An Answer object containing the search results, or None if the tracker did not answer a list.
+fn search_section(
+    tracker_port: u16,
+    tracker_adress: &str,
+    filename: String,
+    op_filesize: String,
+) -> Option<Answer> {
     let look_message = lookf(filename, op_filesize);
     trace!("Prepared message: {}", look_message);
     let mut present_files: Answer = Answer::List(Vec::new());
-    let mut ret: Answer = Answer::List(Vec::new());
-    if let Some(mut stream) = connect(tracker_port, &tracker_adress.to_string()) {
-        send(&mut stream, look_message);
+    let mut ret: Option<Answer> = None;
+    if let Some(mut stream) = open(Remote::Tracker, tracker_port, tracker_adress) {
+        send(&mut stream, &look_message);
         trace!("Message sent waiting for answer");
-        let response = receive(&mut stream, 3000);
-        trace!("Received {}", response);
+        let response = receive_message(&mut stream, 3000);
 
-        match ExpectList.check_answer(&response) {
-            Ok(_) => {
+        match ExpectList.expect(response) {
+            Ok(files) => {
                 //info!("{}", valeur);
-                present_files = ExpectList.retrieve_data(response.clone());
-                ret = ExpectList.retrieve_data(response);
+                if let Answer::List(metafiles) = &files {
+                    present_files = Answer::List(metafiles.clone());
+                }
+                ret = Some(files);
             }
             Err(valeur) => {
                 error!("{}", valeur);
@@ -89,8 +122,12 @@ fn search_section(tracker_port: u16, tracker_adress: &str) -> Answer {
     match present_files {
         Answer::List(metafiles) => {
             for file in metafiles {
-                let buffmap: Vec<u8> = vec![0; get_buffer_size(&file)];
                 let conf: PeerConfig = PeerConfig::new();
+                // do not lose the progress of files we already have
+                if get_buffermap(conf.clone(), &file.hash).is_some() {
+                    continue;
+                }
+                let buffmap = Bitfield::new(get_buffer_size(&file));
                 set_peer_to_file(conf, file, buffmap);
             }
         }
@@ -104,53 +141,65 @@ fn search_section(tracker_port: u16, tracker_adress: &str) -> Answer {
 
 /// Uploads a file to the tracker.
 ///
-/// This function prompts the user for the names of the files they wish to seed.
-/// It then creates a MetaFile object for each file and adds them to the database.
+/// This function creates a MetaFile object for each file and adds them to the database.
 /// It then sends a HAVE message to the tracker for each file.
 /// If the tracker responds with an OK message, it logs the response and shuts down the connection.
 ///
 /// # Arguments
 /// * `tracker_port` - The port number of the tracker.
 /// * `tracker_address` - The address of the tracker.
-fn upload_section(tracker_port: u16, tracker_adress: &str) {
+/// * `file_names` - The paths of the files to seed.
+///
+/// # Returns
+/// * `Result<Vec<MetaFile>, SeedError>` - The files seeded if the tracker accepted them,
+///   nothing is seeded if a file could not be read.
+pub fn upload_section(
+    tracker_port: u16,
+    tracker_adress: &str,
+    file_names: Vec<String>,
+) -> Result<Vec<MetaFile>, SeedError> {
     let peer_config = PeerConfig::new();
-    println!("You're in upload");
-    let seeded_files = get_file_names(io::stdin()); // take the files the user wish to seed
-    let seeded_files: Vec<MetaFile> = seeded_files
+    let seeded_files: Vec<MetaFile> = file_names
         .into_iter()
-        .map(|file| MetaFile::new(file.to_string()))
-        .collect(); // Create vector of Metafiles out of the files name
+        .map(|file| MetaFile::new(file.clone()).map_err(|e| SeedError::File(file, e.to_string())))
+        .collect::<Result<_, _>>()?; // Create vector of Metafiles out of the files name
 
     let seeded_files2 = seeded_files.clone();
     for seed in seeded_files2 {
+        track_file(&seed, Bitfield::full(get_buffer_size(&seed)));
         add_seed_file_to_db(seed);
     }
+    if let Err(e) = save_state() {
+        error!("Could not save state : {}", e);
+    }
 
     // TODO set the right leeching string
-    let seeded_files = seedf(seeded_files, peer_config.port.to_string(), vec!["".to_string()]); // create the message
-    trace!("Prepared message: {}", seeded_files);
-    if let Some(mut stream) = connect(tracker_port, &tracker_adress.to_string()) {
+    let message = seedf(seeded_files.clone(), peer_config.addr.port().to_string(), Vec::new()); // create the message
+    trace!("Prepared message: {}", message);
+    if let Some(mut stream) = open(Remote::Tracker, tracker_port, tracker_adress) {
         // connect to the tracker
-        send(&mut stream, seeded_files.clone()); // send the message
+        send(&mut stream, &message); // send the message
         /*
         info!(
             "Sending to {}:{} : {}",
             stream.peer_addr().unwrap().ip(),
             stream.peer_addr().unwrap().port(),
-            seeded_files.clone()
+            message.clone()
         );
         */
         trace!("Message sent waiting for answer");
-        let response = receive(&mut stream, 3000); // receive the answer
-        trace!("Received: {}", response);
-        match ExpectOk.check_answer(&response) {
-            Ok(_) => {
-            }
+        let response = receive_message(&mut stream, 3000); // receive the answer
+        let accepted = match ExpectOk.expect(response) {
+            Ok(_) => Ok(seeded_files),
             Err(valeur) => {
                 error!("{}", valeur);
+                Err(SeedError::Tracker)
             }
-        }
+        };
         ExpectOk.shutdown(&mut stream);
+        accepted
+    } else {
+        Err(SeedError::Tracker)
     }
 }
 
@@ -172,7 +221,12 @@ fn download_section(tracker_port: u16, tracker_adress: &str, mut pool: Pool, len
     println!("You're in download");
     // display files along with their size
     // if two files are name the same user should be able to choose which one to download
-    let file_key = match choose_file(io::stdin(), &search_section(tracker_port, tracker_adress)) {
+    println!("You're in Search");
+    let filename = get_filename(io::stdin());
+    let op_filesize = get_filesize(io::stdin());
+    let files = search_section(tracker_port, tracker_adress, filename, op_filesize)
+        .unwrap_or(Answer::List(Vec::new()));
+    let file_key = match choose_file(io::stdin(), &files) {
         Some(hash) => hash.trim().to_string(),
         None => return,
     };
@@ -191,3 +245,344 @@ fn download_section(tracker_port: u16, tracker_adress: &str, mut pool: Pool, len
         }
     }
 }
+
+/// Seeds files then keeps serving them until the client is killed.
+///
+/// # Arguments
+/// * `tracker_config` - The tracker to announce the files to.
+/// * `paths` - The paths of the files to seed.
+/// * `limit` - The upload limit of each file in KiB/s, if any.
+///
+/// # Returns
+/// * `ExitCode` - Only returns if a file is missing or the tracker refused the files.
+pub fn seed_command(
+    tracker_config: &TrackerConfig,
+    paths: Vec<String>,
+    limit: Option<u64>,
+) -> ExitCode {
+    let missing: Vec<&String> = paths.iter().filter(|path| !Path::new(path).is_file()).collect();
+    if !missing.is_empty() {
+        for path in missing {
+            error!("File {} does not exist", path);
+        }
+        return ExitCode::from(EXIT_ERROR);
+    }
+    let seeded = match upload_section(tracker_config.port, &tracker_config.address, paths.clone()) {
+        Ok(seeded) => seeded,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    if let Some(limit) = limit {
+        for file in seeded {
+            set_file_limit(&file.hash, Direction::Upload, limit);
+        }
+    }
+    info!("Seeding {} files, stop with Ctrl-C", paths.len());
+    // the listening thread serves the files
+    loop {
+        thread::park();
+    }
+}
+
+/// Prints the files of the tracker matching a name and a size criterion.
+///
+/// # Arguments
+/// * `tracker_config` - The tracker to ask.
+/// * `name` - The name to look for.
+/// * `size` - An operator (`<`, `=` or `>`) and a size.
+///
+/// # Returns
+/// * `ExitCode` - Success if at least one file matched.
+pub fn search_command(
+    tracker_config: &TrackerConfig,
+    name: Option<String>,
+    size: Option<Vec<String>>,
+) -> ExitCode {
+    let op_filesize = match size.as_deref() {
+        None => String::new(),
+        Some([op, size]) if is_size_criterion(op, size) => format!("{}{}", op, size),
+        Some(size) => {
+            error!("Wrong size criterion {:?}, expected an operator (<, = or >) and a size", size);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let files = search_section(
+        tracker_config.port,
+        &tracker_config.address,
+        name.unwrap_or_default(),
+        op_filesize,
+    );
+    match files {
+        Some(Answer::List(files)) if !files.is_empty() => {
+            for file in files {
+                println!("{} {} {}", file.hash, file.length, file.file_name);
+            }
+            ExitCode::SUCCESS
+        }
+        Some(_) => ExitCode::from(EXIT_FAILURE),
+        None => ExitCode::from(EXIT_ERROR),
+    }
+}
+
+fn is_size_criterion(op: &str, size: &str) -> bool {
+    let mut chars = op.chars();
+    let op_ok = matches!((chars.next().and_then(SizeOp::from_char), chars.next()), (Some(_), None));
+    op_ok && size.parse::<usize>().is_ok()
+}
+
+/// Why files could not be seeded
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeedError {
+    /// a file could not be read, with the reason
+    File(String, String),
+    /// the tracker could not be reached or refused the files
+    Tracker,
+}
+
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedError::File(path, e) => write!(f, "Could not read {} : {}", path, e),
+            SeedError::Tracker => write!(f, "The tracker refused the files"),
+        }
+    }
+}
+
+/// Why a download could not be started
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadError {
+    /// the tracker could not be reached or gave a wrong answer
+    Tracker,
+    /// the tracker does not know the file
+    UnknownFile,
+    /// nobody has the file
+    NoPeer,
+    /// the output directory could not be created
+    Output(String),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Tracker => write!(f, "Could not get the file from the tracker"),
+            DownloadError::UnknownFile => write!(f, "The tracker does not know the file"),
+            DownloadError::NoPeer => write!(f, "No peer has the file"),
+            DownloadError::Output(e) => write!(f, "Could not create the output directory : {}", e),
+        }
+    }
+}
+
+/// Starts downloading a file, looking it up on the tracker first if it is unknown.
+///
+/// # Arguments
+/// * `tracker_config` - The tracker to get the file and its peers from.
+/// * `pool` - The pool the download tasks are added to.
+/// * `length_tcp` - How many bytes to ask a peer for at once.
+/// * `key` - The key of the file.
+/// * `out` - The directory to write the file in, the current one if None.
+/// * `strategy` - How to choose the pieces, the configured one if None.
+///
+/// # Returns
+/// * `Result<(), DownloadError>` - Ok once the download tasks are queued, or if the file is already downloaded.
+pub fn queue_download(
+    tracker_config: &TrackerConfig,
+    mut pool: Pool,
+    length_tcp: usize,
+    key: &str,
+    out: Option<&str>,
+    strategy: Option<Strategy>,
+) -> Result<(), DownloadError> {
+    if get_file(key).is_none()
+        && search_section(tracker_config.port, &tracker_config.address, String::new(), String::new())
+            .is_none()
+    {
+        return Err(DownloadError::Tracker);
+    }
+    let file: MetaFile = get_file(key).ok_or(DownloadError::UnknownFile)?;
+    if let Some(out) = out {
+        fs::create_dir_all(out).map_err(|e| DownloadError::Output(e.to_string()))?;
+        let name = Path::new(&file.file_name).file_name().unwrap_or_default();
+        set_file_name(key, Path::new(out).join(name).to_string_lossy().to_string());
+    }
+    if get_written(key).is_some_and(|written| written.is_full()) {
+        info!("{} is already downloaded", file.file_name);
+        return Ok(());
+    }
+    if let Some(strategy) = strategy {
+        set_strategy(key, strategy);
+    }
+
+    let tasks = start_download(
+        key.to_string(),
+        tracker_config.port,
+        &tracker_config.address,
+        pool.clone(),
+        length_tcp,
+    )
+    .map_err(|_| DownloadError::Tracker)?;
+    if tasks.is_empty() {
+        return Err(DownloadError::NoPeer);
+    }
+    for task in tasks {
+        pool.add_task(task);
+    }
+    Ok(())
+}
+
+/// Downloads a file and waits for it to be complete and verified.
+///
+/// # Arguments
+/// * `tracker_config` - The tracker to get the file and its peers from.
+/// * `pool` - The pool the download tasks are added to.
+/// * `length_tcp` - How many bytes to ask a peer for at once.
+/// * `key` - The key of the file.
+/// * `out` - The directory to write the file in, the current one if None.
+/// * `strategy` - How to choose the pieces, the configured one if None.
+/// * `timeout` - Seconds without a new piece after which the download is given up.
+///
+/// # Returns
+/// * `ExitCode` - Success once the file is downloaded.
+pub fn get_command(
+    tracker_config: &TrackerConfig,
+    pool: Pool,
+    length_tcp: usize,
+    key: String,
+    out: Option<String>,
+    strategy: Option<Strategy>,
+    timeout: u64,
+) -> ExitCode {
+    let events = subscribe();
+    let queued = queue_download(tracker_config, pool, length_tcp, &key, out.as_deref(), strategy);
+    if let Err(e) = queued {
+        error!("{} : {}", e, key);
+        return match e {
+            DownloadError::UnknownFile | DownloadError::NoPeer => ExitCode::from(EXIT_FAILURE),
+            _ => ExitCode::from(EXIT_ERROR),
+        };
+    }
+    if get_written(&key).is_some_and(|written| written.is_full()) {
+        return ExitCode::SUCCESS;
+    }
+
+    let mut progress: usize = 0;
+    let mut last_progress = Instant::now();
+    loop {
+        if let Ok(Event::DownloadComplete { key: done, .. }) =
+            events.recv_timeout(Duration::from_secs(1))
+        {
+            if done == key {
+                return ExitCode::SUCCESS;
+            }
+        }
+        let written = get_written(&key).map_or(0, |written| written.count_ones());
+        if written != progress {
+            progress = written;
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() > Duration::from_secs(timeout) {
+            error!("No piece of {} received for {} seconds, giving up", key, timeout);
+            if let Err(e) = save_state() {
+                error!("Could not save state : {}", e);
+            }
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    }
+}
+
+/// Prints the files of the saved state with their progress.
+///
+/// # Returns
+/// * `ExitCode` - Always success.
+pub fn status_command() -> ExitCode {
+    for file in load_state() {
+        let written = get_written(&file.hash).unwrap_or_default();
+        let status = if written.is_full() { "seeding" } else { "downloading" };
+        println!(
+            "{} {} {}/{} {}",
+            file.hash,
+            status,
+            written.count_ones(),
+            written.len(),
+            file.file_name
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+/// Runs the client in the background, driven through the control socket.
+///
+/// # Returns
+/// * `ExitCode` - Only returns if the control socket could not be opened.
+pub fn daemon_command(tracker_config: TrackerConfig, pool: Pool, length_tcp: usize) -> ExitCode {
+    let path = get_control_socket();
+    let daemon = Daemon {
+        tracker_config,
+        pool,
+        length_tcp,
+    };
+    match daemon.serve(&path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Could not open control socket {} : {}", path.display(), e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+/// Calls a method of a running daemon and prints its result.
+///
+/// After `subscribe`, the events are printed until the daemon stops.
+///
+/// # Arguments
+/// * `method` - The method to call.
+/// * `params` - Its parameters as JSON.
+///
+/// # Returns
+/// * `ExitCode` - Success if the daemon answered a result.
+pub fn ctl_command(method: String, params: Option<String>) -> ExitCode {
+    let params: Value = match params.as_deref().map(serde_json::from_str).transpose() {
+        Ok(params) => params.unwrap_or(Value::Null),
+        Err(e) => {
+            error!("Parameters are not JSON : {}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let path = get_control_socket();
+    let (response, events) = match call(&path, &method, params) {
+        Ok(answer) => answer,
+        Err(e) => {
+            error!("Could not reach the daemon on {} : {}", path.display(), e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    if let Some(e) = response.get("error") {
+        error!("{}", e["message"].as_str().unwrap_or("Unknown error"));
+        return ExitCode::from(EXIT_FAILURE);
+    }
+    println!("{}", serde_json::to_string_pretty(&response["result"]).unwrap_or_default());
+    if method == "subscribe" {
+        for line in events.lines() {
+            match line {
+                Ok(line) => println!("{}", line),
+                Err(_) => break,
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_size_criterion() {
+        assert!(is_size_criterion("<", "10"));
+        assert!(is_size_criterion("=", "0"));
+        assert!(!is_size_criterion("<=", "10"));
+        assert!(!is_size_criterion("", "10"));
+        assert!(!is_size_criterion("!", "10"));
+        assert!(!is_size_criterion(">", "ten"));
+    }
+}
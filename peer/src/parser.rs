@@ -1,301 +1,228 @@
-use crate::tasks::*;
-use hashbrown::HashMap;
-use log::{error, trace, info};
-use regex::Regex;
-use std::net::TcpStream;
-use crate::threads::Pool;
+use crate::bitfield::Bitfield;
+use crate::com::send;
 use crate::data::MetaFile;
 use crate::db::get_file;
-use std::cmp::min;
-
-//Enum for request types
-
-enum RequestType {
-    Data = 0,
-    Have = 1,
-    GetPieces = 2,
-    Interested = 3,
-}
-
-/// This function takes a number and returns the corresponding RequestType.
-/// If the number does not correspond to any RequestType, it returns None.
-fn cast_to_request_type(number: u8) -> Option<RequestType> {
-    match number {
-        0 => Some(RequestType::Data),
-        1 => Some(RequestType::Have),
-        2 => Some(RequestType::GetPieces),
-        3 => Some(RequestType::Interested),
-        _ => None,
-    }
-}
+use crate::protocol::{Framing, Message};
+use crate::swarm::is_admitted;
+use crate::tasks::*;
+use crate::threads::Pool;
+use crate::transport::Transport;
+use log::{error, info, trace, warn};
 
 pub enum Stream {
-    Single(Option<TcpStream>),
-    Multiple(Vec<Option<TcpStream>>),
-}
-
-/// Organizes a request based on its type.
-///
-/// # Arguments
-/// * `re` - A Regex object used to parse the request.
-/// * `request` - A String containing the request.
-/// * `req_type` - The type of the request.
-/// * `stream` - An optional TcpStream.
-///
-/// # Returns
-/// * `Box<dyn Task + Send>` - A boxed Task object.
-fn organize_request(
-    re: Regex,
-    request: String,
-    req_type: RequestType,
-    stream: Option<TcpStream>,
-    pool: Pool,
-) -> Box<dyn Task + Send> {
-    match req_type {
-        RequestType::Data => data_request(re, request, stream),
-        RequestType::Have => have_request(re, request, stream),
-        RequestType::GetPieces => getpieces_request(re, request, stream, pool),
-        RequestType::Interested => interested_request(re, request, stream),
-    }
+    Single(Option<Box<dyn Transport>>),
+    Multiple(Vec<Option<Box<dyn Transport>>>),
 }
 
 /// This function takes a data request and returns a Task object that handles the request.
-fn data_request(re: Regex, request: String, stream: Option<TcpStream>) -> Box<dyn Task + Send> {
+fn data_request(
+    key: String,
+    pieces: Vec<(usize, Vec<u8>)>,
+    stream: Option<Box<dyn Transport>>,
+) -> Box<dyn Task + Send> {
     info!("Received data request");
-    trace!("Regex data matched");
-    let capture = re.captures(&request).unwrap();
-    let hash = capture.get(2).unwrap();
-    let datas = capture.get(3).unwrap();
-
-    let mut map: Vec<(usize, Vec<u8>)> = Vec::new();
-
-    let datas_iter = datas.as_str().split(' ');
-
-    for data in datas_iter{
-        let splitted: Vec<&str> = data.split(':').collect();
-        let key: usize = splitted[0].parse().unwrap();
-        let data_str: String = splitted[1].to_string();
-
-        // trace!("Key : {}, Value : {}", key, data_str);
-
-        // convert data string into u8, using 8 bits chunks
-        let mut pieces: Vec<u8> = Vec::new();
-        for chunk in data_str.as_bytes().chunks(8) {
-            let chunk_str = std::str::from_utf8(chunk).unwrap();
-            let num = u8::from_str_radix(chunk_str, 2).unwrap(); //2 means base 2 binary to u8
-            pieces.push(num);
-        }
-
-        map.push((key, pieces));
-    }
-
     let ret = Data {
-        key: hash.as_str().to_string(),
-        pieces: map.clone(),
-        stream: stream,
+        key,
+        pieces,
+        stream,
     };
-    // let ret = EmptyTask {stream : None};
-    // trace!("hash : {}, data : {:?}", hash.as_str(), map);
     Box::new(ret)
-
 }
 
-/// This function takes a data request and returns a Task object that handles the request.
-fn have_request(re: Regex, request: String, stream: Option<TcpStream>) -> Box<dyn Task + Send> {
+/// This function takes a have request and returns a Task object that handles the request.
+fn have_request(
+    key: String,
+    buffermap: Bitfield,
+    stream: Option<Box<dyn Transport>>,
+    session: Session,
+    pool: Pool,
+) -> Box<dyn Task + Send> {
     info!("Received have request");
-    trace!("Regex have matched");
-    let capture = re.captures(&request).unwrap();
-    let hash = capture.get(2).unwrap();
-    let buffermap = capture.get(3).unwrap();
-    let buf: Vec<u8> = buffermap
-        .as_str()
-        .chars()
-        .map(|c| c.to_string())
-        .map(|s| u8::from_str_radix(&s, 2).unwrap())
-        .collect();
     let ret = Have {
-        key: hash.as_str().to_string(),
-        buffermap: buf,
-        stream: stream,
+        key,
+        buffermap,
+        stream,
+        session,
+        pool,
     };
     Box::new(ret)
 }
 
-pub fn parse_data(request: String) -> Option<HashMap<u32, Vec<u8>>> {
-    info!("Received data request");
-    let regex_data = r"^(data) ([[:alnum:]]*) \\[((?:[[:digit:]]*:[01]* ?)*)\\]$";
-    match Regex::new(regex_data) {
-        Ok(re) => {
-            let request_trimmed = request.trim().trim_matches(&['\0', '\n', ' '] as &[_]).to_string();
-            if re.is_match(&request_trimmed) {
-                let capture = re.captures(&request).unwrap();
-                let hashdata = capture.get(3).unwrap();
-                let map: HashMap<u32, Vec<u8>> = hashdata
-                    .as_str()
-                    .split(' ')
-                    .map(|pair| {
-                        let (key, value) = pair.split_once(':').unwrap();
-                        let key: u32 = key.parse().unwrap();
-                        let value: Vec<u8> = value
-                            .chars()
-                            .filter_map(|c| u8::from_str_radix(&c.to_string(), 16).ok())
-                            .collect();
-                        (key, value)
-                    })
-                    .collect();
-                Some(map)
-            } else {
-                error!("Could not parse request as data: {}", &request[..min(128, request.len())]);
-                None
-            }
-        }
-        Err(e) => {
-            error!("Regex error: {}", e);
-            None
-        }
-    }
-}
-
-
-pub fn parse_have_from_have(request: String) -> Option<Have> {
-    info!("Received have request");
-    if request.len() == 0{
-        return None;
-    }
-    let regex_have = r"^(have) ([[:alnum:]]*) ([01]*)$";
-    match Regex::new(regex_have) {
-        Ok(re) => {
-            let request_trimmed = request.trim().trim_matches(&['\0', '\n', ' '] as &[_]).to_string();
-            if re.is_match(&request_trimmed) {
-                let capture = re.captures(&request_trimmed).unwrap();
-
-                let hash = capture.get(2).unwrap();
-                let buffermap = capture.get(3).unwrap();
-                let buf: Vec<u8> = buffermap
-                    .as_str()
-                    .chars()
-                    .map(|c| c.to_string())
-                    .map(|s| u8::from_str_radix(&s, 2).unwrap())
-                    .collect();
-                let ret = Have {
-                    key: hash.as_str().to_string(),
-                    buffermap: buf,
-                    stream: None,
-                };
-                Some(ret)
-            } else {
-                error!("Could not parse request as have: {}", &request[..min(128, request.len())]);
-                None
-            }
-        }
-        Err(e) => {
-            error!("Regex error: {}", e);
-            None
-        }
-    }
+/// This function takes a havepiece request and returns a Task object that handles the request.
+fn havepiece_request(
+    key: String,
+    index: usize,
+    stream: Option<Box<dyn Transport>>,
+    session: Session,
+    pool: Pool,
+) -> Box<dyn Task + Send> {
+    trace!("Received havepiece {} of {}", index, key);
+    let ret = HavePiece {
+        key,
+        index,
+        stream,
+        session,
+        pool,
+    };
+    Box::new(ret)
 }
 
-/// This function takes a data request and returns a Task object that handles the request.
+/// This function takes a getpieces request and returns a Task object that handles the request.
 fn getpieces_request(
-    re: Regex,
-    request: String,
-    stream: Option<TcpStream>,
+    key: String,
+    pieces: Vec<usize>,
+    stream: Option<Box<dyn Transport>>,
+    session: Session,
     pool: Pool,
 ) -> Box<dyn Task + Send> {
-    trace!("Regex getpiece matched");
-    //info!("Received getpieces request");
-    //let regex_getpieces = r"^(getpieces) ([[:alnum:]]*) \\[((?:[[:digit:]]* ?)*)\\]$";
-    let capture = re.captures(&request).unwrap();
-    let hash = capture.get(2).unwrap();
-    let indexes = capture.get(3).unwrap();
-    let numbers: Vec<usize> = indexes
-        .as_str()
-        .split_whitespace()
-        .map(|s| s.parse::<usize>().unwrap())
-        .collect();
-    // trace!("Getpiece parser caught these : {:?}", numbers);
-    let chunk_size: usize;
-    let file_option: Option<MetaFile> = get_file(hash.as_str());
-    match file_option {
-        Some(file) => chunk_size = file.piece_size,
-        None => chunk_size = 1024, 
-    }
+    trace!("Getpieces request for {:?}", pieces);
+    let file_option: Option<MetaFile> = get_file(&key);
+    let chunk_size: usize = match file_option {
+        Some(file) => file.piece_size,
+        None => 1024,
+    };
     let ret = Getpieces {
-        key: hash.as_str().to_string(),
-        chunk_size: chunk_size,
-        pieces: numbers,
-        stream: stream,
-        pool: pool,
+        key,
+        chunk_size,
+        pieces,
+        stream,
+        pool,
         retry: 0,
+        session,
     };
     Box::new(ret)
 }
 
-/// This function takes a data request and returns a Task object that handles the request.
-fn interested_request(
-    re: Regex,
-    request: String,
-    stream: Option<TcpStream>,
+/// This function takes a framing request and returns a Task object that handles the request.
+fn framing_request(
+    framing: Framing,
+    stream: Option<Box<dyn Transport>>,
+    session: Session,
+    pool: Pool,
 ) -> Box<dyn Task + Send> {
-    trace!("Regex interest matched");
-    info!("Received interested request");
-    let capture = re.captures(&request).unwrap();
-    let hash = capture.get(2).unwrap();
-    let ret = Interested {
-        key: hash.as_str().to_string(),
-        stream: stream,
+    info!("Received framing request for {}", framing);
+    let ret = Negotiate {
+        framing,
+        stream,
+        session,
+        pool,
     };
+    Box::new(ret)
+}
+
+/// This function takes an auth request and returns a Task object that handles the request.
+fn auth_request(
+    key: String,
+    stream: Option<Box<dyn Transport>>,
+    session: Session,
+    pool: Pool,
+) -> Box<dyn Task + Send> {
+    info!("Received auth request for {}", key);
+    let ret = Auth {
+        key,
+        stream,
+        session,
+        pool,
+    };
+    Box::new(ret)
+}
+
+/// This function takes an interested request and returns a Task object that handles the request.
+fn interested_request(key: String, stream: Option<Box<dyn Transport>>) -> Box<dyn Task + Send> {
+    info!("Received interested request");
+    let ret = Interested { key, stream };
     let b: Box<dyn Task + Send> = Box::new(ret);
     b
 }
 
-/// This function takes a data request and returns a Task object that handles the request.
-pub fn parse_request(request: String, stream: Option<TcpStream>, pool: Pool) -> Box<dyn Task + Send> {
-    // let empty = EmptyTask {
-    //     stream: Some(stream),
-    // };
-    // return Box::new(empty);
-    // let regex_getpieces = r"^(getpieces) ([[:alnum:]]*) \\[((?:[[:digit:]]* ?)*)\\]$";
-    let regex_getpieces = r"^(getpieces) ([[:alnum:]]*) \[((?:[[:digit:]]* ?)*)\]$";
-    // let regex_getpieces = r"^(getpieces) ([[:alnum:]]*) \[\s*(\d+\s*)*\s*\]$";
-    let regex_interested = r"^(interested) ([[:alnum:]]*)$";
-    //let regex_have = r"^(have) ([[:alnum:]]*) \[((?:[[:digit:]]*)*)\]$";
-    let regex_have = r"^(have) ([[:alnum:]]*) ([01]*)$";
-    let regex_data = r"^(data) ([[:alnum:]]*) \[((?:[[:digit:]]*:[01]* ?)*)\]$";
-    let regex = [regex_data, regex_have, regex_getpieces, regex_interested];
-    let mut count = 0;
-    // let mut reqtype = RequestType::Data;
-    for r in regex {
-        match Regex::new(r) {
-            Ok(re) => {
-                let request_trimmed = request.trim().trim_matches(&['\0', '\n', ' '] as &[_]).to_string();
-                /*
-                trace!("request trimmed look like this : {}", request_trimmed);
-                for c in request_trimmed.chars(){
-                    println!("-> {} : {}", c, c as u32);
-                }
-                */
-                if re.is_match(&request_trimmed) {
-                    let reqtype = cast_to_request_type(count).unwrap();
-                    return organize_request(re, request_trimmed, reqtype, stream, pool);
-                } else {
-                    count += 1;
-                    continue;
-                };
-            }
-            Err(e) => {
-                error!("Regex Error: {}", e);
-            }
-        }
+/// This function takes a choke or unchoke request and returns a Task object that handles the request.
+fn choke_request(key: String, choked: bool, session: Session, pool: Pool) -> Box<dyn Task + Send> {
+    info!("Received {} request", if choked { "choke" } else { "unchoke" });
+    let ret = Choke {
+        key,
+        choked,
+        session,
+        pool,
+    };
+    Box::new(ret)
+}
 
+/// This function takes a getdigests request and returns a Task object that handles the request.
+fn getdigests_request(key: String, stream: Option<Box<dyn Transport>>) -> Box<dyn Task + Send> {
+    info!("Received getdigests request");
+    let ret = Getdigests { key, stream };
+    Box::new(ret)
+}
+
+/// This function takes a request received from a peer and returns a Task object that handles it.
+///
+/// Messages that a peer is not supposed to send us are answered by an `EmptyTask`.
+/// Requests about a private file the peer was not admitted to drop the connection.
+///
+/// # Arguments
+/// * `request` - The decoded request.
+/// * `stream` - The stream the request came from.
+/// * `session` - The state negotiated so far on the stream.
+/// * `pool` - The pool the task will be added to.
+///
+/// # Returns
+/// * `Box<dyn Task + Send>` - A boxed Task object.
+pub fn parse_request(
+    request: Message,
+    stream: Option<Box<dyn Transport>>,
+    session: Session,
+    pool: Pool,
+) -> Box<dyn Task + Send> {
+    let asked: Option<&String> = match &request {
+        Message::Have { key, .. }
+        | Message::HavePiece { key, .. }
+        | Message::Getpieces { key, .. }
+        | Message::Interested { key }
+        | Message::Getdigests { key } => Some(key),
+        _ => None,
+    };
+    if let Some(key) = asked.filter(|key| !is_admitted(&session, key)) {
+        warn!(
+            "Dropping connection of {:?} : {} of private file {} without auth",
+            session.peer_id,
+            request.to_string().split(' ').next().unwrap_or_default(),
+            key
+        );
+        if let Some(mut stream) = stream {
+            let refusal = Message::Error {
+                reason: format!("auth required for {}", key),
+            };
+            send(&mut stream, &refusal);
+        }
+        return Box::new(EmptyTask { stream: None });
+    }
+    match request {
+        Message::Data { key, pieces } => data_request(key, pieces, stream),
+        Message::Have { key, buffermap } => have_request(key, buffermap, stream, session, pool),
+        Message::HavePiece { key, index } => havepiece_request(key, index, stream, session, pool),
+        Message::Getpieces { key, pieces } => {
+            getpieces_request(key, pieces, stream, session, pool)
+        }
+        Message::Interested { key } => interested_request(key, stream),
+        Message::Getdigests { key } => getdigests_request(key, stream),
+        Message::Framing { framing } => framing_request(framing, stream, session, pool),
+        Message::Auth { key } => auth_request(key, stream, session, pool),
+        Message::Choke { key } => choke_request(key, true, session, pool),
+        Message::Unchoke { key } => choke_request(key, false, session, pool),
+        other => {
+            let other = other.to_string();
+            error!(
+                "Request error, not a peer request: {}",
+                other.chars().take(128).collect::<String>()
+            );
+            let empty = EmptyTask { stream };
+            Box::new(empty)
+        }
     }
-    error!("Request error, could not match incoming request: {}", &request[..128]);
-    let empty = EmptyTask { stream: stream };
-    Box::new(empty)
 }
 
 // Connect to the localhost
-// pub fn parse_interested(request: String, stream: Option<TcpStream>) -> Box<dyn Task + Send> {
+// pub fn parse_interested(request: String, stream: Option<Box<dyn Transport>>) -> Box<dyn Task + Send> {
 //     let regex_interested = r"^(interested) ([[:alnum:]]*)$";
 //     let reg: Regex;
 //     match Regex::new(regex_interested) {
@@ -321,7 +248,8 @@ mod tests {
     use super::*;
     use env_logger::Builder;
     use std::io::Write;
-    fn create_dummy_tcp_stream() -> Option<TcpStream> {
+    use std::net::TcpStream;
+    fn create_dummy_tcp_stream() -> Option<Box<dyn Transport>> {
         let stream = match TcpStream::connect("127.0.0.1:8000") {
             Ok(s) => s,
             Err(_) => return None,
@@ -332,7 +260,7 @@ mod tests {
             return None;
         }
 
-        Some(stream)
+        Some(Box::new(stream))
     }
     #[test]
     fn init_logger() {
@@ -342,8 +270,9 @@ mod tests {
     }
     #[test]
     fn test_data_request() {
-        let req = "data av12 [3:110011]";
+        let req = "data av12 [3:MTEwMDEx]";
         let stream_option = create_dummy_tcp_stream();
-        parse_request(req.to_string(), stream_option, Pool::new(0));
+        let request = Message::decode(req.as_bytes()).unwrap();
+        parse_request(request, stream_option, Session::default(), Pool::new(0));
     }
 }
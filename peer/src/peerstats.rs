@@ -0,0 +1,186 @@
+//! measured speed of the peers, so the fast ones are asked for more
+//!
+//! Each `DataWrite` chain sends one request at a time and waits for its
+//! answer. How much a request asks for follows the throughput of the peer,
+//! and how many chains run against it follows its throughput and latency.
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long answering one request should take
+const REQUEST_TIME: Duration = Duration::from_secs(1);
+/// Weight of a new measure against the previous ones
+const SMOOTHING: f64 = 0.3;
+/// Most pieces asked at once
+const MAX_BATCH: usize = 64;
+/// Chains started on a peer that was never measured
+const DEFAULT_CHAINS: usize = 3;
+/// Most chains on one peer for one file
+const MAX_CHAINS: usize = 8;
+/// Throughput in bytes/s worth one more chain
+const CHAIN_THROUGHPUT: f64 = 1024.0 * 1024.0;
+
+/// What we measured of a peer, smoothed over the last transfers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerStats {
+    /// bytes/s received from the peer while waiting for its answers
+    pub throughput: Option<f64>,
+    /// time for the peer to answer a short message
+    pub latency: Option<Duration>,
+}
+
+lazy_static! {
+    static ref STATS: Mutex<HashMap<String, PeerStats>> = Mutex::new(HashMap::new());
+    /// running chains by (peer, file)
+    static ref CHAINS: Mutex<HashMap<(String, String), usize>> = Mutex::new(HashMap::new());
+}
+
+fn smooth(previous: Option<f64>, sample: f64) -> f64 {
+    match previous {
+        Some(previous) => previous * (1.0 - SMOOTHING) + sample * SMOOTHING,
+        None => sample,
+    }
+}
+
+/// Records the answer of a request, an empty one counts as a slow transfer.
+///
+/// # Arguments
+/// * `peer_key` - The key of the peer.
+/// * `bytes` - The bytes of the pieces received.
+/// * `elapsed` - The time between the request and the end of its answer.
+pub fn record_transfer(peer_key: &str, bytes: usize, elapsed: Duration) {
+    let sample: f64 = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let mut stats = STATS.lock().unwrap();
+    let peer = stats.entry(peer_key.to_string()).or_default();
+    peer.throughput = Some(smooth(peer.throughput, sample));
+}
+
+/// Records the round trip of a short message.
+pub fn record_latency(peer_key: &str, round_trip: Duration) {
+    let mut stats = STATS.lock().unwrap();
+    let peer = stats.entry(peer_key.to_string()).or_default();
+    let latency = smooth(peer.latency.map(|l| l.as_secs_f64()), round_trip.as_secs_f64());
+    peer.latency = Some(Duration::from_secs_f64(latency));
+}
+
+pub fn get_stats(peer_key: &str) -> PeerStats {
+    let stats = STATS.lock().unwrap();
+    stats.get(peer_key).copied().unwrap_or_default()
+}
+
+/// Returns how many pieces to ask a peer for at once.
+///
+/// # Arguments
+/// * `peer_key` - The key of the peer.
+/// * `piece_size` - The size of the pieces of the file.
+/// * `default` - The number used until the peer is measured.
+///
+/// # Returns
+/// * `usize` - About what the peer sends in `REQUEST_TIME`, at least one piece.
+pub fn batch_size(peer_key: &str, piece_size: usize, default: usize) -> usize {
+    match get_stats(peer_key).throughput {
+        Some(throughput) if piece_size > 0 => {
+            let pieces = throughput * REQUEST_TIME.as_secs_f64() / piece_size as f64;
+            (pieces.round() as usize).clamp(1, MAX_BATCH)
+        }
+        _ => default.max(1),
+    }
+}
+
+/// Returns how many chains of requests a peer should get.
+///
+/// One chain more per `CHAIN_THROUGHPUT`, and enough chains for the answers
+/// to keep coming while the next requests travel.
+pub fn parallel_requests(peer_key: &str) -> usize {
+    let stats = get_stats(peer_key);
+    let Some(throughput) = stats.throughput else {
+        return DEFAULT_CHAINS;
+    };
+    let by_speed: usize = 1 + (throughput / CHAIN_THROUGHPUT) as usize;
+    let by_latency: usize = stats.latency.map_or(0, |latency| {
+        (latency.as_secs_f64() / REQUEST_TIME.as_secs_f64()).ceil() as usize
+    });
+    (by_speed + by_latency).clamp(1, MAX_CHAINS)
+}
+
+/// A chain of requests to a peer for a file, counted until dropped
+#[derive(Debug)]
+pub struct Chain {
+    peer_key: String,
+    file_key: String,
+}
+
+impl Chain {
+    pub fn start(peer_key: &str, file_key: &str) -> Chain {
+        let mut chains = CHAINS.lock().unwrap();
+        *chains
+            .entry((peer_key.to_string(), file_key.to_string()))
+            .or_default() += 1;
+        Chain {
+            peer_key: peer_key.to_string(),
+            file_key: file_key.to_string(),
+        }
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        let mut chains = CHAINS.lock().unwrap();
+        let key = (self.peer_key.clone(), self.file_key.clone());
+        if let Some(count) = chains.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                chains.remove(&key);
+            }
+        }
+    }
+}
+
+/// Returns how many chains run against a peer for a file.
+pub fn chains_of(peer_key: &str, file_key: &str) -> usize {
+    let chains = CHAINS.lock().unwrap();
+    chains
+        .get(&(peer_key.to_string(), file_key.to_string()))
+        .copied()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizing() {
+        let fast = "peerstats_fast:1";
+        let slow = "peerstats_slow:1";
+        assert_eq!(batch_size(fast, 1024, 5), 5);
+        assert_eq!(parallel_requests(fast), DEFAULT_CHAINS);
+
+        record_transfer(fast, 4 * 1024 * 1024, Duration::from_secs(1));
+        record_latency(fast, Duration::from_millis(20));
+        record_transfer(slow, 1024, Duration::from_secs(2));
+        assert_eq!(batch_size(fast, 1024 * 1024, 5), 4);
+        assert_eq!(batch_size(fast, 1024, 5), MAX_BATCH);
+        assert_eq!(batch_size(slow, 1024, 5), 1);
+        assert_eq!(parallel_requests(fast), 6);
+        assert_eq!(parallel_requests(slow), 1);
+
+        // a peer that stops answering loses its work
+        for _ in 0..20 {
+            record_transfer(fast, 0, Duration::from_secs(3));
+        }
+        assert_eq!(parallel_requests(fast), 2);
+    }
+
+    #[test]
+    fn test_chains() {
+        let first = Chain::start("peerstats_chain:1", "file");
+        let second = Chain::start("peerstats_chain:1", "file");
+        assert_eq!(chains_of("peerstats_chain:1", "file"), 2);
+        drop(first);
+        assert_eq!(chains_of("peerstats_chain:1", "file"), 1);
+        drop(second);
+        assert_eq!(chains_of("peerstats_chain:1", "file"), 0);
+    }
+}
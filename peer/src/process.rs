@@ -1,28 +1,54 @@
 //use crate::back::get_peer_and_piece_indices;
 use crate::back::{
-    get_chunks_from_file, get_wanted_piece_from_peer, is_stream_open, store_have_to_db,
-    FileAssembler,
+    complete_download, fetch_digests, get_chunks_from_file, join_swarm, get_wanted_piece_from_peer, is_stream_open,
+    split_held_pieces, store_have_to_db,
 };
-use crate::com::{connect, dataf, getpiecesf, havef, interestedf, receive, seedf, send};
-use crate::data::{b64_enc, MetaFile, PeerConfig};
+use crate::bitfield::Bitfield;
+use crate::choke::{is_choked, may_upload, set_choked, set_unchoked, wait_unchoke, CHOKED_RECHECK};
+use crate::com::{poll_readable, receive_framed, receive_message, send, send_framed, send_raw};
+use crate::data::{get_piece_digest, MetaFile, PeerConfig};
 use crate::db::{
-    get_buffermap, get_file, get_leeching_files, get_peer_key, get_seeding_files, log_db,
-    set_buffermap, set_peer_to_file,
+    get_buffermap, get_file, get_peer_key, set_buffermap, set_buffermap_piece, set_piece_digests,
 };
-use crate::parser::{parse_have_from_have, parse_request};
+use crate::havelinks::announce_piece;
+use crate::inflight::{complete, is_requested_from, release};
+use crate::parser::parse_request;
+use crate::peerstats::{batch_size, chains_of, parallel_requests, record_latency, record_transfer, Chain};
+use crate::protocol::{Framing, Message, ProtocolError};
+use crate::ratelimit::{bandwidth_wait, reserve_bandwidth, Direction};
 use crate::respons_handler::{Answer, ExpectData, ExpectOk, ExpectedAnswer};
+use crate::state::{add_retry, get_written, is_paused, mark_written, save_state_soon};
+use crate::swarm::challenge;
 use crate::tasks::{
-    Data, DataWrite, EmptyTask, Getpieces, Have, Interested, Peer, Task, ToBeProcessed,
+    Auth, Choke, Data, DataWrite, EmptyTask, Getdigests, Getpieces, Have, HaveLink, HavePiece, Interested, Negotiate,
+    Peer, Session, Task, ToBeProcessed,
 };
-use crate::threads::{handle_client, Pool};
-use log::{debug, error, info, trace};
+use crate::threads::{handle_client, Backoff, Pool};
+use log::{debug, error, trace, warn};
 use rayon::prelude::*;
 use std::cmp::min;
 use std::fs::OpenOptions;
 use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
 use std::mem;
-use std::net::{SocketAddr, TcpStream};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a connection is watched for a next request before it is closed
+const IDLE_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(10),
+    max: Duration::from_secs(1),
+    retries: 12,
+};
+
+/// Time between two looks at a connection carrying the pieces of a peer
+const LINK_POLL: Duration = Duration::from_secs(1);
+
+/// How long a download waits for pieces to become available from a peer
+const STALLED_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+    retries: 10,
+};
 
 impl Task for EmptyTask {
     fn process(&mut self) {
@@ -30,8 +56,7 @@ impl Task for EmptyTask {
         let stream = &mut self.stream;
         match stream {
             Some(stream) => {
-                let msg: String = String::from("EMPTY");
-                send(stream, msg);
+                send_raw(stream, b"EMPTY");
             }
             None => return,
         }
@@ -45,12 +70,14 @@ impl Task for EmptyTask {
 ///
 /// It first checks if a stream is available. If not, it logs an error message.
 /// If a stream is available, it retrieves the key and piece indices from the `Getpieces` struct.
+/// Only the pieces set in our own buffermap are sent, when none of them is the answer is "unavailable key [index ...]".
 /// It then uses the `get_chunks_from_file` function to retrieve the pieces of the file corresponding to the piece indices.
 /// The pieces are then formatted into a string, with each piece represented as "index:piece".
-/// A message is then constructed with the format "data key [index1:piece1 index2:piece2 ...]" and sent over the stream.
+/// A message is then constructed with the format "data key [index1:piece1 index2:piece2 ...]" and sent over the stream,
+/// as binary frames instead if the connection negotiated it.
 ///
 /// # Arguments
-/// * `stream` - A mutable reference to an Option wrapping a TcpStream. This is the stream over which the data will be sent.
+/// * `stream` - A mutable reference to an Option wrapping the connection. This is the stream over which the data will be sent.
 /// * `key` - A string representing the key of the file.
 /// * `pieces` - A vector of u32s representing the indices of the pieces to be sent.
 // write a data to TCP and update db
@@ -58,10 +85,6 @@ impl Task for Getpieces {
     fn process(&mut self) {
         trace!("Processing getpiece task");
 
-        if self.retry > 20 {
-            return;
-        }
-
         let stream = &mut self.stream;
         match stream {
             Some(stream) => {
@@ -69,46 +92,99 @@ impl Task for Getpieces {
                     return;
                 }
                 let piece_indexes = &self.pieces;
-                if self.retry == 0 && piece_indexes.len() > 0 {
+                // peers without an upload slot are told to wait for an unchoke
+                let choked: bool = self.retry == 0
+                    && !piece_indexes.is_empty()
+                    && self.session.peer.as_ref().is_some_and(|peer| !may_upload(peer, &self.key));
+                if choked {
+                    debug!("Choking a request for {}", self.key);
+                    send(stream, &Message::Choke { key: self.key.clone() });
+                } else if self.retry == 0 && !piece_indexes.is_empty() {
                     // get key from getpieces
                     let key = &self.key;
-                    // get the indexes of each piece
-
-                    trace!("Begin to read theses chunk {:?}", piece_indexes);
-                    let data: Vec<(usize, Vec<u8>)> =
-                        get_chunks_from_file(key.to_string(), self.chunk_size, piece_indexes);
+                    // over the upload limit, the request is served once the limit allows it
+                    let wait: Duration = bandwidth_wait(key, Direction::Upload);
+                    if !wait.is_zero() {
+                        trace!("Upload of {} limited, serving it in {:?}", key, wait);
+                        let next = Getpieces {
+                            key: key.clone(),
+                            chunk_size: self.chunk_size,
+                            pieces: piece_indexes.clone(),
+                            stream: self.stream.take(),
+                            pool: self.pool.clone(),
+                            retry: 0,
+                            session: self.session.clone(),
+                        };
+                        self.pool.add_delayed(Box::new(next), wait);
+                        return;
+                    }
+                    // a leecher shares the pieces it wrote, never the holes of its file
+                    let (held, missing) = split_held_pieces(key, piece_indexes);
+                    if held.is_empty() {
+                        debug!("None of {:?} of {} is held", missing, key);
+                        send(
+                            stream,
+                            &Message::Unavailable {
+                                key: key.clone(),
+                                pieces: missing,
+                            },
+                        );
+                    } else {
+                        if !missing.is_empty() {
+                            debug!("Pieces {:?} of {} are not held, sending the others", missing, key);
+                        }
+                        trace!("Begin to read theses chunk {:?}", held);
+                        let data: Vec<(usize, Vec<u8>)> =
+                            get_chunks_from_file(key.to_string(), self.chunk_size, &held);
+                        let sent: usize = data.iter().map(|(_, piece)| piece.len()).sum();
 
-                    let pieces: Vec<String> = data
-                        .par_iter()
-                        .map(|piece| {
-                            let cur_index: usize = piece.0;
-                            let cur_data: Vec<u8> = piece.1.clone();
-                            let cur_data_str: String = b64_enc(cur_data);
-                            format!("{}:{}", cur_index, cur_data_str)
-                        })
-                        .collect();
+                        let message = Message::Data {
+                            key: key.clone(),
+                            pieces: data,
+                        };
 
-                    let message: String = dataf(key, pieces);
+                        send_framed(stream, &message, self.session.framing);
+                        reserve_bandwidth(key, Direction::Upload, sent);
+                    }
+                }
 
-                    send(stream, message);
+                // nothing yet, look again later without holding a worker
+                match poll_readable(stream) {
+                    Some(true) => (),
+                    Some(false) => {
+                        match IDLE_BACKOFF.delay(self.retry) {
+                            Some(delay) => {
+                                let next = Getpieces {
+                                    key: self.key.clone(),
+                                    chunk_size: self.chunk_size,
+                                    pieces: Vec::new(),
+                                    stream: self.stream.take(),
+                                    pool: self.pool.clone(),
+                                    retry: self.retry + 1,
+                                    session: self.session.clone(),
+                                };
+                                self.pool.add_delayed(Box::new(next), delay);
+                            }
+                            None => debug!("Closing idle connection"),
+                        }
+                        return;
+                    }
+                    None => {
+                        debug!("Connection closed by the peer");
+                        return;
+                    }
                 }
 
                 // add a new task
-                let next_pieces: String = receive(&mut self.stream.as_mut().unwrap(), 250);
-
-                let next: Box<dyn Task + Send>;
-                if next_pieces.len() == 0 {
-                    next = Box::new(Getpieces {
-                        key: self.key.clone(),
-                        chunk_size: self.chunk_size,
-                        pieces: Vec::new(),
-                        stream: self.stream.take(),
-                        pool: self.pool.clone(),
-                        retry: self.retry + 1,
-                    });
-                } else {
-                    next = parse_request(next_pieces, self.stream.take(), self.pool.clone());
-                }
+                let next: Box<dyn Task + Send> = match receive_message(stream, 3000) {
+                    Ok(request) => {
+                        parse_request(request, self.stream.take(), self.session.clone(), self.pool.clone())
+                    }
+                    Err(e) => {
+                        error!("Closing connection after bad request: {}", e);
+                        return;
+                    }
+                };
 
                 self.pool.add_task(next);
             }
@@ -121,6 +197,66 @@ impl Task for Getpieces {
 }
 // format the data to be sent to the client
 
+/// `Negotiate` acknowledges a framing request, then waits for the next request on the same stream
+/// which will be answered using the new framing.
+impl Task for Negotiate {
+    fn process(&mut self) {
+        trace!("Processing negotiate task");
+        match self.stream.as_mut() {
+            Some(stream) => {
+                send(stream, &Message::Ok);
+                self.session.framing = self.framing;
+                let next = Getpieces {
+                    key: String::new(),
+                    chunk_size: 0,
+                    pieces: Vec::new(),
+                    stream: self.stream.take(),
+                    pool: self.pool.clone(),
+                    retry: 0,
+                    session: self.session.clone(),
+                };
+                self.pool.add_task(Box::new(next));
+            }
+            None => {
+                error!("No stream found");
+            }
+        }
+    }
+}
+
+/// `Auth` challenges the peer for a private file, admitting the connection to it
+/// and waiting for the next request if the peer proves it knows the secret
+impl Task for Auth {
+    fn process(&mut self) {
+        trace!("Processing auth task");
+        match self.stream.as_mut() {
+            Some(stream) => {
+                if let Err(e) = challenge(stream, &self.key) {
+                    warn!(
+                        "Dropping connection of {:?} : failed the challenge for {} : {}",
+                        self.session.peer_id, self.key, e
+                    );
+                    return;
+                }
+                self.session.admitted.insert(self.key.clone());
+                let next = Getpieces {
+                    key: String::new(),
+                    chunk_size: 0,
+                    pieces: Vec::new(),
+                    stream: self.stream.take(),
+                    pool: self.pool.clone(),
+                    retry: 0,
+                    session: self.session.clone(),
+                };
+                self.pool.add_task(Box::new(next));
+            }
+            None => {
+                error!("No stream found");
+            }
+        }
+    }
+}
+
 /// `Data` is a struct that implements the `Task` trait. It is used to write received pieces of a file to the local file system.
 ///
 /// # Process Method
@@ -133,7 +269,7 @@ impl Task for Getpieces {
 /// After all pieces have been added, an "ok\n" message is sent over the stream.
 ///
 /// # Arguments
-/// * `stream` - A mutable reference to an Option wrapping a TcpStream. This is the stream over which the data was received.
+/// * `stream` - A mutable reference to an Option wrapping the connection. This is the stream over which the data was received.
 /// * `key` - A string representing the key of the file.
 /// * `pieces` - A HashMap where the keys are u32s representing the indices of the pieces and the values are the pieces themselves.
 // send the data to the clien
@@ -171,31 +307,21 @@ impl Task for Data {
 /// If a stream is available, it retrieves the key from the `Have` struct and creates a `PeerConfig` from the current configuration.
 /// It then uses the `get_buffermap` function to retrieve the buffer map for the key.
 /// A message is then constructed with the format "have key buffermap" and sent over the stream.
+/// The connection is then kept open for the "havepiece" messages of the peer.
 ///
 /// # Arguments
-/// * `stream` - A mutable reference to an Option wrapping a TcpStream. This is the stream over which the message will be sent.
+/// * `stream` - A mutable reference to an Option wrapping the connection. This is the stream over which the message will be sent.
 /// * `key` - A string representing the key of the file.
 impl Task for Have {
     fn process(&mut self) {
         trace!("Processing have task");
 
-        // update db with new buffermap
+        // update db with new buffermap, under the listening port given in the handshake
         let have: Have = self.clone();
-        let stream_clone: TcpStream;
-        match self.stream.as_ref().unwrap().try_clone() {
-            Ok(v) => stream_clone = v,
-            Err(e) => {
-                error!("Could not add have to db {}", e);
-                return;
-            }
+        match self.session.peer.clone() {
+            Some(config) => store_have_to_db(config, have),
+            None => error!("Could not add have to db, the peer did not say hello"),
         }
-        let addr: SocketAddr = stream_clone.peer_addr().unwrap();
-        let address: String = addr.ip().to_string();
-        let port: u16 = addr.port();
-
-        let config: PeerConfig = PeerConfig { address, port };
-
-        store_have_to_db(config, have);
 
         // answer with own buffermap
         // create a peer_config from ip, and port taken by the stream
@@ -204,20 +330,21 @@ impl Task for Have {
             Some(stream) => {
                 let key = self.key.clone();
                 let config = PeerConfig::new();
-                let buffermap_option: Option<Vec<u8>> = get_buffermap(config, &key);
-                let buffermap: Vec<u8>;
-
-                match buffermap_option {
-                    Some(arr) => buffermap = arr,
-                    None => {
-                        let len: usize = self.buffermap.len();
-                        // create empty buffermap
-                        buffermap = vec![0; len];
-                    }
-                }
-                let message: String = havef(key, buffermap);
+                let buffermap_option: Option<Bitfield> = get_buffermap(config, &key);
+                let buffermap: Bitfield = match buffermap_option {
+                    Some(arr) => arr,
+                    // create empty buffermap
+                    None => Bitfield::new(self.buffermap.len()),
+                };
+                let message = Message::Have { key, buffermap };
 
-                send(stream, message);
+                send(stream, &message);
+                let link = HaveLink {
+                    stream: self.stream.take(),
+                    session: self.session.clone(),
+                    pool: self.pool.clone(),
+                };
+                self.pool.add_task(Box::new(link));
             }
             None => {
                 error!("No stream found");
@@ -227,6 +354,60 @@ impl Task for Have {
     }
 }
 
+impl Task for HavePiece {
+    fn process(&mut self) {
+        trace!("Processing havepiece task");
+        match self.session.peer.clone() {
+            Some(config) => {
+                let peer_key: String = get_peer_key(config);
+                // a piece of a file we never got the buffermap of is ignored
+                if !set_buffermap_piece(&self.key, &peer_key, self.index, true) {
+                    debug!("No buffermap of {} from {} for piece {}", self.key, peer_key, self.index);
+                }
+            }
+            None => error!("Could not add havepiece to db, the peer did not say hello"),
+        }
+        let link = HaveLink {
+            stream: self.stream.take(),
+            session: self.session.clone(),
+            pool: self.pool.clone(),
+        };
+        self.pool.add_task(Box::new(link));
+    }
+}
+
+impl Task for HaveLink {
+    fn process(&mut self) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        match poll_readable(stream) {
+            Some(true) => (),
+            Some(false) => {
+                let next = HaveLink {
+                    stream: self.stream.take(),
+                    session: self.session.clone(),
+                    pool: self.pool.clone(),
+                };
+                self.pool.add_delayed(Box::new(next), LINK_POLL);
+                return;
+            }
+            None => {
+                debug!("Have connection closed by the peer");
+                return;
+            }
+        }
+        let next: Box<dyn Task + Send> = match receive_message(stream, 3000) {
+            Ok(request) => parse_request(request, self.stream.take(), self.session.clone(), self.pool.clone()),
+            Err(e) => {
+                error!("Closing have connection after bad request: {}", e);
+                return;
+            }
+        };
+        self.pool.add_task(next);
+    }
+}
+
 /// `Interested` is a struct that implements the `Task` trait. It is used to send a "have" message over a TCP stream.
 ///
 /// # Process Method
@@ -238,7 +419,7 @@ impl Task for Have {
 /// If no buffer map is found, an error message is logged.
 ///
 /// # Arguments
-/// * `stream` - A mutable reference to an Option wrapping a TcpStream. This is the stream over which the message will be sent.
+/// * `stream` - A mutable reference to an Option wrapping the connection. This is the stream over which the message will be sent.
 /// * `key` - A string representing the key of the file.
 // send a have message to TCP
 // have $Key $BufferMap
@@ -252,28 +433,65 @@ impl Task for Interested {
                 let key = &self.key;
                 let peerconfig = PeerConfig::new();
                 // get buffermap from the database
-                let buffermap_option: Option<Vec<u8>> = get_buffermap(peerconfig, key);
-                let buffermap: Vec<u8>;
-
-                match buffermap_option {
-                    Some(arr) => {
-                        buffermap = arr;
-                    }
+                let buffermap_option: Option<Bitfield> = get_buffermap(peerconfig, key);
+                let buffermap: Bitfield = match buffermap_option {
+                    Some(arr) => arr,
                     None => {
                         error!("No buffermap found");
                         return;
                     }
-                }
+                };
+
+                let message = Message::Have {
+                    key: key.clone(),
+                    buffermap,
+                };
+                send(stream, &message);
+            }
+            None => {
+                error!("No stream found");
+            }
+        }
+    }
+}
 
-                // convert [0, 0, 1, 0] to 0010
-                let buffermap = buffermap
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>()
-                    .join("");
+/// `Choke` records that the peer choked or unchoked us, waking the downloads waiting on it.
+impl Task for Choke {
+    fn process(&mut self) {
+        trace!("Processing choke task");
+        let peer_key: String = match &self.session.peer {
+            Some(peer) => get_peer_key(peer.clone()),
+            None => {
+                error!("Choke from an unknown peer");
+                return;
+            }
+        };
+        if self.choked {
+            set_choked(&peer_key, &self.key);
+            return;
+        }
+        for handle in set_unchoked(&peer_key, &self.key) {
+            self.pool.wake(&handle);
+        }
+    }
+}
 
-                let message = format!("have {} {}", key, buffermap);
-                send(stream, message);
+/// `Getdigests` answers with the md5 of every piece of the file,
+/// or an empty list when we do not know them yet.
+impl Task for Getdigests {
+    fn process(&mut self) {
+        trace!("Processing getdigests task");
+        match self.stream.as_mut() {
+            Some(stream) => {
+                let digests: Vec<String> = match get_file(&self.key) {
+                    Some(file) => file.piece_digests,
+                    None => Vec::new(),
+                };
+                let message = Message::Digests {
+                    key: self.key.clone(),
+                    digests,
+                };
+                send(stream, &message);
             }
             None => {
                 error!("No stream found");
@@ -281,6 +499,7 @@ impl Task for Interested {
         }
     }
 }
+
 /// send a interested message to TCP
 /// retrieve the buffermap update db
 /// compute pieces to be taken relatvly to others and in function of the adressed peer
@@ -288,37 +507,50 @@ impl Task for Interested {
 impl Task for Peer {
     fn process(&mut self) {
         trace!("Processing peer task");
-        let adress = self.config.address.clone();
-        let port = self.config.port;
         let file_key = &self.hash;
-        debug!("Trying to connect to {}", self.config.address.clone());
-        let stream = &mut connect(port, &adress);
-        match stream {
-            Some(stream) => {
+        debug!("Trying to connect to {}", self.config.addr);
+        match join_swarm(&self.config, &self.hash) {
+            Some((mut stream, _)) => {
+                let stream = &mut stream;
                 let key: String = self.hash.clone();
 
                 // send interested to download
-                let message = interestedf(key);
-                debug!("Sending {} to {}", message, self.config.address.clone());
-                send(stream, message);
-                let response: String = receive(stream, 3000);
-                let cut: String = response.chars().take(128).collect::<String>();
-                debug!("Received {} from {}", cut, self.config.address.clone());
+                let message = Message::Interested { key };
+                debug!("Sending {} to {}", message, self.config.addr);
+                send(stream, &message);
+                let response = receive_message(stream, 3000);
                 // update db
                 // retrieve data
-                if let Some(have_struct) = parse_have_from_have(response) {
+                if let Ok(Message::Have { buffermap, .. }) = response {
                     // instead of adding the task to the pool, just update db
-                    let buffermap = have_struct.buffermap;
                     let peer_key = get_peer_key(self.config.clone());
                     set_buffermap(file_key.clone(), peer_key.clone(), buffermap);
                     // get the pieces that the peer wants relativly to the other buffermap but included into the peers buffermap
                     //let pieces = get_wanted_piece_from_peer(&peer_key, &file_key);
                 }
-                let chunk_size: usize;
-                let file_option: Option<MetaFile> = get_file(&self.hash);
-                match file_option {
-                    Some(file) => chunk_size = file.piece_size,
-                    None => chunk_size = 1024,
+                let file: MetaFile = match get_file(&self.hash) {
+                    Some(file) => file,
+                    None => {
+                        error!("Could not find file {} metadata in db", self.hash);
+                        return;
+                    }
+                };
+                let chunk_size: usize = file.piece_size;
+
+                // pieces are checked against digests from the other peers,
+                // without them only the whole file is checked once complete
+                if file.piece_digests.is_empty() {
+                    match fetch_digests(&self.hash, &self.config) {
+                        Some(digests) => {
+                            if !set_piece_digests(&self.hash, digests) {
+                                error!("Wrong digests for {}", self.hash);
+                            }
+                        }
+                        None => warn!(
+                            "No digests for {}, pieces from {} are not checked",
+                            self.hash, self.config.addr
+                        ),
+                    }
                 }
 
                 let nb_pieces: usize = self.length_tcp / chunk_size;
@@ -335,11 +567,16 @@ impl Task for Peer {
                     nb_pieces,
                     pool,
                     stream,
+                    session: Session::default(),
+                    retry: 0,
+                    chain: None,
                 };
 
-                // Arbitrary number of task,
-                for _ in 0..3 {
-                    let ret_clone = ret.clone();
+                // as many chains as the peer can serve, counting the ones already running
+                let peer_key: String = get_peer_key(self.config.clone());
+                for _ in chains_of(&peer_key, &self.hash)..parallel_requests(&peer_key) {
+                    let mut ret_clone = ret.clone();
+                    ret_clone.chain = Some(Chain::start(&peer_key, &self.hash));
                     self.pool.add_task(Box::new(ret_clone));
                 }
 
@@ -356,13 +593,54 @@ impl Task for Peer {
 impl Task for DataWrite {
     fn process(&mut self) {
         trace!("Processing DataWrite task");
-        let peer: PeerConfig = self.peer.clone();
+        // a paused or cancelled download ends here, starting it again queues new tasks
+        if is_paused(&self.file_key) {
+            debug!("Download of {} is paused", self.file_key);
+            return;
+        }
+        let peer_key: String = get_peer_key(self.peer.clone());
         let hash: String = self.file_key.clone();
-        let pieces: Vec<usize> =
-            get_wanted_piece_from_peer(&get_peer_key(peer), &hash, self.nb_pieces);
+        // a peer choking us is not asked until it unchokes us or the choke is old
+        if is_choked(&peer_key, &hash) {
+            self.wait_unchoke(&peer_key);
+            return;
+        }
+        // over the download limit, nothing is asked until the limit allows it
+        let wait: Duration = bandwidth_wait(&hash, Direction::Download);
+        if !wait.is_zero() {
+            trace!("Download of {} limited, asking again in {:?}", hash, wait);
+            let mut next: DataWrite = self.clone();
+            next.stream = self.stream.take();
+            next.session = self.session.clone();
+            next.chain = self.chain.take();
+            let handle = self.pool.add_delayed(Box::new(next), wait);
+            add_retry(&self.file_key, handle);
+            return;
+        }
+        let piece_size: usize = get_file(&hash).map_or(0, |file| file.piece_size);
+        // as much as the peer sends in about a second once it is measured
+        let nb_pieces: usize = batch_size(&peer_key, piece_size, self.nb_pieces);
+        let pieces: Vec<usize> = get_wanted_piece_from_peer(&peer_key, &hash, nb_pieces);
 
-        // if there is nothing left to download, exit
         if pieces.len() == 0 {
+            // if there is nothing left to download, exit
+            if get_written(&self.file_key).is_none_or(|written| written.is_full()) {
+                return;
+            }
+            // the peer may get new pieces, or pieces asked to others may time out
+            match STALLED_BACKOFF.delay(self.retry) {
+                Some(delay) => {
+                    trace!("Nothing to ask {} for now, trying again in {:?}", hash, delay);
+                    let mut next: DataWrite = self.clone();
+                    next.stream = self.stream.take();
+                    next.session = self.session.clone();
+                    next.retry = self.retry + 1;
+                    next.chain = self.chain.take();
+                    let handle = self.pool.add_delayed(Box::new(next), delay);
+                    add_retry(&self.file_key, handle);
+                }
+                None => debug!("Giving up on {} for {}", peer_key, hash),
+            }
             return;
         }
 
@@ -370,61 +648,90 @@ impl Task for DataWrite {
             Some(_) => (),
             None => {
                 trace!("Stream is closed, opening new one");
-                self.stream = connect(self.peer.port, &self.peer.address)
+                self.open_stream();
             }
         }
 
-        let msg = getpiecesf(self.file_key.clone(), pieces.clone());
-
-        let answer: String;
-        match self.stream.as_mut() {
-            Some(mut stream) => {
-                send(&mut stream, msg);
-                answer = receive(&mut stream, 3000)
+        let msg = Message::Getpieces {
+            key: self.file_key.clone(),
+            pieces: pieces.clone(),
+        };
+        let started = Instant::now();
+        let answer: Result<Message, ProtocolError> = match self.stream.as_mut() {
+            Some(stream) => {
+                send(stream, &msg);
+                receive_framed(stream, 3000, self.session.framing, pieces.len(), piece_size)
             }
             None => {
                 error!("Downloading stream closed prematurarily");
+                self.release_pieces(&pieces);
                 return;
             }
+        };
+        let bytes: usize = match &answer {
+            Ok(Message::Data { pieces, .. }) => pieces.iter().map(|(_, piece)| piece.len()).sum(),
+            _ => 0,
+        };
+        record_transfer(&peer_key, bytes, started.elapsed());
+        // only what arrived counts against the limit
+        reserve_bandwidth(&self.file_key, Direction::Download, bytes);
+        if let Ok(Message::Choke { .. }) = answer {
+            debug!("Choked by {} for {}", peer_key, hash);
+            set_choked(&peer_key, &hash);
+            self.release_pieces(&pieces);
+            self.wait_unchoke(&peer_key);
+            return;
         }
-
-        // init future buffermap
-        //trace!("peer : {:?}, hash : {}", PeerConfig::new(), &self.file_key);
-        let buffmap_option = get_buffermap(PeerConfig::new(), &self.file_key.clone());
-        //let new_buffermap: Mutex<Vec<u8>>;
-        let mut new_buffermap: Vec<u8>;
-        match buffmap_option {
-            Some(arr) => new_buffermap = arr,
-            None => {
-                error!("Got piece of unknown file");
-                return;
-            }
+        // what the peer does not hold is asked to the others
+        let unavailable: Vec<usize> = match &answer {
+            Ok(Message::Unavailable { pieces: missing, .. }) => missing.clone(),
+            Ok(Message::Data { pieces: data, .. }) => pieces
+                .iter()
+                .filter(|&&index| !data.iter().any(|(sent, _)| *sent == index))
+                .copied()
+                .collect(),
+            _ => Vec::new(),
+        };
+        for &index in unavailable.iter().filter(|index| pieces.contains(index)) {
+            debug!("Piece {} of {} is not available from {}", index, hash, peer_key);
+            self.forget_piece(index);
+        }
+        if let Ok(Message::Unavailable { .. }) = answer {
+            self.release_pieces(&pieces);
+            self.next_request(&peer_key);
+            return;
         }
 
-        //let received_pieces: Mutex<Vec<usize>> = Mutex::new(pieces.clone());
+        let me_key: String = get_peer_key(PeerConfig::new());
+        // pieces still reserved by this request, given back when leaving
         let mut received_pieces: Vec<usize> = pieces.clone();
 
-        // to prevent multiple thread to ask for the same pieces
-        set_buffermap(
-            self.file_key.clone(),
-            get_peer_key(PeerConfig::new()),
-            new_buffermap.clone(),
-        );
-
         // Parse answer
-        match ExpectData.check_answer(&answer) {
+        match ExpectData.expect(answer) {
             Ok(answer) => {
-                let answer: Answer = ExpectData.retrieve_data(answer);
                 match answer {
                     Answer::Data(data) => {
                         //data is Vec<(usize, String)>
 
+                        // digests dropped by a failed verification are asked again
+                        if get_file(&self.file_key).is_some_and(|f| f.piece_digests.is_empty()) {
+                            match fetch_digests(&self.file_key, &self.peer) {
+                                Some(digests) => {
+                                    if !set_piece_digests(&self.file_key, digests) {
+                                        error!("Wrong digests for {}", self.file_key);
+                                    }
+                                }
+                                None => warn!("No digests for {}, pieces are not checked", self.file_key),
+                            }
+                        }
+
                         // open file only once
                         let writer: MetaFile;
                         match get_file(&self.file_key.clone()) {
                             Some(value) => writer = value,
                             None => {
                                 error!("Could not find file {} metadata in db", self.file_key);
+                                self.release_pieces(&received_pieces);
                                 return;
                             }
                         }
@@ -437,7 +744,28 @@ impl Task for DataWrite {
 
                         for entry in data {
                             let index: usize = entry.0;
+                            // a piece that came after its deadline may be asked to another peer
+                            if !is_requested_from(&self.file_key, index, &peer_key) {
+                                // in endgame another peer may have sent it first
+                                if get_written(&self.file_key).is_some_and(|written| written.get(index)) {
+                                    debug!("Piece {} already written, copy from {} ignored", index, peer_key);
+                                } else {
+                                    warn!("Got piece {} that was not asked for", index);
+                                }
+                                continue;
+                            }
                             let chunk: Vec<u8> = entry.clone().1;
+                            // a piece that does not match its digest stays missing
+                            if !writer.piece_digests.is_empty()
+                                && writer.piece_digests.get(index) != Some(&get_piece_digest(&chunk))
+                            {
+                                warn!(
+                                    "Piece {} of {} from {} is corrupted",
+                                    index, filename, peer_key
+                                );
+                                self.forget_piece(index);
+                                continue;
+                            }
                             //received_pieces.retain(|&x| x != index);
                             received_pieces = received_pieces
                                 .into_iter()
@@ -454,47 +782,63 @@ impl Task for DataWrite {
                             // Write the chunk to the file
                             let ok = file.write_all(&chunk);
                             match ok {
-                                Ok(_) => {}
+                                Ok(_) => {
+                                    // only now we have the piece
+                                    set_buffermap_piece(&self.file_key, &me_key, index, true);
+                                    announce_piece(&self.file_key, index);
+                                    complete(&self.file_key, index);
+                                    if mark_written(&self.file_key, index) {
+                                        complete_download(&self.file_key);
+                                    }
+                                }
                                 Err(e) => {
                                     error!("Error writing to disk : {}", e);
+                                    self.release_pieces(&received_pieces);
                                     return;
                                 }
                             }
                         }
+                        save_state_soon();
                     }
                     _ => error!("couldn't retrieve data from peer"),
                 }
             }
             Err(e) => {
-                if let Some(io_err) = e.downcast_ref::<Error>() {
-                    if io_err.kind() == ErrorKind::InvalidInput {
+                if let Some(protocol_err) = e.downcast_ref::<ProtocolError>() {
+                    if *protocol_err == ProtocolError::Empty {
                     } else {
                         error!("Wrong answer from getpiece {}", e);
+                        self.release_pieces(&received_pieces);
                         return;
                     }
                 } else {
                     error!("Wrong answer from getpiece {}", e);
+                    self.release_pieces(&received_pieces);
                     return;
                 }
             }
         }
 
-        // check is there is some control to do
-        {
-            let not_received_pieces = received_pieces.clone();
-            for not_received in not_received_pieces {
-                new_buffermap[not_received] = 0;
-            }
-        }
+        // missing pieces can be asked again
+        self.release_pieces(&received_pieces);
+        self.next_request(&peer_key);
+    }
+}
 
-        //let new_buffermap: Vec<u8> = new_buffermap.lock().unwrap().clone();
-        // update db if missing some pieces
-        if received_pieces.len() > 0 {
-            set_buffermap(
-                self.file_key.clone(),
-                get_peer_key(PeerConfig::new()),
-                new_buffermap,
-            );
+impl DataWrite {
+    /// Asks the peer for the next pieces, with more or fewer chains as its speed changed.
+    fn next_request(&mut self, peer_key: &str) {
+        // a peer that got slower needs fewer chains, a faster one more
+        let chains: usize = chains_of(peer_key, &self.file_key);
+        let wanted: usize = parallel_requests(peer_key);
+        if chains > wanted {
+            debug!("{} chains on {} instead of {}, stopping one", chains, peer_key, wanted);
+            return;
+        }
+        if chains < wanted {
+            let mut extra: DataWrite = self.clone();
+            extra.chain = Some(Chain::start(peer_key, &self.file_key));
+            self.pool.add_task(Box::new(extra));
         }
 
         // re adding oneself to continue downloading
@@ -509,18 +853,91 @@ impl Task for DataWrite {
             nb_pieces,
             pool,
             stream: self.stream.take(),
+            session: self.session.clone(),
+            retry: 0,
+            chain: self.chain.take(),
         };
 
         self.pool.add_task(Box::new(next));
     }
+
+    /// Waits for the peer to unchoke us, asking again after a while in case the unchoke is lost.
+    fn wait_unchoke(&mut self, peer_key: &str) {
+        let mut next: DataWrite = self.clone();
+        // the peer closes idle connections meanwhile
+        next.stream = None;
+        next.chain = self.chain.take();
+        let handle = self.pool.add_delayed(Box::new(next), CHOKED_RECHECK);
+        wait_unchoke(peer_key, &self.file_key, handle.clone());
+        add_retry(&self.file_key, handle);
+    }
+
+    /// Connects to the peer and asks for binary framing of the data answers.
+    ///
+    /// Peers that do not understand framing requests close the connection,
+    /// in which case a new one is opened and kept in text framing.
+    fn open_stream(&mut self) {
+        self.reconnect();
+        if let Some(stream) = self.stream.as_mut() {
+            let request = Message::Framing {
+                framing: Framing::Binary,
+            };
+            let started = Instant::now();
+            send(stream, &request);
+            let answer = receive_message(stream, 3000);
+            if answer.is_ok() {
+                record_latency(&get_peer_key(self.peer.clone()), started.elapsed());
+            }
+            match answer {
+                Ok(Message::Ok) => {
+                    debug!("Binary framing accepted by {}", get_peer_key(self.peer.clone()));
+                    self.session.framing = Framing::Binary;
+                }
+                _ => {
+                    debug!(
+                        "Binary framing refused by {}, using text",
+                        get_peer_key(self.peer.clone())
+                    );
+                    self.reconnect();
+                }
+            }
+        }
+    }
+
+    /// Marks a piece as missing from the peer, so it is asked to another one.
+    fn forget_piece(&self, index: usize) {
+        set_buffermap_piece(&self.file_key, &get_peer_key(self.peer.clone()), index, false);
+    }
+
+    /// Gives back pieces reserved by this peer that were not written.
+    fn release_pieces(&self, pieces: &[usize]) {
+        let peer_key: String = get_peer_key(self.peer.clone());
+        for &index in pieces {
+            release(&self.file_key, index, &peer_key);
+        }
+    }
+
+    fn reconnect(&mut self) {
+        match join_swarm(&self.peer, &self.file_key) {
+            Some((stream, session)) => {
+                self.stream = Some(stream);
+                self.session = session;
+            }
+            None => {
+                self.stream = None;
+                self.session = Session::default();
+            }
+        }
+    }
 }
 
 // incoming connection task waiting to be processed
 impl Task for ToBeProcessed {
     fn process(&mut self) {
         trace!("Processing ToBeProcessed task");
-        let stream = self.stream.try_clone().unwrap();
-        handle_client(self.pool.clone(), stream);
+        if let Some(stream) = self.stream.take() {
+            handle_client(self.pool.clone(), stream);
+        }
     }
 }
 
@@ -528,6 +945,7 @@ impl Task for ToBeProcessed {
 mod tests {
     use super::*;
     use crate::db::{get_buffermap, get_peer_key, set_buffermap};
+    use crate::transport::duplex;
     use std::net::{TcpListener, TcpStream};
 
     #[test]
@@ -535,11 +953,12 @@ mod tests {
         // Set up the database in a known state
         let file_key = "test_file_key".to_string();
         let peer_config = PeerConfig {
-            address: "127.0.0.1".to_string(),
-            port: 8080,
+            addr: "127.0.0.1:8080".parse().unwrap(),
         };
         let peer_key = get_peer_key(peer_config.clone());
-        let buffermap = vec![0u8, 1, 2, 3, 4];
+        let mut buffermap = Bitfield::new(5);
+        buffermap.set(1, true);
+        buffermap.set(4, true);
         set_buffermap(file_key.clone(), peer_key.clone(), buffermap.clone());
 
         // Create a TcpStream for testing
@@ -552,8 +971,10 @@ mod tests {
             key: file_key.clone(),
             chunk_size: 1024,
             pieces: vec![0, 1, 2],
-            stream: Some(stream),
+            stream: Some(Box::new(stream)),
             pool: Pool::new(0),
+            retry: 0,
+            session: Session::default(),
         };
 
         // Call the process method
@@ -563,4 +984,48 @@ mod tests {
         let result = get_buffermap(peer_config, &file_key);
         assert_eq!(result, Some(buffermap));
     }
+
+    #[test]
+    fn test_getpieces_unavailable() {
+        let file = MetaFile {
+            file_name: "process_unavailable.dat".to_string(),
+            length: 4096,
+            piece_size: 1024,
+            hash: "processunavailablekey".to_string(),
+            piece_digests: Vec::new(),
+        };
+        let mut buffermap = Bitfield::new(5);
+        buffermap.set(2, true);
+        crate::db::set_peer_to_file(PeerConfig::new(), file.clone(), buffermap);
+        assert_eq!(
+            split_held_pieces(&file.hash, &[0, 2, 9]),
+            (vec![2], vec![0, 9])
+        );
+
+        // neither pieces we lack nor files we do not know are sent
+        let requests = [
+            (file.hash.clone(), vec![0, 1]),
+            ("processunknownkey".to_string(), vec![3]),
+        ];
+        for (key, pieces) in requests {
+            let (mut client, server) = duplex(
+                "127.0.0.1:5000".parse().unwrap(),
+                "127.0.0.1:5001".parse().unwrap(),
+            );
+            let mut getpieces = Getpieces {
+                key: key.clone(),
+                chunk_size: 1024,
+                pieces: pieces.clone(),
+                stream: Some(Box::new(server)),
+                pool: Pool::new(0),
+                retry: 0,
+                session: Session::default(),
+            };
+            getpieces.process();
+            assert_eq!(
+                receive_message(&mut client, 3000).unwrap(),
+                Message::Unavailable { key, pieces }
+            );
+        }
+    }
 }
@@ -0,0 +1,1049 @@
+//! typed messages exchanged between peers and with the tracker
+use crate::bitfield::Bitfield;
+use crate::data::{b64_dec, b64_enc, MetaFile, PeerConfig};
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+/// Tag opening a binary frame that carries one piece
+const FRAME_PIECE: u8 = 0x01;
+/// Tag of the binary frame closing a data answer
+const FRAME_END: u8 = 0x02;
+/// Biggest piece accepted in a binary frame
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+/// Version sent in the handshake, peers with another version are refused
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How `data` answers are written on a connection.
+///
+/// Every connection starts in `Text`, the downloader can ask for `Binary`
+/// with a `framing binary` message, which the uploader acknowledges with `ok`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Framing {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::Text => write!(f, "text"),
+            Framing::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Comparison operator used by the `filesize` criterion of a look request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeOp {
+    Lower,
+    Equal,
+    Greater,
+}
+
+impl SizeOp {
+    pub fn from_char(c: char) -> Option<SizeOp> {
+        match c {
+            '<' => Some(SizeOp::Lower),
+            '=' => Some(SizeOp::Equal),
+            '>' => Some(SizeOp::Greater),
+            _ => None,
+        }
+    }
+
+    fn as_char(&self) -> char {
+        match self {
+            SizeOp::Lower => '<',
+            SizeOp::Equal => '=',
+            SizeOp::Greater => '>',
+        }
+    }
+}
+
+/// Every message of the peer/tracker protocol.
+///
+/// A message is built by the sender, turned into bytes with `encode`,
+/// and turned back into the same value on the other side with `decode`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// `announce listen $Port seed [$Name $Length $PieceSize $Key ...] leech [$Key ...]`
+    Announce {
+        port: u16,
+        seed: Vec<MetaFile>,
+        leech: Vec<String>,
+    },
+    /// `look [filename="$Name" filesize>"$Size"]`
+    Look {
+        filename: Option<String>,
+        filesize: Option<(SizeOp, usize)>,
+    },
+    /// `getfile $Key`
+    Getfile { key: String },
+    /// `update seed [$Key ...] leech [$Key ...]`
+    Update { seed: Vec<String>, leech: Vec<String> },
+    /// `list [$Name $Length $PieceSize $Key ...]`
+    List(Vec<MetaFile>),
+    /// `peers $Key [$Ip:$Port ...]`
+    Peers { key: String, peers: Vec<PeerConfig> },
+    /// `ok`
+    Ok,
+    /// `interested $Key`
+    Interested { key: String },
+    /// `have $Key $Length:$BufferMap`, the buffermap being packed bits in base64
+    Have { key: String, buffermap: Bitfield },
+    /// `havepiece $Key $Index`, the sender got one more piece since its last `have`
+    HavePiece { key: String, index: usize },
+    /// `getpieces $Key [$Index ...]`
+    Getpieces { key: String, pieces: Vec<usize> },
+    /// `data $Key [$Index:$Piece ...]`, the pieces asked that the sender holds
+    Data {
+        key: String,
+        pieces: Vec<(usize, Vec<u8>)>,
+    },
+    /// `unavailable $Key [$Index ...]`, answers a `getpieces` when the sender holds none of the pieces
+    Unavailable { key: String, pieces: Vec<usize> },
+    /// `framing $Mode`
+    Framing { framing: Framing },
+    /// `getdigests $Key`
+    Getdigests { key: String },
+    /// `digests $Key [$Md5 ...]`, the md5 of every piece of the file in order
+    Digests { key: String, digests: Vec<String> },
+    /// `hello $Version $PeerId $Port`, first message of every peer connection
+    Hello {
+        version: u32,
+        peer_id: String,
+        port: u16,
+    },
+    /// `error $Reason`, sent before closing a connection that is refused
+    Error { reason: String },
+    /// `choke $Key`, the sender will not send pieces of the file until it unchokes us
+    Choke { key: String },
+    /// `unchoke $Key`, the sender has an upload slot for us again
+    Unchoke { key: String },
+    /// `auth $Key`, asks to be admitted to the swarm of a private file
+    Auth { key: String },
+    /// `challenge $Key $Nonce`, answers an `auth` with a nonce to sign with the swarm secret
+    Challenge { key: String, nonce: String },
+    /// `proof $Key $Hmac`, the hex HMAC-SHA256 of the nonce signed with the swarm secret
+    Proof { key: String, hmac: String },
+}
+
+/// Error returned when incoming bytes are not a valid message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Nothing was received
+    Empty,
+    /// The message is not valid UTF-8
+    InvalidUtf8,
+    /// The first word is not a known command
+    UnknownCommand(String),
+    /// The command is known but its arguments are not
+    Malformed { command: String, reason: String },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Empty => write!(f, "empty message"),
+            ProtocolError::InvalidUtf8 => write!(f, "message is not valid utf-8"),
+            ProtocolError::UnknownCommand(command) => write!(f, "unknown command {:?}", command),
+            ProtocolError::Malformed { command, reason } => {
+                write!(f, "malformed {} message: {}", command, reason)
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl ProtocolError {
+    /// Builds a `Malformed` error for `command`.
+    pub fn malformed(command: &str, reason: impl Into<String>) -> ProtocolError {
+        ProtocolError::Malformed {
+            command: command.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+fn malformed(command: &str, reason: impl Into<String>) -> ProtocolError {
+    ProtocolError::malformed(command, reason)
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Announce { port, seed, leech } => {
+                let seed: Vec<String> = seed.iter().map(format_metafile).collect();
+                write!(
+                    f,
+                    "announce listen {} seed [{}] leech [{}]",
+                    port,
+                    seed.join(" "),
+                    leech.join(" ")
+                )
+            }
+            Message::Look { filename, filesize } => {
+                let mut criteria: Vec<String> = Vec::new();
+                if let Some(filename) = filename {
+                    criteria.push(format!("filename=\"{}\"", filename));
+                }
+                if let Some((op, size)) = filesize {
+                    criteria.push(format!("filesize{}\"{}\"", op.as_char(), size));
+                }
+                write!(f, "look [{}]", criteria.join(" "))
+            }
+            Message::Getfile { key } => write!(f, "getfile {}", key),
+            Message::Update { seed, leech } => write!(
+                f,
+                "update seed [{}] leech [{}]",
+                seed.join(" "),
+                leech.join(" ")
+            ),
+            Message::List(files) => {
+                let files: Vec<String> = files.iter().map(format_metafile).collect();
+                write!(f, "list [{}]", files.join(" "))
+            }
+            Message::Peers { key, peers } => {
+                let peers: Vec<String> = peers
+                    .iter()
+                    .map(|peer| peer.addr.to_string())
+                    .collect();
+                write!(f, "peers {} [{}]", key, peers.join(" "))
+            }
+            Message::Ok => write!(f, "ok"),
+            Message::Interested { key } => write!(f, "interested {}", key),
+            Message::Have { key, buffermap } => write!(f, "have {} {}", key, buffermap),
+            Message::HavePiece { key, index } => write!(f, "havepiece {} {}", key, index),
+            Message::Getpieces { key, pieces } => {
+                let pieces: Vec<String> = pieces.iter().map(|index| index.to_string()).collect();
+                write!(f, "getpieces {} [{}]", key, pieces.join(" "))
+            }
+            Message::Data { key, pieces } => {
+                let pieces: Vec<String> = pieces
+                    .iter()
+                    .map(|(index, piece)| format!("{}:{}", index, b64_enc(piece.clone())))
+                    .collect();
+                write!(f, "data {} [{}]", key, pieces.join(" "))
+            }
+            Message::Unavailable { key, pieces } => {
+                let pieces: Vec<String> = pieces.iter().map(|index| index.to_string()).collect();
+                write!(f, "unavailable {} [{}]", key, pieces.join(" "))
+            }
+            Message::Framing { framing } => write!(f, "framing {}", framing),
+            Message::Getdigests { key } => write!(f, "getdigests {}", key),
+            Message::Digests { key, digests } => {
+                write!(f, "digests {} [{}]", key, digests.join(" "))
+            }
+            Message::Hello {
+                version,
+                peer_id,
+                port,
+            } => write!(f, "hello {} {} {}", version, peer_id, port),
+            Message::Error { reason } => write!(f, "error {}", reason),
+            Message::Choke { key } => write!(f, "choke {}", key),
+            Message::Unchoke { key } => write!(f, "unchoke {}", key),
+            Message::Auth { key } => write!(f, "auth {}", key),
+            Message::Challenge { key, nonce } => write!(f, "challenge {} {}", key, nonce),
+            Message::Proof { key, hmac } => write!(f, "proof {} {}", key, hmac),
+        }
+    }
+}
+
+fn format_metafile(file: &MetaFile) -> String {
+    format!(
+        "{} {} {} {}",
+        file.file_name, file.length, file.piece_size, file.hash
+    )
+}
+
+impl Message {
+    /// Encodes the message as a newline terminated line ready to be sent.
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{}\n", self).into_bytes()
+    }
+
+    /// Encodes the message for a connection using `framing`.
+    ///
+    /// Only `data` messages are affected, in binary framing each piece is sent as
+    /// `[FRAME_PIECE][key length: u16][key][index: u32][length: u32][raw bytes]`
+    /// and the answer is closed by `[FRAME_END][key length: u16][key]`, all integers big endian.
+    pub fn encode_with(&self, framing: Framing) -> Vec<u8> {
+        match (framing, self) {
+            (Framing::Binary, Message::Data { key, pieces }) => {
+                let size: usize = pieces.iter().map(|(_, piece)| piece.len() + 11 + key.len()).sum();
+                let mut frames: Vec<u8> = Vec::with_capacity(size + 3 + key.len());
+                for (index, piece) in pieces {
+                    frames.push(FRAME_PIECE);
+                    push_key(&mut frames, key);
+                    frames.extend_from_slice(&(*index as u32).to_be_bytes());
+                    frames.extend_from_slice(&(piece.len() as u32).to_be_bytes());
+                    frames.extend_from_slice(piece);
+                }
+                frames.push(FRAME_END);
+                push_key(&mut frames, key);
+                frames
+            }
+            _ => self.encode(),
+        }
+    }
+
+    /// Tells if `byte` starts a binary frame rather than a text line.
+    pub fn is_frame_start(byte: u8) -> bool {
+        byte == FRAME_PIECE || byte == FRAME_END
+    }
+
+    /// Reads a `data` answer sent in binary framing, up to and including its closing frame.
+    ///
+    /// More pieces than asked for, or a piece longer than the pieces of the
+    /// file, are refused before being read.
+    ///
+    /// # Arguments
+    /// * `reader` - Where the frames are read from.
+    /// * `max_pieces` - How many pieces were asked for.
+    /// * `piece_size` - The size of the pieces of the file.
+    ///
+    /// # Returns
+    /// * `Result<Message, ProtocolError>` - The data message, or why the frames are invalid.
+    pub fn read_frames<R: Read + ?Sized>(
+        reader: &mut R,
+        max_pieces: usize,
+        piece_size: usize,
+    ) -> Result<Message, ProtocolError> {
+        let command = "data";
+        let max_len: usize = piece_size.min(MAX_FRAME_LEN);
+        let mut key: Option<String> = None;
+        let mut pieces: Vec<(usize, Vec<u8>)> = Vec::new();
+        loop {
+            let tag = read_array::<R, 1>(reader)?[0];
+            let key_len = u16::from_be_bytes(read_array(reader)?) as usize;
+            let mut frame_key = vec![0u8; key_len];
+            read_exact(reader, &mut frame_key)?;
+            let frame_key =
+                String::from_utf8(frame_key).map_err(|_| ProtocolError::InvalidUtf8)?;
+            match &key {
+                Some(key) if *key != frame_key => {
+                    return Err(malformed(
+                        command,
+                        format!("frame for {} inside answer for {}", frame_key, key),
+                    ))
+                }
+                Some(_) => {}
+                None => key = Some(frame_key),
+            }
+            match tag {
+                FRAME_PIECE => {
+                    let index = u32::from_be_bytes(read_array(reader)?) as usize;
+                    let len = u32::from_be_bytes(read_array(reader)?) as usize;
+                    if len > max_len {
+                        return Err(malformed(
+                            command,
+                            format!("piece {} is {} bytes long", index, len),
+                        ));
+                    }
+                    if pieces.len() == max_pieces {
+                        return Err(malformed(
+                            command,
+                            format!("more than the {} pieces asked for", max_pieces),
+                        ));
+                    }
+                    let mut piece = vec![0u8; len];
+                    read_exact(reader, &mut piece)?;
+                    pieces.push((index, piece));
+                }
+                FRAME_END => {
+                    return Ok(Message::Data {
+                        key: key.unwrap_or_default(),
+                        pieces,
+                    })
+                }
+                _ => return Err(malformed(command, format!("unknown frame tag {}", tag))),
+            }
+        }
+    }
+
+    /// Decodes a single line received from a peer or from the tracker.
+    ///
+    /// Trailing `\r`, `\n`, `\0` and spaces are ignored.
+    ///
+    /// # Arguments
+    /// * `bytes` - The raw line.
+    ///
+    /// # Returns
+    /// * `Result<Message, ProtocolError>` - The decoded message, or why it could not be decoded.
+    pub fn decode(bytes: &[u8]) -> Result<Message, ProtocolError> {
+        let line = std::str::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8)?;
+        let line = line.trim_matches(&['\r', '\n', '\0', ' '] as &[_]);
+        if line.is_empty() {
+            return Err(ProtocolError::Empty);
+        }
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let mut tokens = Tokens { command, rest };
+
+        let message = match command {
+            "announce" => {
+                tokens.keyword("listen")?;
+                let port = tokens.number("port")?;
+                let mut seed = Vec::new();
+                let mut leech = Vec::new();
+                if tokens.peek_keyword("seed") {
+                    seed = parse_metafiles(command, tokens.list()?)?;
+                }
+                if tokens.peek_keyword("leech") {
+                    leech = to_strings(tokens.list()?);
+                }
+                Message::Announce { port, seed, leech }
+            }
+            "look" => {
+                let mut filename = None;
+                let mut filesize = None;
+                for criterion in tokens.list()? {
+                    if let Some(value) = criterion.strip_prefix("filename=") {
+                        filename = Some(unquote(command, value)?.to_string());
+                    } else if let Some(value) = criterion.strip_prefix("filesize") {
+                        let op = value
+                            .chars()
+                            .next()
+                            .and_then(SizeOp::from_char)
+                            .ok_or_else(|| malformed(command, "missing filesize operator"))?;
+                        let size = unquote(command, &value[1..])?
+                            .parse()
+                            .map_err(|_| malformed(command, "filesize is not a number"))?;
+                        filesize = Some((op, size));
+                    } else {
+                        return Err(malformed(
+                            command,
+                            format!("unknown criterion {:?}", criterion),
+                        ));
+                    }
+                }
+                Message::Look { filename, filesize }
+            }
+            "getfile" => Message::Getfile { key: tokens.key()? },
+            "update" => {
+                let mut seed = Vec::new();
+                let mut leech = Vec::new();
+                if tokens.peek_keyword("seed") {
+                    seed = to_strings(tokens.list()?);
+                }
+                if tokens.peek_keyword("leech") {
+                    leech = to_strings(tokens.list()?);
+                }
+                Message::Update { seed, leech }
+            }
+            "list" => Message::List(parse_metafiles(command, tokens.list()?)?),
+            "peers" => {
+                let key = tokens.key()?;
+                let mut peers = Vec::new();
+                for (position, peer) in tokens.list()?.into_iter().enumerate() {
+                    let peer = parse_peer(peer).map_err(|reason| {
+                        malformed(command, format!("peer {} {:?}: {}", position, peer, reason))
+                    })?;
+                    peers.push(peer);
+                }
+                Message::Peers { key, peers }
+            }
+            "ok" => Message::Ok,
+            "framing" => {
+                let framing = match tokens.word("mode")? {
+                    "text" => Framing::Text,
+                    "binary" => Framing::Binary,
+                    mode => return Err(malformed(command, format!("unknown mode {:?}", mode))),
+                };
+                Message::Framing { framing }
+            }
+            "getdigests" => Message::Getdigests { key: tokens.key()? },
+            "digests" => {
+                let key = tokens.key()?;
+                let digests = tokens.list()?;
+                for (index, digest) in digests.iter().enumerate() {
+                    if digest.len() != 32 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(malformed(
+                            command,
+                            format!("digest of piece {} {:?} is not a md5", index, digest),
+                        ));
+                    }
+                }
+                Message::Digests {
+                    key,
+                    digests: to_strings(digests),
+                }
+            }
+            "hello" => Message::Hello {
+                version: tokens.number("version")?,
+                peer_id: tokens.identifier("peer id")?,
+                port: tokens.number("port")?,
+            },
+            "error" => Message::Error {
+                reason: tokens.remainder(),
+            },
+            "interested" => Message::Interested { key: tokens.key()? },
+            "choke" => Message::Choke { key: tokens.key()? },
+            "unchoke" => Message::Unchoke { key: tokens.key()? },
+            "auth" => Message::Auth { key: tokens.key()? },
+            "challenge" => Message::Challenge {
+                key: tokens.key()?,
+                nonce: tokens.identifier("nonce")?,
+            },
+            "proof" => Message::Proof {
+                key: tokens.key()?,
+                hmac: tokens.identifier("hmac")?,
+            },
+            "have" => {
+                let key = tokens.key()?;
+                let buffermap = tokens
+                    .word("buffermap")?
+                    .parse::<Bitfield>()
+                    .map_err(|e| malformed(command, format!("bad buffermap: {}", e)))?;
+                Message::Have { key, buffermap }
+            }
+            "havepiece" => Message::HavePiece {
+                key: tokens.key()?,
+                index: tokens.number("index")?,
+            },
+            "getpieces" | "unavailable" => {
+                let key = tokens.key()?;
+                let pieces = tokens
+                    .list()?
+                    .into_iter()
+                    .map(|index| {
+                        index
+                            .parse()
+                            .map_err(|_| malformed(command, format!("bad index {:?}", index)))
+                    })
+                    .collect::<Result<Vec<usize>, ProtocolError>>()?;
+                if command == "getpieces" {
+                    Message::Getpieces { key, pieces }
+                } else {
+                    Message::Unavailable { key, pieces }
+                }
+            }
+            "data" => {
+                let key = tokens.key()?;
+                let mut pieces = Vec::new();
+                for (position, entry) in tokens.list()?.into_iter().enumerate() {
+                    let (index, piece) = entry.split_once(':').ok_or_else(|| {
+                        malformed(command, format!("piece {} has no index", position))
+                    })?;
+                    let index = index.parse().map_err(|_| {
+                        malformed(
+                            command,
+                            format!("piece {} has a bad index {:?}", position, index),
+                        )
+                    })?;
+                    let piece = b64_dec(piece).map_err(|e| {
+                        malformed(
+                            command,
+                            format!("piece {} (index {}) is not base64: {}", position, index, e),
+                        )
+                    })?;
+                    pieces.push((index, piece));
+                }
+                Message::Data { key, pieces }
+            }
+            _ => return Err(ProtocolError::UnknownCommand(command.to_string())),
+        };
+        tokens.end()?;
+        Ok(message)
+    }
+}
+
+fn push_key(frames: &mut Vec<u8>, key: &str) {
+    frames.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    frames.extend_from_slice(key.as_bytes());
+}
+
+fn read_exact<R: Read + ?Sized>(reader: &mut R, buffer: &mut [u8]) -> Result<(), ProtocolError> {
+    reader
+        .read_exact(buffer)
+        .map_err(|e| malformed("data", format!("truncated frame: {}", e)))
+}
+
+fn read_array<R: Read + ?Sized, const N: usize>(reader: &mut R) -> Result<[u8; N], ProtocolError> {
+    let mut buffer = [0u8; N];
+    read_exact(reader, &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Cursor over the arguments of a message
+struct Tokens<'a> {
+    command: &'a str,
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    /// Takes the next space separated word
+    fn word(&mut self, what: &str) -> Result<&'a str, ProtocolError> {
+        let rest = self.rest.trim_start();
+        let end = rest.find(' ').unwrap_or(rest.len());
+        if end == 0 {
+            return Err(malformed(self.command, format!("missing {}", what)));
+        }
+        self.rest = &rest[end..];
+        Ok(&rest[..end])
+    }
+
+    /// Takes a file key, keys are alphanumeric
+    fn key(&mut self) -> Result<String, ProtocolError> {
+        self.identifier("key")
+    }
+
+    /// Takes an alphanumeric word
+    fn identifier(&mut self, what: &str) -> Result<String, ProtocolError> {
+        let word = self.word(what)?;
+        if !word.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(malformed(
+                self.command,
+                format!("{} {:?} is not alphanumeric", what, word),
+            ));
+        }
+        Ok(word.to_string())
+    }
+
+    /// Takes everything left on the line
+    fn remainder(&mut self) -> String {
+        let rest = self.rest.trim().to_string();
+        self.rest = "";
+        rest
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, ProtocolError> {
+        let word = self.word(what)?;
+        word.parse()
+            .map_err(|_| malformed(self.command, format!("{} {:?} is not a number", what, word)))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), ProtocolError> {
+        if self.word(keyword)? != keyword {
+            return Err(malformed(self.command, format!("expected {}", keyword)));
+        }
+        Ok(())
+    }
+
+    /// Consumes `keyword` if it is the next word
+    fn peek_keyword(&mut self, keyword: &str) -> bool {
+        let rest = self.rest.trim_start();
+        match rest.strip_prefix(keyword) {
+            Some(after) if after.starts_with([' ', '[']) => {
+                self.rest = after;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes a `[a b c]` list
+    fn list(&mut self) -> Result<Vec<&'a str>, ProtocolError> {
+        let rest = self.rest.trim_start();
+        let inner = rest
+            .strip_prefix('[')
+            .ok_or_else(|| malformed(self.command, "expected ["))?;
+        // IPv6 peers are in brackets inside the list
+        let mut depth: usize = 0;
+        let end = inner
+            .char_indices()
+            .find_map(|(index, c)| match c {
+                '[' => {
+                    depth += 1;
+                    None
+                }
+                ']' if depth == 0 => Some(index),
+                ']' => {
+                    depth -= 1;
+                    None
+                }
+                _ => None,
+            })
+            .ok_or_else(|| malformed(self.command, "missing ]"))?;
+        self.rest = &inner[end + 1..];
+        Ok(inner[..end].split_whitespace().collect())
+    }
+
+    fn end(&self) -> Result<(), ProtocolError> {
+        if self.rest.trim().is_empty() {
+            Ok(())
+        } else {
+            Err(malformed(
+                self.command,
+                format!("unexpected trailing {:?}", self.rest.trim()),
+            ))
+        }
+    }
+}
+
+/// Parses an `$Ip:$Port` or `$Host:$Port` peer entry, IPv6 addresses being in brackets
+fn parse_peer(peer: &str) -> Result<PeerConfig, String> {
+    let (address, port) = peer.rsplit_once(':').ok_or("missing port")?;
+    if address.is_empty() {
+        return Err("missing address".to_string());
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("port {:?} is not a number between 0 and 65535", port))?;
+    let ip: IpAddr = match address.strip_prefix('[').and_then(|v6| v6.strip_suffix(']')) {
+        Some(v6) => v6
+            .parse::<Ipv6Addr>()
+            .map(IpAddr::V6)
+            .map_err(|_| format!("invalid address {:?}", address))?,
+        None => match address.parse::<Ipv4Addr>() {
+            Ok(v4) => IpAddr::V4(v4),
+            Err(_) => resolve(address, port)?,
+        },
+    };
+    Ok(PeerConfig {
+        addr: SocketAddr::new(ip, port),
+    })
+}
+
+/// Resolves the host name of a peer entry to its first address
+fn resolve(host: &str, port: u16) -> Result<IpAddr, String> {
+    if !host
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        return Err(format!("invalid address {:?}", host));
+    }
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("could not resolve {:?}: {}", host, e))?
+        .next()
+        .map(|addr| addr.ip())
+        .ok_or_else(|| format!("no address for {:?}", host))
+}
+
+fn to_strings(list: Vec<&str>) -> Vec<String> {
+    list.into_iter().map(|s| s.to_string()).collect()
+}
+
+fn unquote<'a>(command: &str, value: &'a str) -> Result<&'a str, ProtocolError> {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return Ok(inner);
+        }
+    }
+    Err(malformed(command, format!("{:?} is not quoted", value)))
+}
+
+fn parse_metafiles(command: &str, list: Vec<&str>) -> Result<Vec<MetaFile>, ProtocolError> {
+    if !list.len().is_multiple_of(4) {
+        return Err(malformed(
+            command,
+            "files must be given as name length piece_size key",
+        ));
+    }
+    list.chunks(4)
+        .map(|file| {
+            Ok(MetaFile {
+                file_name: file[0].to_string(),
+                length: file[1]
+                    .parse()
+                    .map_err(|_| malformed(command, format!("bad length {:?}", file[1])))?,
+                piece_size: file[2]
+                    .parse()
+                    .map_err(|_| malformed(command, format!("bad piece size {:?}", file[2])))?,
+                hash: file[3].to_string(),
+                piece_digests: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    fn file(name: &str, hash: &str) -> MetaFile {
+        MetaFile {
+            file_name: name.to_string(),
+            length: 2097152,
+            piece_size: 1024,
+            hash: hash.to_string(),
+            piece_digests: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_tracker_messages() {
+        round_trip(Message::Announce {
+            port: 54321,
+            seed: vec![file("a.dat", "aaaa"), file("b.dat", "bbbb")],
+            leech: vec!["cccc".to_string()],
+        });
+        round_trip(Message::Announce {
+            port: 1,
+            seed: Vec::new(),
+            leech: Vec::new(),
+        });
+        round_trip(Message::Look {
+            filename: Some("a.dat".to_string()),
+            filesize: Some((SizeOp::Greater, 10)),
+        });
+        round_trip(Message::Look {
+            filename: None,
+            filesize: None,
+        });
+        round_trip(Message::Getfile {
+            key: "aaaa".to_string(),
+        });
+        round_trip(Message::Update {
+            seed: vec!["aaaa".to_string(), "bbbb".to_string()],
+            leech: Vec::new(),
+        });
+        round_trip(Message::List(vec![file("a.dat", "aaaa")]));
+        round_trip(Message::Peers {
+            key: "aaaa".to_string(),
+            peers: vec![
+                PeerConfig {
+                    addr: "1.2.3.4:1234".parse().unwrap(),
+                },
+                PeerConfig {
+                    addr: "[2001:db8::1]:6881".parse().unwrap(),
+                },
+            ],
+        });
+        round_trip(Message::Ok);
+    }
+
+    #[test]
+    fn test_round_trip_peer_messages() {
+        let key = "8905e92afeb80fc7722ec89eb0bf0966".to_string();
+        round_trip(Message::Interested { key: key.clone() });
+        round_trip(Message::Have {
+            key: key.clone(),
+            buffermap: "4:YA==".parse().unwrap(),
+        });
+        assert_eq!(
+            Message::Have {
+                key: key.clone(),
+                buffermap: Bitfield::full(9),
+            }
+            .to_string(),
+            format!("have {} 9:/4A=", key)
+        );
+        round_trip(Message::HavePiece {
+            key: key.clone(),
+            index: 12,
+        });
+        round_trip(Message::Getpieces {
+            key: key.clone(),
+            pieces: vec![3, 1, 4],
+        });
+        round_trip(Message::Data {
+            key: key.clone(),
+            pieces: vec![(0, b"Hello".to_vec()), (7, vec![0, 255, 10, 13])],
+        });
+        round_trip(Message::Unavailable {
+            key: key.clone(),
+            pieces: vec![2, 5],
+        });
+        round_trip(Message::Framing {
+            framing: Framing::Binary,
+        });
+        round_trip(Message::Getdigests { key: key.clone() });
+        round_trip(Message::Choke { key: key.clone() });
+        round_trip(Message::Unchoke { key: key.clone() });
+        round_trip(Message::Auth { key: key.clone() });
+        round_trip(Message::Challenge {
+            key: key.clone(),
+            nonce: "5f0c7e2a9b4d1e8f3a6c0b7d2e9f4a1c".to_string(),
+        });
+        round_trip(Message::Proof {
+            key: key.clone(),
+            hmac: "b613679a0814d9ec772f95d778c35fc5ff1697c493715653c6c712144292c5ad".to_string(),
+        });
+        round_trip(Message::Digests {
+            key,
+            digests: vec![
+                "d41d8cd98f00b204e9800998ecf8427e".to_string(),
+                "8905e92afeb80fc7722ec89eb0bf0966".to_string(),
+            ],
+        });
+        round_trip(Message::Hello {
+            version: PROTOCOL_VERSION,
+            peer_id: "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b".to_string(),
+            port: 54321,
+        });
+        round_trip(Message::Error {
+            reason: "unsupported protocol version 2".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_binary_frames() {
+        let message = Message::Data {
+            key: "8905e92afeb80fc7722ec89eb0bf0966".to_string(),
+            pieces: vec![(0, b"Hello\n".to_vec()), (70000, vec![0, 255, 10, 13]), (2, Vec::new())],
+        };
+        let frames = message.encode_with(Framing::Binary);
+        assert!(Message::is_frame_start(frames[0]));
+        // no base64, only 11 bytes of header per piece and the closing frame
+        assert_eq!(frames.len(), 3 * (11 + 32) + 10 + 3 + 32);
+        let mut reader = std::io::Cursor::new(frames);
+        assert_eq!(Message::read_frames(&mut reader, 3, 1024).unwrap(), message);
+
+        let empty = Message::Data {
+            key: "aaaa".to_string(),
+            pieces: Vec::new(),
+        };
+        let mut reader = std::io::Cursor::new(empty.encode_with(Framing::Binary));
+        assert_eq!(Message::read_frames(&mut reader, 3, 1024).unwrap(), empty);
+
+        // other messages stay text lines
+        assert_eq!(Message::Ok.encode_with(Framing::Binary), b"ok\n".to_vec());
+        assert!(!Message::is_frame_start(b'o'));
+    }
+
+    #[test]
+    fn test_binary_frames_errors() {
+        let message = Message::Data {
+            key: "aaaa".to_string(),
+            pieces: vec![(1, vec![1, 2, 3])],
+        };
+        let frames = message.encode_with(Framing::Binary);
+        let mut truncated = std::io::Cursor::new(frames[..frames.len() - 4].to_vec());
+        assert!(Message::read_frames(&mut truncated, 1, 1024).is_err());
+
+        let mut huge = vec![FRAME_PIECE, 0, 1, b'a', 0, 0, 0, 1];
+        huge.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Message::read_frames(&mut std::io::Cursor::new(huge), 1, 1024).is_err());
+
+        // pieces longer than the file's, or more pieces than asked for
+        let frames = message.encode_with(Framing::Binary);
+        assert!(Message::read_frames(&mut std::io::Cursor::new(frames.clone()), 1, 2).is_err());
+        let endless = Message::Data {
+            key: "aaaa".to_string(),
+            pieces: (0..1000).map(|index| (index, vec![0u8; 3])).collect(),
+        };
+        let frames = endless.encode_with(Framing::Binary);
+        assert!(Message::read_frames(&mut std::io::Cursor::new(frames.clone()), 2, 1024).is_err());
+        assert_eq!(
+            Message::read_frames(&mut std::io::Cursor::new(frames), 1000, 1024).unwrap(),
+            endless
+        );
+    }
+
+    #[test]
+    fn test_decode_tracker_answers() {
+        let answer = b"list [file_a.dat 2097152 1024 8905e92afeb80fc7722ec89eb0bf0966]\r\n";
+        assert_eq!(
+            Message::decode(answer).unwrap(),
+            Message::List(vec![file("file_a.dat", "8905e92afeb80fc7722ec89eb0bf0966")])
+        );
+        assert_eq!(Message::decode(b"ok\n").unwrap(), Message::Ok);
+        assert_eq!(
+            Message::decode(b"look [filesize<'10']").unwrap(),
+            Message::Look {
+                filename: None,
+                filesize: Some((SizeOp::Lower, 10)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Message::decode(b""), Err(ProtocolError::Empty));
+        assert_eq!(Message::decode(b"\0\0\n"), Err(ProtocolError::Empty));
+        assert_eq!(
+            Message::decode(b"EMPTY"),
+            Err(ProtocolError::UnknownCommand("EMPTY".to_string()))
+        );
+        assert!(Message::decode(b"have aaaa 0120").is_err());
+        assert!(Message::decode(b"have aaaa 4:YA").is_err());
+        assert!(Message::decode(b"have aaaa 2:YA==").is_err());
+        assert!(Message::decode(b"getpieces aaaa [1 x]").is_err());
+        assert!(Message::decode(b"getpieces aaaa [1 2").is_err());
+        assert!(Message::decode(b"data aaaa [1:!!!!]").is_err());
+        assert!(Message::decode(b"list [a.dat 10 1]").is_err());
+        assert!(Message::decode(b"ok trailing").is_err());
+        assert!(Message::decode(b"getfile ../etc").is_err());
+        assert!(Message::decode(b"digests aaaa [d41d8cd98f00b204e9800998ecf8427]").is_err());
+        assert!(Message::decode(b"digests aaaa [d41d8cd98f00b204e9800998ecf8427x]").is_err());
+        assert!(Message::decode(b"hello 1 abcd").is_err());
+        assert!(Message::decode(b"hello x abcd 80").is_err());
+        assert!(Message::decode(b"hello 1 ab-cd 80").is_err());
+        assert!(Message::decode(b"hello 1 abcd 70000").is_err());
+    }
+
+    #[test]
+    fn test_decode_peers_and_data_entries() {
+        let key = "8905e92afeb80fc7722ec89eb0bf0966";
+        assert_eq!(
+            Message::decode(format!("peers {} []", key).as_bytes()).unwrap(),
+            Message::Peers {
+                key: key.to_string(),
+                peers: Vec::new(),
+            }
+        );
+        // host names are resolved, IPv6 addresses come in brackets
+        let Message::Peers { peers, .. } =
+            Message::decode(format!("peers {} [localhost:12 [::1]:13]", key).as_bytes()).unwrap()
+        else {
+            panic!("expected peers");
+        };
+        assert!(peers[0].addr.ip().is_loopback());
+        assert_eq!(peers[0].addr.port(), 12);
+        assert_eq!(peers[1].addr, "[::1]:13".parse().unwrap());
+        assert_eq!(
+            Message::decode(format!("data {} []", key).as_bytes()).unwrap(),
+            Message::Data {
+                key: key.to_string(),
+                pieces: Vec::new(),
+            }
+        );
+        for bad in [
+            "peers aaaa [1.2.3.4]",
+            "peers aaaa [:1234]",
+            "peers aaaa [1.2.3.4:]",
+            "peers aaaa [1.2.3.4:65536]",
+            "peers aaaa [1.2.3.4:-1]",
+            "peers aaaa [1.2/3.4:12]",
+            "peers aaaa [2001:db8::1:12]",
+            "peers aaaa [[2001:db8::1]]",
+            "peers [1.2.3.4:12]",
+            "peers",
+            "data aaaa [MTEw]",
+            "data aaaa [:MTEw]",
+            "data aaaa [x:MTEw]",
+            "data aaaa [1:MTE]",
+            "data",
+        ] {
+            let error = Message::decode(bad.as_bytes()).unwrap_err();
+            assert!(
+                matches!(error, ProtocolError::Malformed { .. }),
+                "{} gave {:?}",
+                bad,
+                error
+            );
+        }
+        let error = Message::decode(b"peers aaaa [1.2.3.4:1 5.6.7.8:x]").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "malformed peers message: peer 1 \"5.6.7.8:x\": port \"x\" is not a number between 0 and 65535"
+        );
+    }
+
+    #[test]
+    fn test_decode_never_panics() {
+        let valid = "peers 8905e92afeb80fc7722ec89eb0bf0966 [1.2.3.4:1234 5.6.7.8:1]\n\
+            data 8905e92afeb80fc7722ec89eb0bf0966 [0:SGVsbG8= 1:AP8KDQ==]\n\
+            announce listen 12 seed [a 1 1 aaaa] leech [bbbb]\n\
+            look [filename=\"a\" filesize>\"1\"]";
+        for line in valid.lines() {
+            let line = line.as_bytes();
+            for end in 0..=line.len() {
+                let _ = Message::decode(&line[..end]);
+                let _ = Message::decode(&line[end..]);
+            }
+        }
+    }
+}
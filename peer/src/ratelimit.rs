@@ -0,0 +1,203 @@
+//! upload and download bandwidth limits
+//!
+//! Every limit is a token bucket shared by all the pool threads. The bytes a
+//! transfer actually moved are taken from the global bucket and from the
+//! bucket of its file, going into debt if needed. A task finding a bucket in
+//! debt is put back in the pool until the debt is paid back, so no worker
+//! sleeps on a limit.
+use crate::data::get_config_path;
+use hashbrown::HashMap;
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::warn;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Refills at `rate` bytes per second, holding at most one second of it
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` tokens and returns how long to wait before using them.
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Limits {
+    global: HashMap<Direction, TokenBucket>,
+    files: HashMap<(String, Direction), TokenBucket>,
+}
+
+lazy_static! {
+    /// limits of the config file, changed by the command line
+    static ref LIMITS: Mutex<Limits> = Mutex::new(load_limits());
+}
+
+// `upload-limit` and `download-limit` of the Peer section, and the Limits section,
+// in KiB/s
+fn load_limits() -> Limits {
+    let mut limits = Limits::default();
+    let Ok(conf) = Ini::load_from_file(get_config_path()) else {
+        return limits;
+    };
+    if let Some(section) = conf.section(Some("Peer")) {
+        for (name, direction) in [
+            ("upload-limit", Direction::Upload),
+            ("download-limit", Direction::Download),
+        ] {
+            match section.get(name).map(|limit| limit.parse::<u64>()) {
+                Some(Ok(0)) | None => (),
+                Some(Ok(limit)) => {
+                    limits.global.insert(direction, TokenBucket::new(limit * 1024));
+                }
+                Some(Err(e)) => warn!("Wrong {} : {}, no limit", name, e),
+            }
+        }
+    }
+    if let Some(section) = conf.section(Some("Limits")) {
+        for (key, value) in section.iter() {
+            match parse_file_limits(value) {
+                Some((upload, download)) => {
+                    for (limit, direction) in [(upload, Direction::Upload), (download, Direction::Download)] {
+                        if limit > 0 {
+                            limits
+                                .files
+                                .insert((key.to_string(), direction), TokenBucket::new(limit * 1024));
+                        }
+                    }
+                }
+                None => warn!("Wrong limits for {}, expected upload and download in KiB/s", key),
+            }
+        }
+    }
+    limits
+}
+
+/// Parses "<upload> <download>" in KiB/s.
+fn parse_file_limits(value: &str) -> Option<(u64, u64)> {
+    let mut limits = value.split_whitespace().map(|limit| limit.parse::<u64>());
+    match (limits.next(), limits.next(), limits.next()) {
+        (Some(Ok(upload)), Some(Ok(download)), None) => Some((upload, download)),
+        _ => None,
+    }
+}
+
+/// Limits all the transfers in one direction.
+///
+/// # Arguments
+/// * `direction` - Upload or download.
+/// * `kib_per_sec` - The limit in KiB/s, 0 to remove it.
+pub fn set_global_limit(direction: Direction, kib_per_sec: u64) {
+    let mut limits = LIMITS.lock().unwrap();
+    if kib_per_sec == 0 {
+        limits.global.remove(&direction);
+    } else {
+        limits.global.insert(direction, TokenBucket::new(kib_per_sec * 1024));
+    }
+}
+
+/// Limits the transfers of one file in one direction, on top of the global limit.
+///
+/// # Arguments
+/// * `key` - The key of the file.
+/// * `direction` - Upload or download.
+/// * `kib_per_sec` - The limit in KiB/s, 0 to remove it.
+pub fn set_file_limit(key: &str, direction: Direction, kib_per_sec: u64) {
+    let mut limits = LIMITS.lock().unwrap();
+    if kib_per_sec == 0 {
+        limits.files.remove(&(key.to_string(), direction));
+    } else {
+        limits
+            .files
+            .insert((key.to_string(), direction), TokenBucket::new(kib_per_sec * 1024));
+    }
+}
+
+/// Counts `bytes` of a file as transferred, returning how long the limits need to recover.
+pub fn reserve_bandwidth(key: &str, direction: Direction, bytes: usize) -> Duration {
+    let mut limits = LIMITS.lock().unwrap();
+    let now = Instant::now();
+    let global = match limits.global.get_mut(&direction) {
+        Some(bucket) => bucket.take(bytes, now),
+        None => Duration::ZERO,
+    };
+    let file = match limits.files.get_mut(&(key.to_string(), direction)) {
+        Some(bucket) => bucket.take(bytes, now),
+        None => Duration::ZERO,
+    };
+    global.max(file)
+}
+
+/// Returns how long to wait before transferring more of a file, ZERO if the limits allow it now.
+pub fn bandwidth_wait(key: &str, direction: Direction) -> Duration {
+    reserve_bandwidth(key, direction, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000);
+        // the first second is a burst
+        assert_eq!(bucket.take(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+        // debts add up, whichever thread made them
+        assert_eq!(bucket.take(500, start), Duration::from_secs(1));
+        assert_eq!(bucket.take(0, start + Duration::from_secs(1)), Duration::ZERO);
+        // idle time does not refill past one second
+        assert_eq!(bucket.take(1000, start + Duration::from_secs(10)), Duration::ZERO);
+        assert!(bucket.take(1, start + Duration::from_secs(10)) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_file_limits() {
+        let key = "ratelimit_test";
+        assert_eq!(parse_file_limits("10 0"), Some((10, 0)));
+        assert_eq!(parse_file_limits("10"), None);
+        assert_eq!(parse_file_limits("a b"), None);
+
+        assert_eq!(reserve_bandwidth(key, Direction::Upload, 1 << 20), Duration::ZERO);
+        set_file_limit(key, Direction::Upload, 1);
+        assert_eq!(reserve_bandwidth(key, Direction::Upload, 1024), Duration::ZERO);
+        assert!(reserve_bandwidth(key, Direction::Upload, 1024) > Duration::from_millis(900));
+        // waiting takes nothing from the bucket
+        let wait = bandwidth_wait(key, Direction::Upload);
+        assert!(wait > Duration::from_millis(800));
+        assert!(bandwidth_wait(key, Direction::Upload) <= wait);
+        assert_eq!(bandwidth_wait(key, Direction::Download), Duration::ZERO);
+        assert_eq!(reserve_bandwidth(key, Direction::Download, 1 << 20), Duration::ZERO);
+        set_file_limit(key, Direction::Upload, 0);
+        assert_eq!(reserve_bandwidth(key, Direction::Upload, 1 << 20), Duration::ZERO);
+    }
+}
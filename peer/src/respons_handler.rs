@@ -1,198 +1,297 @@
-use crate::data::b64_dec;
-use crate::data::{MetaFile, PeerConfig};
+use crate::data::{get_buffer_size, get_peer_id, MetaFile, PeerConfig};
+use crate::db::{get_file, get_peer_key};
+use crate::protocol::{Message, ProtocolError, PROTOCOL_VERSION};
 use crate::tasks::Peer;
 use crate::threads::Pool;
-use log::{error, trace};
-use regex::Regex;
+use crate::transport::Transport;
+use hashbrown::HashSet;
+use log::{error, trace, warn};
 use std::error::Error;
 use std::io;
-use std::net::TcpStream;
 
 pub trait ExpectedAnswer {
-    // Check if the answer is correctly formatted
-    fn check_answer(&self, answer: &str) -> Result<String, Box<dyn Error>>;
+    // Check if the answer is the expected message
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>>;
     // Retrieve the relevant data from the answer returns an Answer enum which convey right data type
-    fn retrieve_data(&self, answer: String) -> Answer;
+    fn retrieve_data(&self, answer: Message) -> Answer;
     // Shutdown the stream so that it does ping pong style communication
-    fn shutdown(&self, stream: &mut TcpStream);
+    fn shutdown(&self, stream: &mut dyn Transport);
+    // Check a received answer and retrieve its data in one go
+    fn expect(&self, answer: Result<Message, ProtocolError>) -> Result<Answer, Box<dyn Error>> {
+        let answer = answer?;
+        self.check_answer(&answer)?;
+        Ok(self.retrieve_data(answer))
+    }
+}
+
+fn bad_answer(expected: &str, answer: &Message) -> Box<dyn Error> {
+    let answer = answer.to_string();
+    error!(
+        "Failed answer, expected {} got: {}",
+        expected,
+        answer.chars().take(128).collect::<String>()
+    );
+    Box::new(io::Error::other(format!("Bad answer, expected {}", expected)))
+}
+
+/// Checks that `key` is a file key, the md5 of the file written in hexadecimal
+fn check_file_key(command: &str, key: &str) -> Result<(), Box<dyn Error>> {
+    if key.len() != 32 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Box::new(ProtocolError::malformed(
+            command,
+            format!("{:?} is not a 32 characters md5 key", key),
+        )));
+    }
+    Ok(())
 }
+
 impl ExpectedAnswer for ExpectOk {
-    fn check_answer(&self, answer: &str) -> Result<String, Box<dyn Error>> {
-        match Regex::new(r"^ok$") {
-            Ok(re) => {
-                let first_line = answer.lines().next().unwrap_or("");
-                if re.is_match(first_line) {
-                    Ok("Correct tracker answer".to_string())
-                } else {
-                    error!("Failed tracker answer: {}", answer);
-                    Err(Box::new(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Bad tracker answer",
-                    )))
-                }
-            }
-            Err(e) => {
-                error!("Regex error: {}", e);
-                Err(Box::new(e))
-            }
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>> {
+        match answer {
+            Message::Ok => Ok("Correct tracker answer".to_string()),
+            _ => Err(bad_answer("ok", answer)),
         }
     }
-    fn retrieve_data(&self, _answer: String) -> Answer {
+    fn retrieve_data(&self, _answer: Message) -> Answer {
         Answer::Ok
     }
 
-    fn shutdown(&self, stream: &mut TcpStream) {
-        stream.shutdown(std::net::Shutdown::Both).unwrap();
+    fn shutdown(&self, stream: &mut dyn Transport) {
+        if let Err(e) = stream.shutdown() {
+            trace!("Stream already closed: {}", e);
+        }
     }
 }
 impl ExpectedAnswer for ExpectList {
-    fn check_answer(&self, answer: &str) -> Result<String, Box<dyn Error>> {
-        //match Regex::new(r"^list \[(\S+ \d+ \d+ \w+ ?)*\] ?((\u{000A})?(\u{000D})?(\u{0000})*)?$") {
-        match Regex::new(r"^list \[(\S+ \d+ \d+ \w+ ?)*\] ?$") {
-            Ok(re) => {
-                let first_line = answer.lines().next().unwrap_or("");
-                trace!("Answer to be checked: {}", first_line);
-                if re.is_match(first_line) {
-                    Ok("Correct tracker answer".to_string())
-                } else {
-                    error!("Failed tracker answer: {}", answer);
-                    for c in first_line.chars() {
-                        trace!("U+{:04X} {}", c as u32, c)
-                    }
-                    Err(Box::new(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Bad tracker answer",
-                    )))
-                }
-            }
-            Err(e) => {
-                error!("Regex error: {}", e);
-                Err(Box::new(e))
-            }
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>> {
+        match answer {
+            Message::List(_) => Ok("Correct tracker answer".to_string()),
+            _ => Err(bad_answer("list", answer)),
         }
     }
     // precond : answer is a valid list answer
-    fn retrieve_data(&self, answer: String) -> Answer {
-        let answer = answer.trim().to_string();
+    fn retrieve_data(&self, answer: Message) -> Answer {
         trace!("Answer to be retrieved: {}", answer);
-        let answer = &answer[6..answer.len() - 1]; // Remove "list [" and "]"
-        let re_file =
-            Regex::new(r"(?P<file_name>\S+) (?P<length>\d+) (?P<piece_size>\d+) (?P<hash>\w+)")
-                .unwrap();
         let mut files: Vec<MetaFile> = Vec::new();
-        for caps in re_file.captures_iter(answer) {
-            let file = MetaFile {
-                file_name: caps["file_name"].to_string(),
-                length: caps["length"].parse().unwrap(),
-                piece_size: caps["piece_size"].parse().unwrap(),
-                hash: caps["hash"].to_string(),
-            };
-            let mut already_in: bool = false;
-            for e in &files {
-                if (*e).hash == file.hash {
-                    already_in = true;
-                    break;
+        if let Message::List(list) = answer {
+            for file in list {
+                let mut already_in: bool = false;
+                for e in &files {
+                    if e.hash == file.hash {
+                        already_in = true;
+                        break;
+                    }
+                }
+                if !already_in {
+                    files.push(file);
                 }
-            }
-            if !already_in {
-                files.push(file);
             }
         }
         Answer::List(files)
     }
 
-    fn shutdown(&self, stream: &mut TcpStream) {
-        stream.shutdown(std::net::Shutdown::Both).unwrap();
+    fn shutdown(&self, stream: &mut dyn Transport) {
+        if let Err(e) = stream.shutdown() {
+            trace!("Stream already closed: {}", e);
+        }
     }
 }
 
 impl ExpectedAnswer for ExpectPeers {
-    fn check_answer(&self, answer: &str) -> Result<String, Box<dyn Error>> {
-        // TODO Correct chech instead of always true
-        let res: String = String::from(answer);
-        Ok(res)
+    // Grammar is checked while decoding, here the values are checked
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>> {
+        match answer {
+            Message::Peers { key, peers } => {
+                check_file_key("peers", key)?;
+                for (position, peer) in peers.iter().enumerate() {
+                    if peer.addr.port() == 0 {
+                        return Err(Box::new(ProtocolError::malformed(
+                            "peers",
+                            format!("peer {} {} has port 0", position, peer.addr.ip()),
+                        )));
+                    }
+                }
+                Ok("Correct tracker answer".to_string())
+            }
+            _ => Err(bad_answer("peers", answer)),
+        }
     }
-    fn retrieve_data(&self, answer: String) -> Answer {
-        let answer = answer.trim().to_string();
+    // precond : answer is a valid peers answer
+    fn retrieve_data(&self, answer: Message) -> Answer {
         trace!("Answer to be retrieved: {}", answer);
-        // get hash
-        let key: String = String::from(&answer[6..38]);
-        // Remove "peers %hash% [" and "]"
-        let answer = &answer[40..answer.len() - 1];
         let mut ret: Vec<Peer> = Vec::new();
-
-        let peers = answer.split(" ");
-
-        let myself = PeerConfig::new();
-        for peer in peers {
-            let splitted: Vec<&str> = peer.split(":").collect();
-            let address: &str = splitted[0];
-            let port: &str = splitted[1];
-            let port: u16 = port.parse().unwrap();
-
-            trace!("Succefully captured peer : {}:{}", address, port);
-            let config: PeerConfig = PeerConfig { address: address.to_string(), port };
-            if myself.address == config.address && myself.port == config.port {
-                continue;
+        if let Message::Peers { key, peers } = answer {
+            let myself = PeerConfig::new();
+            let mut seen: HashSet<String> = HashSet::new();
+            for config in peers {
+                trace!("Succefully captured peer : {}", config.addr);
+                if myself.addr == config.addr {
+                    continue;
+                }
+                if !seen.insert(get_peer_key(config.clone())) {
+                    warn!("Peer {} listed twice", config.addr);
+                    continue;
+                }
+                let hash: String = key.clone();
+                // trying to init with an empty pool
+                let pool: Pool = Pool::new(0);
+                ret.push(Peer {
+                    hash,
+                    length_tcp: 0,
+                    config,
+                    pool,
+                });
             }
-            let hash: String = key.clone();
-            // trying to init with an empty pool
-            let pool: Pool = Pool::new(0);
-            ret.push(Peer { hash, length_tcp: 0, config, pool });
         }
 
-
         Answer::Peers(ret)
     }
-    fn shutdown(&self, stream: &mut TcpStream) {
-        todo!()
+    fn shutdown(&self, stream: &mut dyn Transport) {
+        if let Err(e) = stream.shutdown() {
+            trace!("Stream already closed: {}", e);
+        }
     }
 }
 
 impl ExpectedAnswer for ExpectData {
-    fn check_answer(&self, answer: &str) -> Result<String, Box<dyn Error>> {
-        // TODO Correct chech instead of always true
-        let res: String = String::from(answer);
-        if answer.to_string().starts_with("data ") {
-            return Ok(res);
-        }
-        let error = io::Error::new(io::ErrorKind::InvalidInput, "The input string is empty");
-        return Err(Box::new(error));
-    }
-    fn retrieve_data(&self, answer: String) -> Answer {
-        let mut map: Vec<(usize, Vec<u8>)> = Vec::new();
-
-        let answer: String = answer.trim().to_string();
-
-        let datas: Vec<&str> = answer.as_str().split('[').collect();
-        let datas: Vec<&str> = datas[1].split(']').collect();
-        let datas: Vec<&str> = datas[0].split(' ').collect();
-
-        for data in datas {
-            let splitted: Vec<&str> = data.split(':').collect();
-            let key: usize = splitted[0].parse().unwrap();
-            let data_str: String = splitted[1].to_string();
-
-            // trace!("Key : {}, Value : {}", key, data_str);
-
-            // convert data string into u8, using 8 bits chunks
-            /*
-            let mut pieces: Vec<u8> = Vec::new();
-            for chunk in data_str.as_bytes().chunks(8) {
-                let chunk_str = std::str::from_utf8(chunk).unwrap();
-                let num = u8::from_str_radix(chunk_str, 2).unwrap(); //2 means base 2 binary to u8
-                //trace!("Num : {}", num);
-                pieces.push(num);
+    // Grammar is checked while decoding, here the pieces are checked against the file
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>> {
+        match answer {
+            Message::Data { key, pieces } => {
+                check_file_key("data", key)?;
+                let file: Option<MetaFile> = get_file(key);
+                let mut seen: HashSet<usize> = HashSet::new();
+                for (index, piece) in pieces {
+                    if !seen.insert(*index) {
+                        return Err(Box::new(ProtocolError::malformed(
+                            "data",
+                            format!("piece {} sent twice", index),
+                        )));
+                    }
+                    if let Some(file) = &file {
+                        if *index >= get_buffer_size(file) {
+                            return Err(Box::new(ProtocolError::malformed(
+                                "data",
+                                format!(
+                                    "piece {} is out of {} ({} pieces)",
+                                    index,
+                                    file.file_name,
+                                    get_buffer_size(file)
+                                ),
+                            )));
+                        }
+                        if piece.len() > file.piece_size {
+                            return Err(Box::new(ProtocolError::malformed(
+                                "data",
+                                format!(
+                                    "piece {} is {} bytes long, pieces of {} are {} bytes",
+                                    index,
+                                    piece.len(),
+                                    file.file_name,
+                                    file.piece_size
+                                ),
+                            )));
+                        }
+                    }
+                }
+                Ok("Correct peer answer".to_string())
             }
-            */
-            let pieces: Vec<u8> = b64_dec(data_str);
+            _ => Err(bad_answer("data", answer)),
+        }
+    }
+    // precond : answer is a valid data answer
+    fn retrieve_data(&self, answer: Message) -> Answer {
+        match answer {
+            Message::Data { pieces, .. } => Answer::Data(pieces),
+            _ => Answer::Data(Vec::new()),
+        }
+    }
+    fn shutdown(&self, stream: &mut dyn Transport) {
+        if let Err(e) = stream.shutdown() {
+            trace!("Stream already closed: {}", e);
+        }
+    }
+}
 
-            map.push((key, pieces));
+impl ExpectedAnswer for ExpectDigests {
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>> {
+        match answer {
+            Message::Digests { key, digests } => {
+                check_file_key("digests", key)?;
+                if let Some(file) = get_file(key) {
+                    // an empty list means the peer does not know them
+                    if !digests.is_empty() && digests.len() != get_buffer_size(&file) {
+                        return Err(Box::new(ProtocolError::malformed(
+                            "digests",
+                            format!(
+                                "{} digests for the {} pieces of {}",
+                                digests.len(),
+                                get_buffer_size(&file),
+                                file.file_name
+                            ),
+                        )));
+                    }
+                }
+                Ok("Correct peer answer".to_string())
+            }
+            _ => Err(bad_answer("digests", answer)),
+        }
+    }
+    // precond : answer is a valid digests answer
+    fn retrieve_data(&self, answer: Message) -> Answer {
+        match answer {
+            Message::Digests { digests, .. } => Answer::Digests(digests),
+            _ => Answer::Digests(Vec::new()),
         }
-        Answer::Data(map)
     }
-    fn shutdown(&self, stream: &mut TcpStream) {
-        todo!();
+    fn shutdown(&self, stream: &mut dyn Transport) {
+        if let Err(e) = stream.shutdown() {
+            trace!("Stream already closed: {}", e);
+        }
+    }
+}
+
+// Used on both ends of the handshake, to check the hello we receive
+impl ExpectedAnswer for ExpectHello {
+    fn check_answer(&self, answer: &Message) -> Result<String, Box<dyn Error>> {
+        match answer {
+            Message::Hello {
+                version,
+                peer_id,
+                port,
+            } => {
+                if *version != PROTOCOL_VERSION {
+                    return Err(Box::new(io::Error::other(format!(
+                        "Unsupported protocol version {}, expected {}",
+                        version, PROTOCOL_VERSION
+                    ))));
+                }
+                if *peer_id == get_peer_id() {
+                    return Err(Box::new(io::Error::other("Connected to myself")));
+                }
+                if *port == 0 {
+                    return Err(Box::new(ProtocolError::malformed("hello", "port 0")));
+                }
+                Ok("Correct peer hello".to_string())
+            }
+            Message::Error { reason } => Err(Box::new(io::Error::other(format!(
+                "Refused by peer: {}",
+                reason
+            )))),
+            _ => Err(bad_answer("hello", answer)),
+        }
+    }
+    // precond : answer is a valid hello
+    fn retrieve_data(&self, answer: Message) -> Answer {
+        match answer {
+            Message::Hello { peer_id, port, .. } => Answer::Hello { peer_id, port },
+            _ => Answer::Ok,
+        }
+    }
+    fn shutdown(&self, stream: &mut dyn Transport) {
+        if let Err(e) = stream.shutdown() {
+            trace!("Stream already closed: {}", e);
+        }
     }
 }
 
@@ -202,11 +301,15 @@ pub enum Answer {
     List(Vec<MetaFile>),
     Peers(Vec<Peer>),
     Data(Vec<(usize, Vec<u8>)>),
+    Hello { peer_id: String, port: u16 },
+    Digests(Vec<String>),
 }
 pub struct ExpectOk;
 pub struct ExpectList;
 pub struct ExpectPeers;
 pub struct ExpectData;
+pub struct ExpectHello;
+pub struct ExpectDigests;
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -214,9 +317,10 @@ mod tests {
     #[test]
     fn test_check_answer_with_list_trait() {
         let answer = "list [file_a.dat 2097152 1024 8905e92afeb80fc7722ec89eb0bf0966]\r\n";
+        let answer = Message::decode(answer.as_bytes()).unwrap();
         let expect_list = ExpectList;
 
-        let result = expect_list.check_answer(answer);
+        let result = expect_list.check_answer(&answer);
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "Correct tracker answer");
@@ -225,11 +329,139 @@ mod tests {
     #[test]
     fn test_check_answer_with_list_trait_additional_elements() {
         let answer = "list [file_a.dat 2097152 1024 8905e92afeb80fc7722ec89eb0bf0966 file_b.dat 2097152 1024 8905e92afeb80fc7722ec89eb0bf0966 file_c.dat 2097152 1024 8905e92afeb80fc7722ec89eb0bf0966 file_d.dat 2097152 1024 8905e92afeb80fc7722ec89eb0bf0966]\r\n";
+        let answer = Message::decode(answer.as_bytes()).unwrap();
         let expect_list = ExpectList;
 
-        let result = expect_list.check_answer(answer);
+        let result = expect_list.check_answer(&answer);
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "Correct tracker answer");
     }
+
+    const KEY: &str = "8905e92afeb80fc7722ec89eb0bf0966";
+
+    fn decode(answer: &str) -> Result<Message, ProtocolError> {
+        Message::decode(answer.as_bytes())
+    }
+
+    #[test]
+    fn test_expect_peers() {
+        let answer = decode(&format!("peers {} [1.2.3.4:1234 5.6.7.8:80 1.2.3.4:1234]\n", KEY));
+        match ExpectPeers.expect(answer).unwrap() {
+            Answer::Peers(peers) => {
+                assert_eq!(peers.len(), 2);
+                assert_eq!(peers[0].hash, KEY);
+                assert_eq!(peers[1].config.addr, "5.6.7.8:80".parse().unwrap());
+            }
+            other => panic!("expected peers, got {:?}", other),
+        }
+
+        match ExpectPeers.expect(decode(&format!("peers {} []", KEY))).unwrap() {
+            Answer::Peers(peers) => assert!(peers.is_empty()),
+            other => panic!("expected peers, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expect_peers_errors() {
+        for answer in [
+            "peers 8905e92a [1.2.3.4:1234]".to_string(),
+            "peers 8905e92afeb80fc7722ec89eb0bf0966aa [1.2.3.4:1234]".to_string(),
+            format!("peers {} [1.2.3.4:0]", KEY),
+            format!("peers {} [1.2.3.4]", KEY),
+            format!("peers {} 1.2.3.4:12", KEY),
+            "list []".to_string(),
+            "".to_string(),
+        ] {
+            let result = ExpectPeers.expect(decode(&answer));
+            assert!(result.is_err(), "{:?} was accepted", answer);
+        }
+        let error = ExpectPeers
+            .expect(decode("peers abc [1.2.3.4:12]"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "malformed peers message: \"abc\" is not a 32 characters md5 key"
+        );
+    }
+
+    #[test]
+    fn test_expect_data() {
+        let answer = decode(&format!("data {} [0:SGVsbG8= 3:]", KEY));
+        match ExpectData.expect(answer).unwrap() {
+            Answer::Data(pieces) => {
+                assert_eq!(pieces, vec![(0, b"Hello".to_vec()), (3, Vec::new())]);
+            }
+            other => panic!("expected data, got {:?}", other),
+        }
+
+        match ExpectData.expect(decode(&format!("data {} []", KEY))).unwrap() {
+            Answer::Data(pieces) => assert!(pieces.is_empty()),
+            other => panic!("expected data, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expect_digests() {
+        let answer = decode(&format!("digests {} [d41d8cd98f00b204e9800998ecf8427e]", KEY));
+        match ExpectDigests.expect(answer).unwrap() {
+            Answer::Digests(digests) => {
+                assert_eq!(digests, vec!["d41d8cd98f00b204e9800998ecf8427e".to_string()]);
+            }
+            other => panic!("expected digests, got {:?}", other),
+        }
+        assert!(ExpectDigests
+            .expect(decode("digests abc [d41d8cd98f00b204e9800998ecf8427e]"))
+            .is_err());
+        assert!(ExpectDigests.expect(decode("ok")).is_err());
+    }
+
+    #[test]
+    fn test_expect_hello() {
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+            peer_id: "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b".to_string(),
+            port: 6000,
+        };
+        match ExpectHello.expect(Ok(hello)).unwrap() {
+            Answer::Hello { peer_id, port } => {
+                assert_eq!(peer_id, "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b");
+                assert_eq!(port, 6000);
+            }
+            other => panic!("expected hello, got {:?}", other),
+        }
+
+        for answer in [
+            Message::Hello {
+                version: PROTOCOL_VERSION + 1,
+                peer_id: "0f3e9a1b2c4d5e6f7a8b9c0d1e2f3a4b".to_string(),
+                port: 6000,
+            },
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                peer_id: get_peer_id(),
+                port: 6000,
+            },
+            Message::Error {
+                reason: "unsupported protocol version".to_string(),
+            },
+            Message::Ok,
+        ] {
+            assert!(ExpectHello.expect(Ok(answer.clone())).is_err(), "{} was accepted", answer);
+        }
+    }
+
+    #[test]
+    fn test_expect_data_errors() {
+        for answer in [
+            format!("data {} [0:SGVsbG8= 0:SGVsbG8=]", KEY),
+            format!("data {} [0:SGVsbG8]", KEY),
+            format!("data {} [SGVsbG8=]", KEY),
+            "data abc [0:SGVsbG8=]".to_string(),
+            "EMPTY".to_string(),
+        ] {
+            let result = ExpectData.expect(decode(&answer));
+            assert!(result.is_err(), "{:?} was accepted", answer);
+        }
+    }
 }
@@ -0,0 +1,232 @@
+//! choice of the pieces to ask a peer for
+use crate::bitfield::Bitfield;
+use crate::data::get_config_path;
+use hashbrown::HashMap;
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Pieces picked at random before `RandomFirst` switches to rarest first
+const RANDOM_PIECES: usize = 4;
+
+/// Picks which of the pieces a peer can give us are asked first.
+pub trait PieceSelector {
+    /// Chooses up to `nb_pieces` pieces.
+    ///
+    /// # Arguments
+    /// * `candidates` - The pieces the peer has and we do not.
+    /// * `availability` - How many peers have each piece, may be shorter than `candidates`.
+    /// * `done` - How many pieces we already have.
+    /// * `nb_pieces` - How many pieces to choose at most.
+    ///
+    /// # Returns
+    /// * `Vec<usize>` - The indexes of the chosen pieces, in the order to ask them.
+    fn select(
+        &self,
+        candidates: &Bitfield,
+        availability: &[usize],
+        done: usize,
+        nb_pieces: usize,
+    ) -> Vec<usize>;
+}
+
+/// The pieces held by the fewest peers, so they do not disappear with them
+pub struct RarestFirst;
+
+impl PieceSelector for RarestFirst {
+    fn select(
+        &self,
+        candidates: &Bitfield,
+        availability: &[usize],
+        _done: usize,
+        nb_pieces: usize,
+    ) -> Vec<usize> {
+        let mut pieces: Vec<usize> = candidates.ones().collect();
+        pieces.sort_by_key(|&i| (availability.get(i).copied().unwrap_or(0), i));
+        pieces.truncate(nb_pieces);
+        pieces
+    }
+}
+
+/// The pieces in file order, so the beginning can be read while downloading
+pub struct Sequential;
+
+impl PieceSelector for Sequential {
+    fn select(
+        &self,
+        candidates: &Bitfield,
+        _availability: &[usize],
+        _done: usize,
+        nb_pieces: usize,
+    ) -> Vec<usize> {
+        candidates.ones().take(nb_pieces).collect()
+    }
+}
+
+/// Random pieces until we have a few to share, then rarest first.
+///
+/// A new peer has nothing to give, rare pieces are slow to get,
+/// so common ones are taken first to start trading sooner.
+pub struct RandomFirst;
+
+impl PieceSelector for RandomFirst {
+    fn select(
+        &self,
+        candidates: &Bitfield,
+        availability: &[usize],
+        done: usize,
+        nb_pieces: usize,
+    ) -> Vec<usize> {
+        if done >= RANDOM_PIECES {
+            return RarestFirst.select(candidates, availability, done, nb_pieces);
+        }
+        let mut pieces: Vec<usize> = candidates.ones().collect();
+        // Fisher-Yates, seeded by the random keys of the std hasher
+        let mut seed = RandomState::new().hash_one(pieces.len());
+        for i in (1..pieces.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            pieces.swap(i, (seed % (i as u64 + 1)) as usize);
+        }
+        pieces.truncate(nb_pieces);
+        pieces
+    }
+}
+
+/// The piece selectors a download can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    #[default]
+    RarestFirst,
+    Sequential,
+    RandomFirst,
+}
+
+impl Strategy {
+    pub fn selector(&self) -> Box<dyn PieceSelector> {
+        match self {
+            Strategy::RarestFirst => Box::new(RarestFirst),
+            Strategy::Sequential => Box::new(Sequential),
+            Strategy::RandomFirst => Box::new(RandomFirst),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Strategy::RarestFirst => "rarest-first",
+            Strategy::Sequential => "sequential",
+            Strategy::RandomFirst => "random-first",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rarest-first" => Ok(Strategy::RarestFirst),
+            "sequential" => Ok(Strategy::Sequential),
+            "random-first" => Ok(Strategy::RandomFirst),
+            _ => Err(format!(
+                "unknown strategy {}, expected rarest-first, sequential or random-first",
+                s
+            )),
+        }
+    }
+}
+
+lazy_static! {
+    static ref STRATEGIES: Mutex<HashMap<String, Strategy>> = Mutex::new(HashMap::new());
+    /// `piece-selection` of the Peer section, read once
+    static ref DEFAULT_STRATEGY: Strategy = Ini::load_from_file(get_config_path())
+        .ok()
+        .and_then(|conf| {
+            conf.section(Some("Peer"))
+                .and_then(|section| section.get("piece-selection"))
+                .and_then(|name| match name.parse() {
+                    Ok(strategy) => Some(strategy),
+                    Err(e) => {
+                        warn!("{}, using rarest-first", e);
+                        None
+                    }
+                })
+        })
+        .unwrap_or_default();
+}
+
+/// Chooses the strategy of a download.
+pub fn set_strategy(key: &str, strategy: Strategy) {
+    let mut strategies = STRATEGIES.lock().unwrap();
+    strategies.insert(key.to_string(), strategy);
+}
+
+/// Returns the strategy of a download, the configured one if none was chosen.
+pub fn get_strategy(key: &str) -> Strategy {
+    let strategies = STRATEGIES.lock().unwrap();
+    strategies.get(key).copied().unwrap_or(*DEFAULT_STRATEGY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(len: usize, ones: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for &i in ones {
+            bitfield.set(i, true);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let candidates = bitfield(6, &[0, 1, 3, 4, 5]);
+        let availability = [3, 1, 0, 2, 1];
+        // piece 5 is past the counts, nobody else announced it
+        assert_eq!(RarestFirst.select(&candidates, &availability, 0, 3), vec![5, 1, 4]);
+        assert_eq!(RarestFirst.select(&candidates, &availability, 0, 10).len(), 5);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let candidates = bitfield(6, &[1, 3, 4]);
+        assert_eq!(Sequential.select(&candidates, &[9, 9, 9, 9, 9, 0], 0, 2), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_random_first() {
+        let candidates = bitfield(100, &(0..100).step_by(2).collect::<Vec<usize>>());
+        let availability = vec![1; 100];
+        let mut pieces = RandomFirst.select(&candidates, &availability, 0, 10);
+        assert_eq!(pieces.len(), 10);
+        assert!(pieces.iter().all(|&i| candidates.get(i)));
+        pieces.sort();
+        pieces.dedup();
+        assert_eq!(pieces.len(), 10);
+
+        let mut availability = availability;
+        availability[50] = 0;
+        let pieces = RandomFirst.select(&candidates, &availability, RANDOM_PIECES, 1);
+        assert_eq!(pieces, vec![50]);
+    }
+
+    #[test]
+    fn test_strategy_names() {
+        for strategy in [Strategy::RarestFirst, Strategy::Sequential, Strategy::RandomFirst] {
+            assert_eq!(strategy.to_string().parse::<Strategy>().unwrap(), strategy);
+        }
+        assert!("fastest".parse::<Strategy>().is_err());
+        set_strategy("selector_test", Strategy::Sequential);
+        assert_eq!(get_strategy("selector_test"), Strategy::Sequential);
+    }
+}
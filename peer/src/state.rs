@@ -0,0 +1,367 @@
+//! on-disk copy of our files and buffermaps, reloaded when the client starts
+use crate::bitfield::Bitfield;
+use crate::data::{get_buffer_size, get_config_path, get_piece_digests, MetaFile, PeerConfig};
+use crate::db::set_peer_to_file;
+use crate::threads::TaskHandle;
+use hashbrown::{HashMap, HashSet};
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the store inside the state directory
+const STATE_FILE: &str = "files.ini";
+/// Minimum time between two saves triggered by `save_state_soon`
+const SAVE_PERIOD: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref STATE_DIR: Mutex<Option<String>> = Mutex::new(None);
+    /// our files with the pieces written on disk, which is what gets saved
+    static ref TRACKED: Mutex<HashMap<String, (MetaFile, Bitfield)>> = Mutex::new(HashMap::new());
+    static ref LAST_SAVE: Mutex<Option<Instant>> = Mutex::new(None);
+    /// downloads stopped on demand, their tasks end instead of asking more pieces
+    static ref PAUSED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    /// delayed tasks of the downloads, cancelled when they are paused
+    static ref RETRIES: Mutex<HashMap<String, Vec<TaskHandle>>> = Mutex::new(HashMap::new());
+}
+
+pub fn set_state_dir(dir: String) {
+    let mut state_dir = STATE_DIR.lock().unwrap();
+    *state_dir = Some(dir);
+}
+
+/// Returns the state directory, given on the command line or by `state-dir` in the Peer section.
+pub fn get_state_dir() -> PathBuf {
+    if let Some(dir) = STATE_DIR.lock().unwrap().clone() {
+        return PathBuf::from(dir);
+    }
+    // tests must not leave a state next to the sources
+    if cfg!(test) {
+        return std::env::temp_dir().join(format!("peer-state-{}", std::process::id()));
+    }
+    let dir = Ini::load_from_file(get_config_path())
+        .ok()
+        .and_then(|conf| {
+            conf.section(Some("Peer"))
+                .and_then(|section| section.get("state-dir"))
+                .map(|dir| dir.to_string())
+        })
+        .unwrap_or_else(|| "state".to_string());
+    PathBuf::from(dir)
+}
+
+/// Adds a file to the saved state.
+///
+/// # Arguments
+/// * `file` - The file we seed or download.
+/// * `written` - The pieces of the file already on disk.
+pub fn track_file(file: &MetaFile, written: Bitfield) {
+    let mut tracked = TRACKED.lock().unwrap();
+    tracked.insert(file.hash.clone(), (file.clone(), written));
+}
+
+/// Removes a file from the saved state, its pieces stay on disk.
+pub fn untrack_file(key: &str) -> bool {
+    let mut tracked = TRACKED.lock().unwrap();
+    tracked.remove(key).is_some()
+}
+
+/// Lists the tracked files with the pieces written on disk.
+pub fn get_tracked() -> Vec<(MetaFile, Bitfield)> {
+    let tracked = TRACKED.lock().unwrap();
+    tracked.values().cloned().collect()
+}
+
+/// Stops the download of a file until `unpause_download` is called.
+pub fn pause_download(key: &str) {
+    let mut paused = PAUSED.lock().unwrap();
+    paused.insert(key.to_string());
+    if let Some(handles) = RETRIES.lock().unwrap().remove(key) {
+        for handle in handles {
+            handle.cancel();
+        }
+    }
+}
+
+/// Remembers a delayed task of a download, so pausing it does not leave it waiting.
+pub fn add_retry(key: &str, handle: TaskHandle) {
+    let mut retries = RETRIES.lock().unwrap();
+    let handles = retries.entry(key.to_string()).or_default();
+    handles.retain(TaskHandle::is_pending);
+    handles.push(handle);
+}
+
+pub fn unpause_download(key: &str) {
+    let mut paused = PAUSED.lock().unwrap();
+    paused.remove(key);
+}
+
+pub fn is_paused(key: &str) -> bool {
+    let paused = PAUSED.lock().unwrap();
+    paused.contains(key)
+}
+
+/// Records that a piece has been written to disk.
+///
+/// # Arguments
+/// * `key` - The key of the file.
+/// * `index` - The index of the piece.
+///
+/// # Returns
+/// * `bool` - true if this was the last missing piece of the file,
+///   false if it was already written.
+pub fn mark_written(key: &str, index: usize) -> bool {
+    let mut tracked = TRACKED.lock().unwrap();
+    match tracked.get_mut(key) {
+        Some((_, written)) if index < written.len() && !written.get(index) => {
+            written.set(index, true);
+            written.is_full()
+        }
+        _ => false,
+    }
+}
+
+/// Records that pieces are no longer valid on disk, so they are downloaded again.
+///
+/// # Arguments
+/// * `key` - The key of the file.
+/// * `pieces` - The indexes of the pieces.
+pub fn forget_written(key: &str, pieces: &[usize]) {
+    let mut tracked = TRACKED.lock().unwrap();
+    if let Some((_, written)) = tracked.get_mut(key) {
+        let len = written.len();
+        for &index in pieces.iter().filter(|&&index| index < len) {
+            written.set(index, false);
+        }
+    }
+}
+
+/// Returns the pieces of a tracked file that are written on disk.
+pub fn get_written(key: &str) -> Option<Bitfield> {
+    let tracked = TRACKED.lock().unwrap();
+    tracked.get(key).map(|(_, written)| written.clone())
+}
+
+/// Writes the tracked files to the state directory.
+///
+/// The store is written next to the previous one then renamed over it,
+/// so a crash while saving leaves the last complete state.
+///
+/// # Returns
+/// * `io::Result<()>` - An error if the directory or the file could not be written.
+pub fn save_state() -> io::Result<()> {
+    let mut conf = Ini::new();
+    {
+        let tracked = TRACKED.lock().unwrap();
+        for (key, (file, written)) in tracked.iter() {
+            conf.with_section(Some(key.as_str()))
+                .set("file-name", file.file_name.as_str())
+                .set("length", file.length.to_string())
+                .set("piece-size", file.piece_size.to_string())
+                .set("buffermap", written.to_string());
+        }
+    }
+    let dir = get_state_dir();
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(STATE_FILE);
+    let tmp = dir.join(format!("{}.tmp", STATE_FILE));
+    conf.write_to_file(&tmp)?;
+    fs::rename(&tmp, &path)?;
+    *LAST_SAVE.lock().unwrap() = Some(Instant::now());
+    debug!("State saved to {}", path.display());
+    Ok(())
+}
+
+/// Saves the state unless it was saved less than `SAVE_PERIOD` ago.
+pub fn save_state_soon() {
+    let recent = match *LAST_SAVE.lock().unwrap() {
+        Some(last) => last.elapsed() < SAVE_PERIOD,
+        None => false,
+    };
+    if !recent {
+        if let Err(e) = save_state() {
+            error!("Could not save state : {}", e);
+        }
+    }
+}
+
+/// Reloads the files saved by a previous run into the database.
+///
+/// A file that is no longer on disk is reloaded with an empty buffermap,
+/// so it is downloaded again instead of being announced as seeded.
+///
+/// # Returns
+/// * `Vec<MetaFile>` - The reloaded files.
+pub fn load_state() -> Vec<MetaFile> {
+    let me = PeerConfig::new();
+    let mut files: Vec<MetaFile> = Vec::new();
+    for (file, written) in read_state() {
+        info!(
+            "Restored {} ({}/{} pieces)",
+            file.file_name,
+            written.count_ones(),
+            written.len()
+        );
+        track_file(&file, written.clone());
+        set_peer_to_file(me.clone(), file.clone(), written);
+        files.push(file);
+    }
+    files
+}
+
+// reads the store without touching the database
+fn read_state() -> Vec<(MetaFile, Bitfield)> {
+    let path = get_state_dir().join(STATE_FILE);
+    if !path.exists() {
+        debug!("No state in {}", path.display());
+        return Vec::new();
+    }
+    let conf = match Ini::load_from_file(&path) {
+        Ok(conf) => conf,
+        Err(e) => {
+            error!("Could not read state {} : {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (key, section) in conf.iter() {
+        let Some(key) = key else { continue };
+        let (mut file, mut written) = match parse_entry(key, section) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping {} in {} : {}", key, path.display(), e);
+                continue;
+            }
+        };
+        if !Path::new(&file.file_name).exists() {
+            warn!("{} is gone, it will be downloaded again", file.file_name);
+            written = Bitfield::new(written.len());
+        } else if written.is_full() {
+            // digests are not saved, the ones of a partial file are asked again to peers
+            match get_piece_digests(&file.file_name, file.piece_size, written.len()) {
+                Ok(digests) => file.piece_digests = digests,
+                Err(e) => warn!("Could not hash {} : {}", file.file_name, e),
+            }
+        }
+        entries.push((file, written));
+    }
+    entries
+}
+
+fn parse_entry(key: &str, section: &ini::Properties) -> Result<(MetaFile, Bitfield), String> {
+    let get = |name: &str| section.get(name).ok_or(format!("missing {}", name));
+    let number = |name: &str| -> Result<usize, String> {
+        get(name)?
+            .parse()
+            .map_err(|_| format!("{} is not a number", name))
+    };
+    let file = MetaFile {
+        file_name: get("file-name")?.to_string(),
+        length: number("length")?,
+        piece_size: number("piece-size")?,
+        hash: key.to_string(),
+        piece_digests: Vec::new(),
+    };
+    if file.piece_size == 0 {
+        return Err("piece-size is 0".to_string());
+    }
+    let written: Bitfield = get("buffermap")?.parse()?;
+    if written.len() != get_buffer_size(&file) {
+        return Err(format!(
+            "buffermap has {} pieces instead of {}",
+            written.len(),
+            get_buffer_size(&file)
+        ));
+    }
+    Ok((file, written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_state() {
+        let dir = get_state_dir();
+
+        let file = MetaFile {
+            file_name: "Cargo.toml".to_string(),
+            length: 3000,
+            piece_size: 1024,
+            hash: "0123456789abcdef0123456789abcdef".to_string(),
+            piece_digests: Vec::new(),
+        };
+        let gone = MetaFile {
+            file_name: "no_such_file.dat".to_string(),
+            length: 10,
+            piece_size: 1024,
+            hash: "fedcba9876543210fedcba9876543210".to_string(),
+            piece_digests: Vec::new(),
+        };
+        track_file(&file, Bitfield::new(3));
+        track_file(&gone, Bitfield::full(1));
+        assert!(!mark_written(&file.hash, 0));
+        assert!(!mark_written(&file.hash, 7));
+        save_state().unwrap();
+
+        let mut entries = read_state();
+        entries.sort_by(|a, b| a.0.hash.cmp(&b.0.hash));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, file);
+        assert_eq!(entries[0].1.ones().collect::<Vec<usize>>(), vec![0]);
+        // the file is not on disk anymore, its pieces are lost
+        assert_eq!(entries[1].0, gone);
+        assert_eq!(entries[1].1, Bitfield::new(1));
+
+        assert!(!mark_written(&file.hash, 1));
+        assert!(mark_written(&file.hash, 2));
+        assert!(!mark_written(&file.hash, 2));
+        forget_written(&file.hash, &[1, 9]);
+        assert_eq!(get_written(&file.hash).unwrap().count_ones(), 2);
+        assert!(mark_written(&file.hash, 1));
+        assert!(untrack_file(&gone.hash));
+        assert!(!untrack_file(&gone.hash));
+        // other tests keep files in the same directory
+        fs::remove_file(dir.join(STATE_FILE)).unwrap();
+    }
+
+    #[test]
+    fn test_pause() {
+        let key = "00112233445566778899aabbccddeeff";
+        assert!(!is_paused(key));
+        let retry = TaskHandle::default();
+        add_retry(key, retry.clone());
+        pause_download(key);
+        assert!(is_paused(key));
+        assert!(!retry.is_pending());
+        unpause_download(key);
+        assert!(!is_paused(key));
+    }
+
+    #[test]
+    fn test_parse_entry_errors() {
+        let mut conf = Ini::new();
+        conf.with_section(Some("a"))
+            .set("file-name", "a.dat")
+            .set("length", "10")
+            .set("piece-size", "1024");
+        conf.with_section(Some("b"))
+            .set("file-name", "b.dat")
+            .set("length", "5000")
+            .set("piece-size", "1024")
+            .set("buffermap", "2:wA==");
+        conf.with_section(Some("c"))
+            .set("file-name", "c.dat")
+            .set("length", "ten")
+            .set("piece-size", "1024")
+            .set("buffermap", "1:gA==");
+        for key in ["a", "b", "c"] {
+            assert!(parse_entry(key, conf.section(Some(key)).unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! private swarms, shared only by the peers knowing a secret
+//!
+//! A file whose key has a secret, in the Secrets section or through the
+//! `swarm-secret` of the Peer section, is only served to the peers proving
+//! they know it. The downloader sends `auth $Key`, the uploader answers a
+//! `challenge` with a random nonce and admits the connection to the file if
+//! the `proof` is the HMAC-SHA256 of the nonce, the key and keying material
+//! exported from the TLS session, keyed by the secret. The exported material
+//! is only known to the two ends of that connection, so a proof cannot be
+//! relayed to another one. Private files are therefore only shared over TLS.
+use crate::com::{receive_message, send};
+use crate::data::get_config_path;
+use crate::protocol::Message;
+use crate::tasks::Session;
+use crate::transport::Transport;
+use hashbrown::HashMap;
+use hmac::{Hmac, Mac};
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::debug;
+use sha2::Sha256;
+use std::error::Error;
+use std::sync::Mutex;
+
+/// Bytes of randomness of a challenge
+const NONCE_LENGTH: usize = 16;
+
+#[derive(Default)]
+struct Secrets {
+    global: Option<String>,
+    files: HashMap<String, String>,
+}
+
+lazy_static! {
+    static ref SECRETS: Mutex<Secrets> = Mutex::new(load_secrets());
+}
+
+// `swarm-secret` of the Peer section, and the Secrets section by file key
+fn load_secrets() -> Secrets {
+    let mut secrets = Secrets::default();
+    let Ok(conf) = Ini::load_from_file(get_config_path()) else {
+        return secrets;
+    };
+    secrets.global = conf
+        .section(Some("Peer"))
+        .and_then(|section| section.get("swarm-secret"))
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| secret.to_string());
+    if let Some(section) = conf.section(Some("Secrets")) {
+        for (key, secret) in section.iter() {
+            secrets.files.insert(key.to_string(), secret.to_string());
+        }
+    }
+    secrets
+}
+
+// the secret of a file, its own one before the global one
+fn secret_of(key: &str) -> Option<String> {
+    let secrets = SECRETS.lock().unwrap();
+    secrets.files.get(key).or(secrets.global.as_ref()).cloned()
+}
+
+fn mac(secret: &str, nonce: &str, key: &str, binding: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    for part in [nonce, key] {
+        mac.update(part.as_bytes());
+        mac.update(b" ");
+    }
+    mac.update(binding);
+    mac
+}
+
+/// Tells if a file is only served to the peers knowing its secret.
+pub fn is_private(key: &str) -> bool {
+    secret_of(key).is_some()
+}
+
+/// Tells if the peer of a connection may ask for the pieces of a file.
+///
+/// # Arguments
+/// * `session` - The state of the connection.
+/// * `key` - The key of the file.
+pub fn is_admitted(session: &Session, key: &str) -> bool {
+    session.admitted.contains(key) || !is_private(key)
+}
+
+/// Proves to the peer of a connection we opened that we know the secret of a file.
+///
+/// Nothing is sent for a file without a secret. A private file is never asked in plaintext.
+///
+/// # Arguments
+/// * `stream` - The connection, after the handshake.
+/// * `key` - The key of the file.
+///
+/// # Returns
+/// * `Result<(), Box<dyn Error>>` - Ok once the peer admitted us, or why it did not.
+pub fn prove(stream: &mut dyn Transport, key: &str) -> Result<(), Box<dyn Error>> {
+    let Some(secret) = secret_of(key) else {
+        return Ok(());
+    };
+    let Some(binding) = stream.channel_binding() else {
+        return Err(format!("{} is private, it is only shared over TLS", key).into());
+    };
+    send(
+        stream,
+        &Message::Auth {
+            key: key.to_string(),
+        },
+    );
+    match receive_message(stream, 3000)? {
+        Message::Challenge { key: asked, nonce } if asked == key => {
+            let hmac: String = hex::encode(
+                mac(&secret, &nonce, key, &binding)
+                    .finalize()
+                    .into_bytes(),
+            );
+            send(stream, &Message::Proof { key: asked, hmac });
+        }
+        // the file is not private for the peer
+        Message::Ok => return Ok(()),
+        other => return Err(format!("expected a challenge, got {}", other).into()),
+    }
+    match receive_message(stream, 3000)? {
+        Message::Ok => Ok(()),
+        Message::Error { reason } => Err(reason.into()),
+        other => Err(format!("expected ok, got {}", other).into()),
+    }
+}
+
+/// Challenges the peer of an incoming connection asking for a file, and answers ok if it passes.
+///
+/// A file without a secret is answered ok at once, a private one is refused in plaintext.
+/// On failure an error is sent back and the connection should be dropped.
+///
+/// # Arguments
+/// * `stream` - The connection the `auth` came from.
+/// * `key` - The key of the file.
+///
+/// # Returns
+/// * `Result<(), Box<dyn Error>>` - Ok if the peer may have the file, or why it may not.
+pub fn challenge(stream: &mut dyn Transport, key: &str) -> Result<(), Box<dyn Error>> {
+    let Some(secret) = secret_of(key) else {
+        send(stream, &Message::Ok);
+        return Ok(());
+    };
+    let Some(binding) = stream.channel_binding() else {
+        let refusal = Message::Error {
+            reason: format!("{} is only shared over TLS", key),
+        };
+        send(stream, &refusal);
+        return Err(format!("{} asked in plaintext", key).into());
+    };
+    let mut random = [0u8; NONCE_LENGTH];
+    getrandom::getrandom(&mut random)?;
+    let nonce: String = hex::encode(random);
+    send(
+        stream,
+        &Message::Challenge {
+            key: key.to_string(),
+            nonce: nonce.clone(),
+        },
+    );
+    let passed: bool = match receive_message(stream, 3000)? {
+        Message::Proof { key: proved, hmac } if proved == key => {
+            hex::decode(hmac).is_ok_and(|hmac| {
+                mac(&secret, &nonce, key, &binding)
+                    .verify_slice(&hmac)
+                    .is_ok()
+            })
+        }
+        _ => false,
+    };
+    if !passed {
+        let refusal = Message::Error {
+            reason: format!("wrong proof for {}", key),
+        };
+        send(stream, &refusal);
+        return Err(format!("wrong proof for {}", key).into());
+    }
+    debug!("Admitted to the swarm of {}", key);
+    send(stream, &Message::Ok);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{secure_client, secure_server, TlsStream};
+    use crate::transport::duplex;
+    use std::thread;
+
+    fn set_secret(key: &str, secret: &str) {
+        SECRETS
+            .lock()
+            .unwrap()
+            .files
+            .insert(key.to_string(), secret.to_string());
+    }
+
+    // both ends of an in-memory connection in TLS
+    fn tls_pair() -> (TlsStream, TlsStream) {
+        let (client, server) = duplex(
+            "127.0.0.1:40000".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+        );
+        let server = thread::spawn(move || secure_server(Box::new(server)).unwrap());
+        let client = secure_client(Box::new(client), "127.0.0.1").unwrap();
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_challenge() {
+        let key = "swarmtestkey";
+        set_secret(key, "our team secret");
+        let (mut client, mut server) = tls_pair();
+        assert!(client.channel_binding().is_some());
+        assert_eq!(client.channel_binding(), server.channel_binding());
+        let uploader = thread::spawn(move || {
+            assert_eq!(
+                receive_message(&mut server, 3000).unwrap(),
+                Message::Auth {
+                    key: key.to_string()
+                }
+            );
+            challenge(&mut server, key).is_ok()
+        });
+        assert!(prove(&mut client, key).is_ok());
+        assert!(uploader.join().unwrap());
+
+        // a proof relayed from another connection, or made with another secret, is refused
+        for (secret, relayed) in [("our team secret", true), ("guessed", false)] {
+            let (mut client, mut server) = tls_pair();
+            let (other, _) = tls_pair();
+            let uploader = thread::spawn(move || challenge(&mut server, key).is_ok());
+            let Message::Challenge { nonce, .. } = receive_message(&mut client, 3000).unwrap()
+            else {
+                panic!("expected a challenge");
+            };
+            let binding = if relayed {
+                other.channel_binding().unwrap()
+            } else {
+                client.channel_binding().unwrap()
+            };
+            let hmac = hex::encode(mac(secret, &nonce, key, &binding).finalize().into_bytes());
+            send(
+                &mut client,
+                &Message::Proof {
+                    key: key.to_string(),
+                    hmac,
+                },
+            );
+            assert!(matches!(
+                receive_message(&mut client, 3000),
+                Ok(Message::Error { .. })
+            ));
+            assert!(!uploader.join().unwrap());
+        }
+
+        // a private file is not shared in plaintext
+        let (mut client, mut server) = duplex(
+            "127.0.0.1:40000".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+        );
+        assert!(prove(&mut client, key).is_err());
+        assert!(challenge(&mut server, key).is_err());
+        assert!(matches!(
+            receive_message(&mut client, 3000),
+            Ok(Message::Error { .. })
+        ));
+
+        // public files need no proof
+        assert!(is_admitted(&Session::default(), "swarmtestpublic"));
+        assert!(!is_admitted(&Session::default(), key));
+    }
+}
@@ -1,8 +1,24 @@
-use std::net::TcpStream;
+use crate::bitfield::Bitfield;
 use crate::data::PeerConfig;
 use std::sync::{Arc, Mutex};
 use std::collections::VecDeque;
+use crate::peerstats::Chain;
+use crate::protocol::Framing;
 use crate::threads::Pool;
+use crate::transport::Transport;
+use hashbrown::HashSet;
+
+/// state negotiated on a connection, handed from task to task with its stream
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub framing: Framing,
+    /// identifier the remote peer sent in its hello
+    pub peer_id: Option<String>,
+    /// address and listening port of the remote peer
+    pub peer: Option<PeerConfig>,
+    /// private files the remote peer proved it knows the secret of
+    pub admitted: HashSet<String>,
+}
 
 /// task struct, which is the parent class
 pub trait Task: Send {
@@ -11,14 +27,14 @@ pub trait Task: Send {
 
 /// empty task
 pub struct EmptyTask {
-    pub stream: Option<TcpStream>,
+    pub stream: Option<Box<dyn Transport>>,
 }
 
 /// To be processed task, to get listener free
 pub struct ToBeProcessed {
     //pub tasklist: Arc<Mutex<VecDeque<Box<dyn Task + Send>>>>,
     pub pool: Pool,
-    pub stream: TcpStream,
+    pub stream: Option<Box<dyn Transport>>,
 }
 
 /// Receieved via TCP getpieces and return a data request to be send
@@ -26,22 +42,55 @@ pub struct Getpieces {
     pub key: String,
     pub chunk_size: usize,
     pub pieces: Vec<usize>,
-    pub stream: Option<TcpStream>,
+    pub stream: Option<Box<dyn Transport>>,
     pub pool: Pool,
     pub retry: usize,
+    pub session: Session,
+}
+
+/// Receieved via TCP framing, switch the connection framing and wait for the next request
+pub struct Negotiate {
+    pub framing: Framing,
+    pub stream: Option<Box<dyn Transport>>,
+    pub session: Session,
+    pub pool: Pool,
+}
+
+/// Receieved via TCP auth, challenge the peer for a private file and wait for the next request
+pub struct Auth {
+    pub key: String,
+    pub stream: Option<Box<dyn Transport>>,
+    pub session: Session,
+    pub pool: Pool,
 }
 
 /// Receieved via TCP interested and return a have request to be send
 pub struct Interested {
     pub key: String,
-    pub stream: Option<TcpStream>,
+    pub stream: Option<Box<dyn Transport>>,
+}
+
+/// Receieved via TCP choke or unchoke, records whether the sender will upload to us
+pub struct Choke {
+    pub key: String,
+    pub choked: bool,
+    pub session: Session,
+    pub pool: Pool,
+}
+
+/// Receieved via TCP getdigests and return a digests answer to be send
+pub struct Getdigests {
+    pub key: String,
+    pub stream: Option<Box<dyn Transport>>,
 }
 
 /// Receieved via TCP have and return a interested request to be send
 pub struct Have {
     pub key: String,
-    pub buffermap: Vec<u8>,
-    pub stream: Option<TcpStream>,
+    pub buffermap: Bitfield,
+    pub stream: Option<Box<dyn Transport>>,
+    pub session: Session,
+    pub pool: Pool,
 }
 
 impl Clone for Have {
@@ -50,14 +99,32 @@ impl Clone for Have {
             key: self.key.clone(),
             buffermap: self.buffermap.clone(),
             stream: None,
+            session: self.session.clone(),
+            pool: self.pool.clone(),
         }
     }
 }
 
+/// Receieved via TCP havepiece, records one more piece of the sender
+pub struct HavePiece {
+    pub key: String,
+    pub index: usize,
+    pub stream: Option<Box<dyn Transport>>,
+    pub session: Session,
+    pub pool: Pool,
+}
+
+/// Waits for the next have or havepiece on the connection a peer announces its pieces on
+pub struct HaveLink {
+    pub stream: Option<Box<dyn Transport>>,
+    pub session: Session,
+    pub pool: Pool,
+}
+
 pub struct Data {
     pub key: String,
     pub pieces: Vec<(usize, Vec<u8>)>,
-    pub stream: Option<TcpStream>,
+    pub stream: Option<Box<dyn Transport>>,
 }
 /// send a get_piece, recieve the data and write it
 pub struct DataWrite {
@@ -65,7 +132,12 @@ pub struct DataWrite {
     pub file_key: String,
     pub nb_pieces: usize,
     pub pool: Pool, 
-    pub stream: Option<TcpStream>,
+    pub stream: Option<Box<dyn Transport>>,
+    pub session: Session,
+    /// times in a row the peer had nothing to give us
+    pub retry: usize,
+    /// counts this chain of requests while it runs
+    pub chain: Option<Chain>,
 }
 
 impl Clone for DataWrite {
@@ -76,6 +148,9 @@ impl Clone for DataWrite {
             nb_pieces: self.nb_pieces,
             pool: self.pool.clone(),
             stream: None,
+            session: Session::default(),
+            retry: self.retry,
+            chain: None,
         }
     }
 }
@@ -1,32 +1,176 @@
-use crate::back::store_have_to_db;
-use crate::com::{connect, havef, receive, send, updatef};
-use crate::data::{MetaFile, PeerConfig, TrackerConfig};
-use crate::db::{get_buffermap, get_leeching_files, get_peers_from_file};
-use crate::parser::{parse_have_from_have, parse_request};
+use crate::back::{accept_peer, connect_peer};
+use crate::choke::{rechoke, CHOKE_PERIOD};
+use crate::com::{receive_message, send, updatef};
+use crate::data::{PeerConfig, TrackerConfig};
+use crate::db::get_leeching_files;
+use crate::events::subscribe;
+use crate::havelinks::{written_pieces, Links};
+use crate::lan::Lan;
+use crate::parser::parse_request;
+use crate::peerstats::get_stats;
 use crate::tasks::Task;
-use crate::tasks::{EmptyTask, Have, ToBeProcessed};
+use crate::tasks::{EmptyTask, Session, ToBeProcessed};
+use crate::tls::{accept, open, Remote};
+use crate::transport::Transport;
 use log::{debug, error, info, trace, warn};
 use rayon::prelude::*;
-use std::collections::VecDeque;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, VecDeque};
 use std::io::{BufRead, BufReader};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use std::{fmt, thread};
+use socket2::{Domain, Protocol, Socket, Type};
 
 // for UPnP
 use easy_upnp::{add_ports, delete_ports, Ipv4Cidr, PortMappingProtocol, UpnpConfig};
 use std::error::Error;
 
-// gloval var, used to stop threads
-static mut RUNNING: bool = true;
-
 // clean exit, idk how to do it
 static mut PORT: u16 = 0;
 
+/// Stop flag shared by the pool threads, the ones sleeping on it wake up when it is set
+#[derive(Default)]
+pub struct Shutdown {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Shutdown {
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+
+    /// Sleeps for `period`, or less if the pool stops meanwhile.
+    ///
+    /// # Returns
+    /// * `bool` - true if the pool is stopping.
+    pub fn sleep(&self, period: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .wake
+            .wait_timeout_while(stopped, period, |stopped| !*stopped)
+            .unwrap();
+        *stopped
+    }
+}
+
+// states of a delayed task
+const PENDING: u8 = 0;
+const QUEUED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Lets the owner of a delayed task cancel it before it runs
+#[derive(Debug, Clone, Default)]
+pub struct TaskHandle {
+    state: Arc<AtomicU8>,
+}
+
+impl TaskHandle {
+    /// Cancels the task if it is still waiting.
+    ///
+    /// # Returns
+    /// * `bool` - false if the task was already queued.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .compare_exchange(PENDING, CANCELLED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Tells if the task is still waiting for its time.
+    pub fn is_pending(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == PENDING
+    }
+
+    // true if the task was not cancelled first
+    fn queue(&self) -> bool {
+        self.state
+            .compare_exchange(PENDING, QUEUED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+/// Exponential backoff, `initial` doubled at each retry up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub retries: usize,
+}
+
+impl Backoff {
+    /// Returns how long to wait before a retry.
+    ///
+    /// # Arguments
+    /// * `attempt` - The number of retries already done.
+    ///
+    /// # Returns
+    /// * `Option<Duration>` - The delay, None once every retry is used.
+    pub fn delay(&self, attempt: usize) -> Option<Duration> {
+        if attempt >= self.retries {
+            return None;
+        }
+        let factor: u32 = 1u32.checked_shl(attempt as u32).unwrap_or(u32::MAX);
+        Some(self.initial.saturating_mul(factor).min(self.max))
+    }
+}
+
+/// a task waiting for its time
+struct Delayed {
+    due: Instant,
+    task: Box<dyn Task + Send>,
+    handle: TaskHandle,
+}
+
+// ordered so the BinaryHeap pops the earliest first
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.due.cmp(&self.due)
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due
+    }
+}
+
+impl Eq for Delayed {}
+
+/// delayed tasks, moved to the task list by the timer thread when due
+#[derive(Default)]
+struct Timers {
+    queue: Mutex<BinaryHeap<Delayed>>,
+    // a task was delayed or the pool is stopping
+    changed: Condvar,
+}
+
 //pool struct
 pub struct Pool {
     tasklist: Arc<Mutex<VecDeque<Box<dyn Task + Send>>>>,
+    // a task was added or the pool is stopping
+    ready: Arc<Condvar>,
+    // the last task was taken
+    drained: Arc<Condvar>,
+    shutdown: Arc<Shutdown>,
+    timers: Arc<Timers>,
+    // where the listening thread waits for connections
+    listening: Arc<Mutex<Option<SocketAddr>>>,
     thread_pool: Arc<Mutex<VecDeque<std::thread::JoinHandle<i32>>>>,
     size: usize,
 }
@@ -35,6 +179,11 @@ impl Clone for Pool {
     fn clone(&self) -> Self {
         Pool {
             tasklist: self.tasklist.clone(),
+            ready: self.ready.clone(),
+            drained: self.drained.clone(),
+            shutdown: self.shutdown.clone(),
+            timers: self.timers.clone(),
+            listening: self.listening.clone(),
             thread_pool: self.thread_pool.clone(),
             size: self.size,
         }
@@ -53,11 +202,16 @@ impl Pool {
         let thread_pool = Arc::new(Mutex::new(VecDeque::new()));
         let tasklist: Arc<Mutex<VecDeque<Box<dyn Task + Send>>>> =
             Arc::new(Mutex::new(VecDeque::new()));
+        let ready: Arc<Condvar> = Arc::new(Condvar::new());
+        let drained: Arc<Condvar> = Arc::new(Condvar::new());
+        let shutdown: Arc<Shutdown> = Arc::new(Shutdown::default());
 
         (0..size).into_par_iter().for_each(|i| {
             let clone = Arc::clone(&tasklist);
+            let ready = Arc::clone(&ready);
+            let drained = Arc::clone(&drained);
+            let shutdown = Arc::clone(&shutdown);
             let handle = thread::Builder::new()
-            //let handle = thread::spawn(move || {
             .name(i.to_string())
             .spawn(move || {
                 let res: i32 = 0;
@@ -65,32 +219,28 @@ impl Pool {
 
                 debug!("thread {} started", id);
 
-                unsafe {
-                    let mut option: Option<Box<dyn Task + Send>>;
-                    let mut len: usize;
-                    while RUNNING {
-                        {
-                            let mut data = clone.lock().unwrap();
-                            len = data.len();
-                            if len > 0 {
-                                option = data.pop_front();
-                            } else {
-                                option = None;
-                            }
+                loop {
+                    let mut task: Box<dyn Task + Send> = {
+                        let mut data = clone.lock().unwrap();
+                        // sleep until there is something to do
+                        while data.is_empty() && !shutdown.is_stopped() {
+                            data = ready.wait(data).unwrap();
                         }
-                        if len > 0 {
-                            trace!("Thread {} is processing a task", id);
-                            match option {
-                                Some(mut task) => task.process(),
-                                None => {}
-                            }
-                            trace!("Thread {} has finished processing a task", id);
-                        } else {
-                            thread::sleep(Duration::from_millis(10));
+                        if shutdown.is_stopped() {
+                            break;
                         }
-                    }
+                        let task = data.pop_front().unwrap();
+                        if data.is_empty() {
+                            drained.notify_all();
+                        }
+                        task
+                    };
+                    trace!("Thread {} is processing a task", id);
+                    task.process();
+                    trace!("Thread {} has finished processing a task", id);
                 }
 
+                debug!("thread {} stopped", id);
                 res
             }).unwrap();
             {
@@ -98,10 +248,52 @@ impl Pool {
             }
         });
 
-        Pool {
+        let mut pool = Pool {
             tasklist,
+            ready,
+            drained,
+            shutdown,
+            timers: Arc::new(Timers::default()),
+            listening: Arc::new(Mutex::new(None)),
             thread_pool,
             size: size as usize,
+        };
+        // without workers delayed tasks would never run anyway
+        if size > 0 {
+            pool.start_timer();
+        }
+        pool
+    }
+
+    /// start timer thread, queuing the delayed tasks when they are due
+    fn start_timer(&mut self) {
+        let pool: Pool = self.clone();
+        let timer = thread::spawn(move || {
+            loop {
+                let delayed: Delayed = {
+                    let mut queue = pool.timers.queue.lock().unwrap();
+                    loop {
+                        if pool.shutdown.is_stopped() {
+                            return 0;
+                        }
+                        let now = Instant::now();
+                        queue = match queue.peek().map(|delayed| delayed.due) {
+                            Some(due) if due <= now => break,
+                            Some(due) => pool.timers.changed.wait_timeout(queue, due - now).unwrap().0,
+                            None => pool.timers.changed.wait(queue).unwrap(),
+                        };
+                    }
+                    queue.pop().unwrap()
+                };
+                if !delayed.handle.queue() {
+                    trace!("Delayed task was cancelled");
+                    continue;
+                }
+                pool.clone().add_task(delayed.task);
+            }
+        });
+        {
+            self.thread_pool.lock().unwrap().push_front(timer);
         }
     }
 
@@ -111,9 +303,9 @@ impl Pool {
 
     pub fn start_listening(&mut self, pc: PeerConfig) {
         // listen to port
-        let add = format!("{}:{}", pc.address, pc.port);
-        debug!("Listening on {}", add);
-        let door = TcpListener::bind(add).unwrap();
+        debug!("Listening on {}", pc.addr);
+        let door = bind_listener(pc.addr).unwrap();
+        *self.listening.lock().unwrap() = door.local_addr().ok();
         let thread_pool_clone = self.thread_pool.clone();
 
         //let tasklist_clone = self.tasklist.clone();
@@ -123,27 +315,27 @@ impl Pool {
         let pool_clone: Pool = self.clone();
 
         let lithread = thread::spawn(move || {
-            while unsafe { RUNNING } {
-                for con in door.incoming() {
-                    match con {
-                        Ok(stream) => {
-                            debug!("incoming from {}", stream.peer_addr().unwrap());
-
-                            let stream = stream.try_clone().unwrap();
-
-                            let tbp: ToBeProcessed = ToBeProcessed {
-                                pool: pool_clone.clone(),
-                                stream,
-                            };
-                            {
-                                //tasklist_clone.lock().unwrap().push_front(Box::new(tbp));
-                                pool_clone.clone().add_task(Box::new(tbp));
-                            }
-                        }
-                        Err(e) => {
-                            error!("{}", e);
+            for con in door.incoming() {
+                // drop wakes us up with a connection of its own
+                if pool_clone.shutdown.is_stopped() {
+                    break;
+                }
+                match con {
+                    Ok(stream) => {
+                        debug!("incoming from {}", stream.peer_addr().unwrap());
+
+                        let tbp: ToBeProcessed = ToBeProcessed {
+                            pool: pool_clone.clone(),
+                            stream: Some(Box::new(stream)),
+                        };
+                        {
+                            //tasklist_clone.lock().unwrap().push_front(Box::new(tbp));
+                            pool_clone.clone().add_task(Box::new(tbp));
                         }
                     }
+                    Err(e) => {
+                        error!("{}", e);
+                    }
                 }
             }
             0
@@ -155,53 +347,31 @@ impl Pool {
     }
 
     /// start have thread
+    ///
+    /// The peers of the files we download get our buffermap once per connection,
+    /// then every piece we write as it comes. New peers are looked for every `period` seconds.
     pub fn start_have(&mut self, period: i32) {
+        let shutdown = self.shutdown.clone();
+        let written = written_pieces();
         let havethread = thread::spawn(move || {
-            unsafe {
-                while RUNNING {
-                    // send them a have request
-
-                    let main_config: PeerConfig = PeerConfig::new();
-                    let leeching_files: Vec<MetaFile> = get_leeching_files();
-
-                    // foreach leeching file
-                    leeching_files.par_iter().for_each(|file| {
-                        //for file in leeching_files {
-                        let peers = get_peers_from_file(file.hash.clone());
-                        let buffmap: Vec<u8> =
-                            get_buffermap(main_config.clone(), &file.hash.clone()).unwrap();
-
-                        // get list of peers
-                        for peer in peers {
-                            if peer.address.clone() == main_config.address.clone()
-                                && peer.port == main_config.port
-                            {
-                                continue;
-                            }
-                            let ip: String = peer.address.clone();
-                            let port: u16 = peer.port;
-                            let stream_option: Option<TcpStream> = connect(port, &ip);
-                            match stream_option {
-                                Some(mut stream) => {
-                                    let msg: String = havef(file.hash.clone(), buffmap.clone());
-                                    info!("Sending have to {}:{}", ip, port);
-                                    send(&mut stream, msg);
-                                    let answer: String = receive(&mut stream, 3000);
-
-                                    let have_option: Option<Have> = parse_have_from_have(answer);
-
-                                    // and update their buffermap
-                                    match have_option {
-                                        Some(have) => store_have_to_db(peer, have),
-                                        None => warn!("Received wrong have answer"),
-                                    }
-
-                                }
-                                None => warn!("Could not send have to {}:{}", ip, port),
-                            }
+            let period = Duration::from_secs(period as u64);
+            let mut links = Links::default();
+            let mut refreshed: Option<Instant> = None;
+            while !shutdown.is_stopped() {
+                if refreshed.is_none_or(|refreshed| refreshed.elapsed() >= period) {
+                    links.refresh(&get_leeching_files());
+                    refreshed = Some(Instant::now());
+                }
+                match written.recv_timeout(Duration::from_secs(1)) {
+                    Ok((key, index)) => {
+                        links.announce(&key, index);
+                        // the pieces written meanwhile go out together
+                        for (key, index) in written.try_iter() {
+                            links.announce(&key, index);
                         }
-                    });
-                    thread::sleep(Duration::from_secs(period as u64));
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
             }
             0
@@ -211,18 +381,46 @@ impl Pool {
         }
     }
 
+    /// start LAN discovery thread
+    ///
+    /// Our files are announced to the peers of the subnet every `period` seconds,
+    /// and the peers they announce are added to the files. Nothing starts if discovery is off.
+    pub fn start_lan(&mut self, period: i32) {
+        let Some(mut lan) = Lan::open() else {
+            return;
+        };
+        let shutdown = self.shutdown.clone();
+        let lanthread = thread::spawn(move || {
+            let period = Duration::from_secs(period as u64);
+            let mut announced: Option<Instant> = None;
+            while !shutdown.is_stopped() {
+                if announced.is_none_or(|announced| announced.elapsed() >= period) {
+                    lan.announce();
+                    announced = Some(Instant::now());
+                }
+                // a new peer learns about our files without waiting for the period
+                if lan.receive() {
+                    announced = None;
+                }
+            }
+            0
+        });
+        {
+            self.thread_pool.lock().unwrap().push_front(lanthread);
+        }
+    }
+
     /// start update thread
     pub fn start_update(&mut self, tc: TrackerConfig, period: i32) {
+        let shutdown = self.shutdown.clone();
         let upthread = thread::spawn(move || {
-            unsafe {
-                while RUNNING {
-                    let msg: String = updatef();
-                    if let Some(mut stream) = connect(tc.port, tc.address.as_str()) {
-                        info!("Sending update to tracker");
-                        send(&mut stream, msg);
-                    } 
-                    thread::sleep(Duration::from_secs(period as u64));
-                }
+            while !shutdown.is_stopped() {
+                let msg = updatef();
+                if let Some(mut stream) = open(Remote::Tracker, tc.port, tc.address.as_str()) {
+                    info!("Sending update to tracker");
+                    send(&mut stream, &msg);
+                } 
+                shutdown.sleep(Duration::from_secs(period as u64));
             }
             0
         });
@@ -231,24 +429,115 @@ impl Pool {
         }
     }
 
+    /// start choke thread, giving the upload slots to the peers that upload to us
+    pub fn start_choker(&mut self) {
+        let shutdown = self.shutdown.clone();
+        let chokethread = thread::spawn(move || {
+            while !shutdown.sleep(CHOKE_PERIOD) {
+                for (peer, message) in rechoke(|peer_key| get_stats(peer_key).throughput) {
+                    match connect_peer(&peer) {
+                        Some((mut stream, _)) => {
+                            debug!("Sending {} to {}", message, peer.addr);
+                            send(&mut stream, &message);
+                        }
+                        None => warn!("Could not send {} to {}", message, peer.addr),
+                    }
+                }
+            }
+            0
+        });
+        {
+            self.thread_pool.lock().unwrap().push_front(chokethread);
+        }
+    }
+
+    /// start events thread, logging finished downloads
+    pub fn start_events(&mut self) {
+        let events = subscribe();
+        let shutdown = self.shutdown.clone();
+        let evthread = thread::spawn(move || {
+            while !shutdown.is_stopped() {
+                match events.recv_timeout(Duration::from_secs(1)) {
+                    Ok(event) => info!("{}", event),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            0
+        });
+        {
+            self.thread_pool.lock().unwrap().push_front(evthread);
+        }
+    }
+
     pub fn add_task(&mut self, task: Box<dyn Task + Send>) {
         // + 'static>) {
+        if self.shutdown.is_stopped() {
+            debug!("Pool is stopping, task dropped");
+            return;
+        }
         let mut data = self.tasklist.lock().unwrap();
         data.push_back(task);
+        self.ready.notify_one();
+    }
+
+    /// Queues a task once `delay` has passed, without holding a worker meanwhile.
+    ///
+    /// # Arguments
+    /// * `task` - The task to run.
+    /// * `delay` - How long to wait before queuing it.
+    ///
+    /// # Returns
+    /// * `TaskHandle` - To cancel the task before it runs.
+    pub fn add_delayed(&mut self, task: Box<dyn Task + Send>, delay: Duration) -> TaskHandle {
+        let handle = TaskHandle::default();
+        if self.shutdown.is_stopped() {
+            debug!("Pool is stopping, delayed task dropped");
+            return handle;
+        }
+        let mut queue = self.timers.queue.lock().unwrap();
+        queue.push(Delayed {
+            due: Instant::now() + delay,
+            task,
+            handle: handle.clone(),
+        });
+        self.timers.changed.notify_one();
+        handle
+    }
+
+    /// Runs a delayed task now instead of waiting for its time.
+    ///
+    /// # Returns
+    /// * `bool` - false if the task already ran or was cancelled.
+    pub fn wake(&mut self, handle: &TaskHandle) -> bool {
+        if !handle.is_pending() {
+            return false;
+        }
+        let mut queue = self.timers.queue.lock().unwrap();
+        let mut delayed: Vec<Delayed> = std::mem::take(&mut *queue).into_vec();
+        let now = Instant::now();
+        let mut found: bool = false;
+        for task in delayed.iter_mut() {
+            if Arc::ptr_eq(&task.handle.state, &handle.state) {
+                task.due = now;
+                found = true;
+            }
+        }
+        *queue = BinaryHeap::from(delayed);
+        self.timers.changed.notify_one();
+        found
     }
 
     //join threads (wait for them to die)
     fn join(self) {
-        let mut len: usize = 1;
-        let mut thread: std::thread::JoinHandle<i32>;
-
-        while len > 0 {
-            {
-                let mut data = self.thread_pool.lock().unwrap();
-                thread = data.pop_front().unwrap();
-                len = data.len();
+        loop {
+            let thread = self.thread_pool.lock().unwrap().pop_front();
+            match thread {
+                Some(thread) => {
+                    thread.join().unwrap();
+                }
+                None => break,
             }
-            thread.join().unwrap();
         }
     }
 
@@ -256,21 +545,32 @@ impl Pool {
     pub fn drop(self) {
         debug!("Requested threads stop");
 
-        loop {
-            let len: usize;
-            {
-                let data = self.tasklist.lock().unwrap();
-                len = data.len();
-            }
-            if len > 0 {
-                thread::sleep(Duration::from_millis(100));
-            } else {
-                break;
+        if self.size > 0 {
+            let mut data = self.tasklist.lock().unwrap();
+            while !data.is_empty() {
+                data = self.drained.wait(data).unwrap();
             }
         }
 
-        unsafe {
-            RUNNING = false;
+        self.shutdown.stop();
+        {
+            // workers check the flag with the list locked, so none misses this
+            let _data = self.tasklist.lock().unwrap();
+            self.ready.notify_all();
+        }
+        {
+            // delayed tasks not due yet are dropped
+            let _queue = self.timers.queue.lock().unwrap();
+            self.timers.changed.notify_all();
+        }
+        // accept() only returns on a connection
+        if let Some(mut addr) = *self.listening.lock().unwrap() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            if let Err(e) = TcpStream::connect(addr) {
+                warn!("Could not wake up the listening thread : {}", e);
+            }
         }
         self.join();
         info!("All threads have been stopped");
@@ -278,8 +578,29 @@ impl Pool {
     }
 }
 
+/// Binds the listening socket, on IPv6 and IPv4 at once for the `::` address.
+///
+/// A host without IPv6 listens on every IPv4 address instead.
+fn bind_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    if addr.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return TcpListener::bind(addr);
+    }
+    let dual_stack = || -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    };
+    dual_stack().or_else(|e| {
+        warn!("Could not listen on IPv6 : {}, listening on IPv4 only", e);
+        TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port()))
+    })
+}
+
 /// used by listening thread
-pub fn handle_client(mut pool: Pool, mut stream: TcpStream) {
+pub fn handle_client(mut pool: Pool, stream: Box<dyn Transport>) {
     /*
     let mut reader = BufReader::new(&mut stream);
     let mut buff: Vec<u8> = Vec::new();
@@ -292,9 +613,29 @@ pub fn handle_client(mut pool: Pool, mut stream: TcpStream) {
     let ip = peer.ip();
     let port = peer.port();
     info!("Incoming connection from {}:{}", ip, port);
-    let msg: String = receive(&mut stream, 3000);
-    debug!("Received msg {}", msg.chars().take(128).collect::<String>());
-    let task: Box<(dyn Task + Send + 'static)> = parse_request(msg, Some(stream), pool.clone());
+    let mut stream: Box<dyn Transport> = match accept(stream) {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Refused connection from {}:{} : {}", ip, port, e);
+            return;
+        }
+    };
+    let session: Session = match accept_peer(&mut stream) {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("Refused connection from {}:{} : {}", ip, port, e);
+            return;
+        }
+    };
+    let task: Box<dyn Task + Send + 'static> = match receive_message(&mut stream, 3000) {
+        Ok(msg) => parse_request(msg, Some(stream), session, pool.clone()),
+        Err(e) => {
+            error!("Could not handle request from {}:{} : {}", ip, port, e);
+            Box::new(EmptyTask {
+                stream: Some(stream),
+            })
+        }
+    };
     pool.add_task(task);
     /*} else {
         error!("Connection close by {:?}", stream.peer_addr());
@@ -357,6 +698,90 @@ mod tests {
         assert_eq!(len, 0);
         pool.drop();
     }
+
+    #[test]
+    fn test_drop_stops_every_thread() {
+        let mut pool: Pool = Pool::new(2);
+        pool.start_listening(PeerConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+        });
+        pool.start_events();
+        let (done, stopped) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            pool.drop();
+            done.send(()).unwrap();
+        });
+        assert!(stopped.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    struct Notify {
+        sender: std::sync::mpsc::Sender<u32>,
+        id: u32,
+    }
+
+    impl Task for Notify {
+        fn process(&mut self) {
+            self.sender.send(self.id).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_delayed_tasks() {
+        let mut pool: Pool = Pool::new(1);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        pool.add_delayed(Box::new(Notify { sender: sender.clone(), id: 2 }), Duration::from_millis(100));
+        let cancelled = pool.add_delayed(Box::new(Notify { sender: sender.clone(), id: 3 }), Duration::from_millis(50));
+        let first = pool.add_delayed(Box::new(Notify { sender, id: 1 }), Duration::from_millis(20));
+        assert!(cancelled.cancel());
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(1));
+        // too late, it ran already
+        assert!(!first.cancel());
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(2));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(!cancelled.is_pending());
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let later = pool.add_delayed(Box::new(Notify { sender, id: 4 }), Duration::from_secs(60));
+        assert!(pool.wake(&later));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(4));
+        assert!(!pool.wake(&later));
+        pool.drop();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+            retries: 4,
+        };
+        let delays: Vec<Option<Duration>> = (0..5).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_millis(10)),
+                Some(Duration::from_millis(20)),
+                Some(Duration::from_millis(40)),
+                Some(Duration::from_millis(50)),
+                None
+            ]
+        );
+        assert!(backoff.delay(usize::MAX).is_none());
+    }
+
+    #[test]
+    fn test_shutdown_wakes_sleepers() {
+        let shutdown: Arc<Shutdown> = Arc::new(Shutdown::default());
+        assert!(!shutdown.sleep(Duration::from_millis(10)));
+        let clone = shutdown.clone();
+        let sleeper = thread::spawn(move || clone.sleep(Duration::from_secs(60)));
+        shutdown.stop();
+        assert!(sleeper.join().unwrap());
+        assert!(shutdown.is_stopped());
+    }
 }
 
 fn get_upnp_config(port: u16) -> [UpnpConfig; 1] {
@@ -0,0 +1,557 @@
+//! TLS on the connections to the peers and to the tracker
+//!
+//! Every peer has a self-signed certificate, made on first start and kept in
+//! the state directory. There is no authority to check it against, so the
+//! first certificate seen with a peer id is pinned to it, and a connection
+//! later saying hello with that peer id under another certificate, or in
+//! plaintext, is refused. The tracker is pinned by its address.
+//!
+//! An incoming connection opening with a TLS handshake is served in TLS, a
+//! plaintext one only if `tls-fallback` allows it. An outgoing connection
+//! whose TLS handshake fails is opened again in plaintext under the same
+//! condition.
+use crate::com::connect;
+use crate::com::send;
+use crate::data::{get_config_path, get_peer_id, host_port};
+use crate::protocol::Message;
+use crate::state::get_state_dir;
+use crate::transport::Transport;
+use hashbrown::HashMap;
+use ini::Ini;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, DigitallySignedStruct, DistinguishedName,
+    ServerConfig, ServerConnection, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// First byte of a TLS handshake record
+const TLS_HANDSHAKE: u8 = 0x16;
+/// How long the TLS handshake may take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Label of the keying material the swarm proofs are bound to
+const BINDING_LABEL: &[u8] = b"EXPORTER-peer-swarm-proof";
+
+/// Who is at the other end of a connection, each has its own settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Remote {
+    Peer,
+    Tracker,
+}
+
+/// `tls` and `tls-fallback` of a config section
+#[derive(Debug, Clone, Copy, Default)]
+struct TlsPolicy {
+    enabled: bool,
+    fallback: bool,
+}
+
+lazy_static! {
+    static ref PEER_POLICY: TlsPolicy = load_policy("Peer");
+    static ref TRACKER_POLICY: TlsPolicy = load_policy("Tracker");
+    /// what our connections use, None when the certificate could not be made
+    static ref CONFIGS: Option<(Arc<ClientConfig>, Arc<ServerConfig>)> = match make_configs() {
+        Ok(configs) => Some(configs),
+        Err(e) => {
+            error!("TLS is unavailable : {}", e);
+            None
+        }
+    };
+    /// certificate fingerprints by peer id, or by address for the tracker
+    static ref PINS: Mutex<HashMap<String, String>> = Mutex::new(load_pins());
+}
+
+fn load_policy(section: &str) -> TlsPolicy {
+    let conf = Ini::load_from_file(get_config_path()).ok();
+    let flag = |name: &str| {
+        conf.as_ref()
+            .and_then(|conf| conf.section(Some(section)))
+            .and_then(|section| section.get(name))
+            .is_some_and(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"))
+    };
+    TlsPolicy {
+        enabled: flag("tls"),
+        fallback: flag("tls-fallback"),
+    }
+}
+
+fn policy(remote: Remote) -> TlsPolicy {
+    match remote {
+        Remote::Peer => *PEER_POLICY,
+        Remote::Tracker => *TRACKER_POLICY,
+    }
+}
+
+// writes a file only we can read, for the private key
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(bytes)
+}
+
+// our certificate and its key, made on first start
+fn load_identity() -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), Box<dyn Error>> {
+    let dir = get_state_dir();
+    let cert_path = dir.join("tls_cert.der");
+    let key_path = dir.join("tls_key.der");
+    if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+        return Ok((CertificateDer::from(cert), PrivateKeyDer::Pkcs8(key.into())));
+    }
+    let certified = rcgen::generate_simple_self_signed(vec![get_peer_id()])?;
+    let cert: CertificateDer<'static> = certified.cert.der().clone();
+    let key: Vec<u8> = certified.key_pair.serialize_der();
+    fs::create_dir_all(&dir)?;
+    fs::write(&cert_path, &cert)?;
+    write_private(&key_path, &key)?;
+    info!("Generated TLS certificate {}", cert_path.display());
+    Ok((cert, PrivateKeyDer::Pkcs8(key.into())))
+}
+
+fn make_configs() -> Result<(Arc<ClientConfig>, Arc<ServerConfig>), Box<dyn Error>> {
+    let (cert, key) = load_identity()?;
+    let provider = Arc::new(ring::default_provider());
+    let verifier = Arc::new(SelfSigned(provider.clone()));
+    let client = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_client_auth_cert(vec![cert.clone()], key.clone_key())?;
+    let server = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![cert], key)?;
+    Ok((Arc::new(client), Arc::new(server)))
+}
+
+/// Takes any certificate whose key signed the handshake, the pins say who it belongs to
+#[derive(Debug)]
+struct SelfSigned(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SelfSigned {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for SelfSigned {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A connection carried in TLS over another one
+pub struct TlsStream {
+    inner: Mutex<TlsInner>,
+}
+
+struct TlsInner {
+    conn: Connection,
+    transport: Box<dyn Transport>,
+    // decrypted and not read yet
+    plain: VecDeque<u8>,
+}
+
+impl TlsInner {
+    // sends what TLS has to send
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.transport)?;
+        }
+        self.transport.flush()
+    }
+
+    // decrypts until some plaintext is there, 0 at the end of the stream
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut buffer = [0u8; 4096];
+        while self.plain.is_empty() {
+            match self.conn.reader().read(&mut buffer) {
+                Ok(0) => return Ok(0),
+                Ok(len) => self.plain.extend(&buffer[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    // a timeout of the transport comes out of here as for a socket
+                    if self.conn.read_tls(&mut self.transport)? == 0 {
+                        return Ok(0);
+                    }
+                    self.conn
+                        .process_new_packets()
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                    self.flush_tls()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.plain.len())
+    }
+}
+
+impl TlsStream {
+    fn handshake(mut conn: Connection, mut transport: Box<dyn Transport>) -> io::Result<TlsStream> {
+        transport.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut transport)?;
+        }
+        Ok(TlsStream {
+            inner: Mutex::new(TlsInner {
+                conn,
+                transport,
+                plain: VecDeque::new(),
+            }),
+        })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.fill()?.min(buf.len());
+        for (byte, read) in buf.iter_mut().zip(inner.plain.drain(..len)) {
+            *byte = read;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.conn.writer().write_all(buf)?;
+        inner.flush_tls()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush_tls()
+    }
+}
+
+impl Transport for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.lock().unwrap().transport.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.lock().unwrap().transport.set_nonblocking(nonblocking)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.fill()?.min(buf.len());
+        for (byte, read) in buf.iter_mut().zip(inner.plain.iter()) {
+            *byte = *read;
+        }
+        Ok(len)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.lock().unwrap().transport.peer_addr()
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.lock().unwrap().transport.take_error()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.conn.send_close_notify();
+        // the other end may be gone already
+        let _ = inner.flush_tls();
+        inner.transport.shutdown()
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.to_vec())
+    }
+
+    fn channel_binding(&self) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .conn
+            .export_keying_material(vec![0u8; 32], BINDING_LABEL, None)
+            .ok()
+    }
+}
+
+/// Starts TLS on a connection we opened.
+///
+/// # Arguments
+/// * `transport` - The connection, nothing sent on it yet.
+/// * `adress` - The address we connected to.
+pub fn secure_client(transport: Box<dyn Transport>, adress: &str) -> io::Result<TlsStream> {
+    let Some((client, _)) = CONFIGS.as_ref() else {
+        return Err(io::Error::other("no TLS certificate"));
+    };
+    // the name is not checked, the pin is
+    let name = ServerName::try_from(adress.to_string())
+        .unwrap_or_else(|_| ServerName::try_from("peer").unwrap());
+    let conn = ClientConnection::new(client.clone(), name).map_err(io::Error::other)?;
+    TlsStream::handshake(conn.into(), transport)
+}
+
+/// Starts TLS on a connection we accepted.
+pub fn secure_server(transport: Box<dyn Transport>) -> io::Result<TlsStream> {
+    let Some((_, server)) = CONFIGS.as_ref() else {
+        return Err(io::Error::other("no TLS certificate"));
+    };
+    let conn = ServerConnection::new(server.clone()).map_err(io::Error::other)?;
+    TlsStream::handshake(conn.into(), transport)
+}
+
+/// Opens a connection, in TLS if the config asks for it.
+///
+/// # Arguments
+/// * `remote` - Whether a peer or the tracker listens there, each has its own settings.
+/// * `port` - The port to connect to.
+/// * `adress` - The address to connect to.
+///
+/// # Returns
+/// * `Option<Box<dyn Transport>>` - The connection, or None if it could not be opened as the config wants.
+pub fn open(remote: Remote, port: u16, adress: &str) -> Option<Box<dyn Transport>> {
+    let policy = policy(remote);
+    let stream = connect(port, adress)?;
+    if !policy.enabled {
+        return Some(stream);
+    }
+    match secure_client(stream, adress) {
+        Ok(tls) => {
+            // the tracker says no hello, it is known by its address
+            if remote == Remote::Tracker {
+                if let Err(e) = check_pin(&host_port(adress, port), &tls) {
+                    error!("Refusing tracker {} : {}", host_port(adress, port), e);
+                    return None;
+                }
+            }
+            Some(Box::new(tls))
+        }
+        Err(e) if policy.fallback => {
+            warn!("No TLS with {}, going on in plaintext : {}", host_port(adress, port), e);
+            connect(port, adress)
+        }
+        Err(e) => {
+            error!("No TLS with {} : {}", host_port(adress, port), e);
+            None
+        }
+    }
+}
+
+/// Serves an incoming connection in TLS if it opens with a TLS handshake.
+///
+/// # Arguments
+/// * `transport` - The accepted connection.
+///
+/// # Returns
+/// * `Result<Box<dyn Transport>, Box<dyn Error>>` - The connection to talk on,
+///   or why it was refused, plaintext being refused unless `tls-fallback` allows it.
+pub fn accept(mut transport: Box<dyn Transport>) -> Result<Box<dyn Transport>, Box<dyn Error>> {
+    let policy = policy(Remote::Peer);
+    if !policy.enabled {
+        return Ok(transport);
+    }
+    let mut first = [0u8; 1];
+    transport.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    if transport.peek(&mut first)? == 1 && first[0] == TLS_HANDSHAKE {
+        return Ok(Box::new(secure_server(transport)?));
+    }
+    if policy.fallback {
+        return Ok(transport);
+    }
+    let reason = "TLS is required".to_string();
+    send(&mut transport, &Message::Error { reason: reason.clone() });
+    Err(reason.into())
+}
+
+fn pins_path() -> std::path::PathBuf {
+    get_state_dir().join("tls_pins")
+}
+
+// one "identity fingerprint" per line
+fn load_pins() -> HashMap<String, String> {
+    match fs::read_to_string(pins_path()) {
+        Ok(pins) => pins
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(identity, fingerprint)| (identity.to_string(), fingerprint.to_string()))
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_pins(pins: &HashMap<String, String>) {
+    let lines: Vec<String> = pins
+        .iter()
+        .map(|(identity, fingerprint)| format!("{} {}\n", identity, fingerprint))
+        .collect();
+    if let Err(e) = fs::create_dir_all(get_state_dir()).and_then(|_| fs::write(pins_path(), lines.concat())) {
+        warn!("Could not save the TLS pins : {}", e);
+    }
+}
+
+/// Checks the certificate of a connection against the one pinned for `identity`,
+/// pinning it if there is none. A plaintext connection is refused once a certificate
+/// is pinned, it could come from anyone giving that identity.
+///
+/// # Arguments
+/// * `identity` - The peer id given in the hello, or the address of the tracker.
+/// * `transport` - The connection.
+pub fn check_pin(identity: &str, transport: &dyn Transport) -> Result<(), Box<dyn Error>> {
+    let mut pins = PINS.lock().unwrap();
+    let Some(cert) = transport.peer_certificate() else {
+        return match pins.get(identity) {
+            Some(pinned) => Err(format!(
+                "{} has the pinned certificate {}, plaintext is refused",
+                identity, pinned
+            )
+            .into()),
+            None => Ok(()),
+        };
+    };
+    let fingerprint: String = hex::encode(Sha256::digest(&cert));
+    match pins.get(identity) {
+        Some(pinned) if *pinned == fingerprint => Ok(()),
+        Some(pinned) => Err(format!(
+            "certificate {} of {} is not the pinned one {}",
+            fingerprint, identity, pinned
+        )
+        .into()),
+        None => {
+            info!("Pinning certificate {} to {}", fingerprint, identity);
+            pins.insert(identity.to_string(), fingerprint);
+            save_pins(&pins);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::com::receive_message;
+    use crate::transport::duplex;
+    use std::thread;
+
+    #[test]
+    fn test_tls_over_transport() {
+        let (plain, _) = duplex(
+            "127.0.0.1:40001".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+        );
+        let (client, server) = duplex(
+            "127.0.0.1:40000".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+        );
+        let server = thread::spawn(move || {
+            let mut server = secure_server(Box::new(server)).unwrap();
+            let request = receive_message(&mut server, 3000).unwrap();
+            send(&mut server, &Message::Ok);
+            (request, server.peer_certificate())
+        });
+        let mut client = secure_client(Box::new(client), "127.0.0.1").unwrap();
+        send(&mut client, &Message::Interested { key: "aaaa".to_string() });
+        assert_eq!(receive_message(&mut client, 3000).unwrap(), Message::Ok);
+        let (request, client_cert) = server.join().unwrap();
+        assert_eq!(request, Message::Interested { key: "aaaa".to_string() });
+        // both ends proved who they are, here the same peer
+        assert!(client_cert.is_some());
+        assert_eq!(client_cert, client.peer_certificate());
+
+        // the first certificate of a peer id is the only one it may use
+        PINS.lock().unwrap().remove("tlstestpeer");
+        assert!(check_pin("tlstestpeer", &plain).is_ok());
+        assert!(check_pin("tlstestpeer", &client).is_ok());
+        assert!(check_pin("tlstestpeer", &client).is_ok());
+        // once pinned, it cannot come back in plaintext
+        assert!(check_pin("tlstestpeer", &plain).is_err());
+        PINS.lock().unwrap().insert("tlstestother".to_string(), "00".to_string());
+        assert!(check_pin("tlstestother", &client).is_err());
+        PINS.lock().unwrap().remove("tlstestother");
+        PINS.lock().unwrap().remove("tlstestpeer");
+
+        // the certificate and the pins went to the state directory of the tests
+        let dir = get_state_dir();
+        for name in ["tls_cert.der", "tls_key.der", "tls_pins"] {
+            fs::remove_file(dir.join(name)).unwrap();
+        }
+    }
+}
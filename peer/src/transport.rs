@@ -0,0 +1,298 @@
+//! what carries the bytes of a connection
+//!
+//! Every function talking to a peer or to the tracker works on a
+//! `Transport`, so the connection may be plain TCP, wrapped in another
+//! protocol, or an in-memory `duplex` in the tests.
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A connection to a peer or to the tracker.
+///
+/// The methods behave like the ones of `TcpStream`: a read timing out or a
+/// non blocking read finding nothing fails with `WouldBlock`.
+pub trait Transport: Read + Write + Send {
+    /// Waits at most `timeout` for a read, forever with None.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Makes the reads return at once when nothing arrived.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    /// Reads without consuming, the next read gets the same bytes.
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+    /// The address of the other end.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// The pending error of the connection, if any.
+    fn take_error(&self) -> io::Result<Option<io::Error>>;
+    /// Closes both directions, the other end reads the end of the stream.
+    fn shutdown(&self) -> io::Result<()>;
+    /// The certificate the other end proved it holds, None in plaintext.
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Secret bytes only the two ends of this very connection can derive, None in plaintext.
+    fn channel_binding(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        TcpStream::take_error(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+// a boxed connection is handed to the functions taking any connection
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).peek(buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        (**self).take_error()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        (**self).peer_certificate()
+    }
+
+    fn channel_binding(&self) -> Option<Vec<u8>> {
+        (**self).channel_binding()
+    }
+}
+
+/// in-memory connections for the tests, both ends in the same process
+#[cfg(test)]
+pub mod memory {
+    use super::Transport;
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+    use std::time::{Duration, Instant};
+
+    /// one direction of a duplex
+    #[derive(Default)]
+    struct Pipe {
+        state: Mutex<PipeState>,
+        changed: Condvar,
+    }
+
+    #[derive(Default)]
+    struct PipeState {
+        bytes: VecDeque<u8>,
+        closed: bool,
+    }
+
+    impl Pipe {
+        fn close(&self) {
+            self.state.lock().unwrap().closed = true;
+            self.changed.notify_all();
+        }
+    }
+
+    /// One end of an in-memory connection, made by `duplex`
+    pub struct MemoryStream {
+        incoming: Arc<Pipe>,
+        outgoing: Arc<Pipe>,
+        peer: SocketAddr,
+        timeout: Mutex<Option<Duration>>,
+        nonblocking: AtomicBool,
+    }
+
+    /// Makes the two ends of an in-memory connection.
+    ///
+    /// # Arguments
+    /// * `left` - The address of the first end, seen by the second.
+    /// * `right` - The address of the second end, seen by the first.
+    ///
+    /// # Returns
+    /// * `(MemoryStream, MemoryStream)` - What one end writes, the other reads.
+    pub fn duplex(left: SocketAddr, right: SocketAddr) -> (MemoryStream, MemoryStream) {
+        let to_right: Arc<Pipe> = Arc::new(Pipe::default());
+        let to_left: Arc<Pipe> = Arc::new(Pipe::default());
+        let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>, peer: SocketAddr| MemoryStream {
+            incoming: incoming.clone(),
+            outgoing: outgoing.clone(),
+            peer,
+            timeout: Mutex::new(None),
+            nonblocking: AtomicBool::new(false),
+        };
+        (
+            end(&to_left, &to_right, right),
+            end(&to_right, &to_left, left),
+        )
+    }
+
+    impl MemoryStream {
+        // waits for bytes or for the end of the stream
+        fn readable(&self) -> io::Result<MutexGuard<'_, PipeState>> {
+            let timeout: Option<Duration> = *self.timeout.lock().unwrap();
+            let deadline: Option<Instant> = timeout.map(|timeout| Instant::now() + timeout);
+            let mut state = self.incoming.state.lock().unwrap();
+            loop {
+                if !state.bytes.is_empty() || state.closed {
+                    return Ok(state);
+                }
+                if self.nonblocking.load(Ordering::SeqCst) {
+                    return Err(io::Error::from(ErrorKind::WouldBlock));
+                }
+                state = match deadline {
+                    Some(deadline) => {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            return Err(io::Error::from(ErrorKind::WouldBlock));
+                        }
+                        self.incoming.changed.wait_timeout(state, left).unwrap().0
+                    }
+                    None => self.incoming.changed.wait(state).unwrap(),
+                };
+            }
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut state = self.readable()?;
+            let len = buf.len().min(state.bytes.len());
+            for (byte, read) in buf.iter_mut().zip(state.bytes.drain(..len)) {
+                *byte = read;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.outgoing.state.lock().unwrap();
+            if state.closed {
+                return Err(io::Error::from(ErrorKind::BrokenPipe));
+            }
+            state.bytes.extend(buf);
+            self.outgoing.changed.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            if timeout == Some(Duration::ZERO) {
+                return Err(io::Error::from(ErrorKind::InvalidInput));
+            }
+            *self.timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.nonblocking.store(nonblocking, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let state = self.readable()?;
+            let len = buf.len().min(state.bytes.len());
+            for (byte, read) in buf.iter_mut().zip(state.bytes.iter()) {
+                *byte = *read;
+            }
+            Ok(len)
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer)
+        }
+
+        fn take_error(&self) -> io::Result<Option<io::Error>> {
+            Ok(None)
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.incoming.close();
+            self.outgoing.close();
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryStream {
+        fn drop(&mut self) {
+            let _ = Transport::shutdown(self);
+        }
+    }
+}
+
+#[cfg(test)]
+pub use memory::duplex;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_duplex() {
+        let left: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let right: SocketAddr = "10.0.0.2:2".parse().unwrap();
+        let (mut a, mut b) = duplex(left, right);
+        assert_eq!(a.peer_addr().unwrap(), right);
+        assert_eq!(b.peer_addr().unwrap(), left);
+
+        a.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(b.peek(&mut buf).unwrap(), 5);
+        assert_eq!(b.read(&mut buf[..3]).unwrap(), 3);
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(b.read(&mut buf).unwrap(), 2);
+
+        // nothing to read, like a socket
+        b.set_nonblocking(true).unwrap();
+        assert_eq!(b.peek(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        b.set_nonblocking(false).unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert_eq!(b.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // the other end reads the end of the stream, and cannot write anymore
+        a.write_all(b"bye").unwrap();
+        drop(a);
+        assert_eq!(b.read(&mut buf).unwrap(), 3);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(b.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}